@@ -0,0 +1,12 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+REPO=toast-clean-all
+"$TOAST" --repo "$REPO"
+"$TOAST" --repo "$REPO" --clean unreachable
+[ -n "$(docker image ls --quiet "$REPO")" ]
+"$TOAST" --repo "$REPO" --clean all --dry-run
+[ -n "$(docker image ls --quiet "$REPO")" ]
+"$TOAST" --repo "$REPO" --clean all
+[ -z "$(docker image ls --quiet "$REPO")" ]
+rm toast.lock
@@ -0,0 +1,15 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+ENGINE_LOG="$(mktemp)"
+export ENGINE_LOG
+"$TOAST" --read-local-cache false --write-local-cache false \
+  --container-engine "$PWD/bin/podman"
+grep '^container ls --quiet --no-trunc --all --filter label=toast.pid ' "$ENGINE_LOG"
+grep '^container create .* --label toast.task=greet .* debian /bin/su -c echo hello root$' \
+  "$ENGINE_LOG"
+grep '^container cp - stub-container:/$' "$ENGINE_LOG"
+grep '^container start --attach stub-container$' "$ENGINE_LOG"
+grep '^container commit --change LABEL .* --format docker stub-container toast:' "$ENGINE_LOG"
+grep '^container rm --force stub-container$' "$ENGINE_LOG"
+rm "$ENGINE_LOG" toast.lock
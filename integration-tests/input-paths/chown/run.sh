@@ -0,0 +1,11 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p foo/bar
+echo 'Hello, World!' > foo/bar/baz.txt
+"$TOAST" --read-local-cache false --write-local-cache false > output.txt
+grep 'nobody .* foo$' output.txt
+grep 'nobody .* bar$' output.txt
+grep 'nobody .* baz\.txt$' output.txt
+rm output.txt
+rm -rf foo
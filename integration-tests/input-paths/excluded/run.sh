@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p foo/target foo/bar
+touch foo/target/baz.o foo/bar/qux.txt foo/bar/quux.log
+echo '*.log' > .toastignore
+"$TOAST" --read-local-cache false --write-local-cache false > output.txt
+grep '^foo/bar/qux\.txt$' output.txt
+if grep 'target\|quux\.log' output.txt; then
+  exit 1
+fi
+rm output.txt .toastignore
+rm -r foo
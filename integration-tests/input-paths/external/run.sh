@@ -0,0 +1,9 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p foo
+ln -s ../.. foo/bar
+if "$TOAST" --read-local-cache false --write-local-cache false; then
+  exit 1
+fi
+rm -rf foo
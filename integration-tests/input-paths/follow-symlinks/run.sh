@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p foo
+echo 'Hello, World!' > bar.txt
+ln -s ../bar.txt foo/bar.txt
+"$TOAST" --read-local-cache false --write-local-cache false > output.txt
+grep '^Hello, World!$' output.txt
+rm output.txt
+rm -rf foo bar.txt
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+"$TOAST" --read-local-cache false --write-local-cache false > output.txt
+grep '^foo/baz\.txt$' output.txt
+grep '^foo/bar/qux\.txt$' output.txt
+if grep 'quux\.md' output.txt; then
+  exit 1
+fi
+rm output.txt
@@ -0,0 +1,11 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p foo
+echo 'Hello, World!' > foo/bar.txt
+ln foo/bar.txt foo/baz.txt
+"$TOAST" --read-local-cache false --write-local-cache false > output.txt
+grep '^2 .*foo/bar\.txt$' output.txt
+grep '^2 .*foo/baz\.txt$' output.txt
+rm output.txt
+rm -rf foo
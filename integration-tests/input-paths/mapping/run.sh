@@ -0,0 +1,8 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p config foo
+echo 'prod' > config/prod.yml
+echo 'Hello, World!' > foo/bar.txt
+"$TOAST" --read-local-cache false --write-local-cache false
+rm -rf config foo
@@ -0,0 +1,9 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+echo 'Hello, World!' > foo.txt
+chmod 640 foo.txt
+"$TOAST" --read-local-cache false --write-local-cache false > output.txt
+grep '^-rw-r----- .* root root .* foo\.txt$' output.txt
+rm output.txt
+rm foo.txt
@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p foo
+echo 'Hello, World!' > foo/bar.txt
+mkfifo foo/baz.fifo
+"$TOAST" --read-local-cache false --write-local-cache false > output.txt
+grep '^foo/bar\.txt$' output.txt
+if grep 'baz\.fifo' output.txt; then
+  exit 1
+fi
+rm output.txt
+rm -rf foo
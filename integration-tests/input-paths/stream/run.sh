@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p foo/bar
+echo 'Hello, World!' > foo/bar/baz.txt
+"$TOAST" \
+  --read-local-cache false \
+  --write-local-cache false \
+  --stream-input-paths true \
+  > output.txt
+grep '^Hello, World!$' output.txt
+rm output.txt
+rm -rf foo
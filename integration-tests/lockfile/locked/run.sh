@@ -0,0 +1,15 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+rm -f toast.lock
+if "$TOAST" --read-local-cache false --write-local-cache false --locked; then
+  exit 1
+fi
+"$TOAST" --read-local-cache false --write-local-cache false
+grep '^image: "debian"$' toast.lock
+grep '^digest: "sha256:' toast.lock
+"$TOAST" --read-local-cache false --write-local-cache false --locked
+"$TOAST" --update-lock
+"$TOAST" --read-local-cache false --write-local-cache false --locked
+grep Hello output.txt
+rm output.txt toast.lock
@@ -0,0 +1,12 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p dist
+echo 'fresh' > dist/fresh.txt
+"$TOAST" --read-local-cache false --write-local-cache false --check
+echo 'stale' > dist/fresh.txt
+if "$TOAST" --read-local-cache false --write-local-cache false --check; then
+  exit 1
+fi
+grep 'stale' dist/fresh.txt
+rm -rf dist
@@ -0,0 +1,11 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+"$TOAST" --read-local-cache false --write-local-cache false
+
+test -f dist/foo.whl
+test -f dist/bar.whl
+test ! -e dist/baz.tar.gz
+test ! -e dist/nested
+
+rm -r dist
@@ -0,0 +1,7 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+"$TOAST" --read-local-cache false --write-local-cache false
+grep 'Hello, World!' out/bin/foo.txt
+grep 'Hello, World!' bar.txt
+rm -rf out bar.txt
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+mkdir -p dist
+echo 'stale' > dist/stale.txt
+"$TOAST" --read-local-cache false --write-local-cache false
+grep 'fresh' dist/fresh.txt
+[ ! -e dist/stale.txt ]
+[ -z "$(find . -maxdepth 1 -name '.toast-*')" ]
+rm -rf dist
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+if "$TOAST" --read-local-cache false --write-local-cache false; then
+  exit 1
+fi
+
+grep 'FAILED' report.txt
+[ ! -e foo.txt ]
+rm report.txt
@@ -0,0 +1,1126 @@
+use crate::{
+    failure,
+    failure::Failure,
+    format::CodeStr,
+    http,
+    http::{io_failure, Body, Endpoint, Response},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fs::{read_to_string, rename},
+    io,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tar::Archive;
+use tempfile::Builder;
+
+// Where the Docker daemon listens by default
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+// The prefixes of the `DOCKER_HOST` values which are supported
+const UNIX_SCHEME: &str = "unix://";
+const TCP_SCHEME: &str = "tcp://";
+
+// The registry which hosts images with unqualified names, as it appears in the Docker CLI's
+// configuration file
+const DEFAULT_REGISTRY: &str = "https://index.docker.io/v1/";
+
+// A bind mount for a container
+pub struct Mount {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub readonly: bool,
+}
+
+// A client for the Docker Engine API. This is an alternative to running the `docker` command-line
+// tool for each operation. It avoids the cost of starting a process, and it can distinguish
+// failures by their status codes rather than by parsing error messages.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Client {
+    endpoint: Endpoint,
+}
+
+impl Client {
+    // Construct a client which connects to a particular endpoint.
+    pub fn new(endpoint: Endpoint) -> Self {
+        Client { endpoint }
+    }
+
+    // Determine where the daemon is listening from the `DOCKER_HOST` environment variable, like
+    // the Docker CLI does. If it isn't set, the default socket is used. TLS isn't supported.
+    pub fn from_env() -> Result<Self, Failure> {
+        let host = match env::var("DOCKER_HOST") {
+            Ok(host) if !host.is_empty() => host,
+            _ => return Ok(Client::new(Endpoint::Unix(PathBuf::from(DEFAULT_SOCKET)))),
+        };
+
+        // TLS isn't supported, so TCP hosts are only accepted when TLS verification is off.
+        let tls = env::var("DOCKER_TLS_VERIFY")
+            .map(|verify| !verify.is_empty())
+            .unwrap_or(false);
+        if host.starts_with(UNIX_SCHEME) {
+            Ok(Client::new(Endpoint::Unix(PathBuf::from(
+                &host[UNIX_SCHEME.len()..],
+            ))))
+        } else if host.starts_with(TCP_SCHEME) && !tls {
+            Ok(Client::new(Endpoint::Tcp(
+                host[TCP_SCHEME.len()..].trim_end_matches('/').to_owned(),
+            )))
+        } else {
+            Err(Failure::User(
+                format!(
+                    "The Docker host {} is not supported by the Docker API client.",
+                    host.code_str(),
+                ),
+                None,
+            ))
+        }
+    }
+
+    // Send a request. If the daemon responds with an error, the failure includes the message from
+    // the daemon.
+    fn call(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Body,
+        error: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Response, Failure> {
+        let response = http::request(&self.endpoint, method, path, headers, body, interrupted)
+            .map_err(|e| match e {
+                Failure::Interrupted => Failure::Interrupted,
+                Failure::System(message, source) | Failure::User(message, source) => {
+                    Failure::System(format!("{} {}", error, message), source)
+                }
+            })?;
+
+        if response.status < 400 {
+            Ok(response)
+        } else {
+            let status = response.status;
+            let text = response.text(interrupted)?;
+            Err(Failure::System(
+                format!(
+                    "{}\n{}",
+                    error,
+                    serde_json::from_str::<Value>(&text)
+                        .ok()
+                        .and_then(|value| value["message"].as_str().map(ToOwned::to_owned))
+                        .unwrap_or_else(|| format!("The daemon responded with status {}.", status)),
+                ),
+                None,
+            ))
+        }
+    }
+
+    // Fetch the details of a local image. Fails if the image doesn't exist.
+    pub fn inspect_image(
+        &self,
+        image: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Value, Failure> {
+        let response = self.call(
+            "GET",
+            &format!("/images/{}/json", http::encode(image, true)),
+            &[],
+            Body::Empty,
+            "The image doesn't exist.",
+            interrupted,
+        )?;
+
+        let text = response.text(interrupted)?;
+        serde_json::from_str::<Value>(&text).map_err(|_| {
+            Failure::System(
+                format!(
+                    "Unable to inspect image.\nUnexpected response: {}",
+                    text.code_str(),
+                ),
+                None,
+            )
+        })
+    }
+
+    // List the images which match some filters (e.g., `{ "reference": ["toast"] }`), along with
+    // the details the daemon reports for each of them.
+    pub fn list_images(
+        &self,
+        filters: &Value,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Value>, Failure> {
+        self.list(
+            &format!(
+                "/images/json?filters={}",
+                http::encode(&filters.to_string(), false),
+            ),
+            "Unable to list images.",
+            interrupted,
+        )
+    }
+
+    // List the containers, running or not, which match some filters, along with the details the
+    // daemon reports for each of them.
+    pub fn list_containers(
+        &self,
+        filters: &Value,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Value>, Failure> {
+        self.list(
+            &format!(
+                "/containers/json?all=1&filters={}",
+                http::encode(&filters.to_string(), false),
+            ),
+            "Unable to list containers.",
+            interrupted,
+        )
+    }
+
+    // Fetch a list of objects from the daemon.
+    fn list(
+        &self,
+        path: &str,
+        error: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Value>, Failure> {
+        let response = self.call("GET", path, &[], Body::Empty, error, interrupted)?;
+
+        let text = response.text(interrupted)?;
+        serde_json::from_str::<Vec<Value>>(&text).map_err(|_| {
+            Failure::System(
+                format!("{}\nUnexpected response: {}", error, text.code_str()),
+                None,
+            )
+        })
+    }
+
+    // Push an image.
+    pub fn push_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        let (repository, tag) = split_reference(image);
+        let auth = registry_auth(repository);
+
+        let response = self.call(
+            "POST",
+            &format!(
+                "/images/{}/push?tag={}",
+                http::encode(repository, true),
+                http::encode(tag.unwrap_or("latest"), false),
+            ),
+            &[("X-Registry-Auth", &auth)],
+            Body::Empty,
+            "Unable to push image.",
+            interrupted,
+        )?;
+
+        check_progress(response, "Unable to push image.", interrupted)
+    }
+
+    // Pull an image.
+    pub fn pull_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        let (repository, tag) = split_reference(image);
+        let auth = registry_auth(repository);
+
+        // If the reference has a digest, it's passed along as part of the image name.
+        let query = if image.contains('@') {
+            format!("fromImage={}", http::encode(image, false))
+        } else {
+            format!(
+                "fromImage={}&tag={}",
+                http::encode(repository, false),
+                http::encode(tag.unwrap_or("latest"), false),
+            )
+        };
+
+        let response = self.call(
+            "POST",
+            &format!("/images/create?{}", query),
+            &[("X-Registry-Auth", &auth)],
+            Body::Empty,
+            "Unable to pull image.",
+            interrupted,
+        )?;
+
+        check_progress(response, "Unable to pull image.", interrupted)
+    }
+
+    // Delete an image.
+    pub fn delete_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        self.call(
+            "DELETE",
+            &format!("/images/{}?force=1", http::encode(image, true)),
+            &[],
+            Body::Empty,
+            "Unable to delete image.",
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Create a container and return its ID. The `ports` use the same syntax as the `--publish`
+    // option of the Docker CLI.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_container(
+        &self,
+        image: &str,
+        workdir: &str,
+        environment: &[String],
+        mounts: &[Mount],
+        ports: &[String],
+        command: &[&str],
+        labels: &BTreeMap<String, String>,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<String, Failure> {
+        // Translate the ports into the form the API expects.
+        let mut exposed_ports = HashMap::new();
+        let mut port_bindings = HashMap::<String, Vec<Value>>::new();
+        for port in ports {
+            let (host_ip, host_port, container_port) = parse_port(port)?;
+            exposed_ports.insert(container_port.clone(), json!({}));
+            port_bindings
+                .entry(container_port)
+                .or_default()
+                .push(json!({ "HostIp": host_ip, "HostPort": host_port }));
+        }
+
+        // Construct the container configuration. `Init` is the equivalent of `--init`
+        // [ref:--init].
+        let config = json!({
+            "Image": image,
+            "WorkingDir": workdir,
+            "Env": environment,
+            "Cmd": command,
+            "Labels": labels,
+            "AttachStdout": true,
+            "AttachStderr": true,
+            "ExposedPorts": exposed_ports,
+            "HostConfig": {
+                "Init": true,
+                "Mounts": mounts
+                    .iter()
+                    .map(|mount| json!({
+                        "Type": "bind",
+                        "Source": mount.source.to_string_lossy(),
+                        "Target": mount.target.to_string_lossy(),
+                        "ReadOnly": mount.readonly,
+                    }))
+                    .collect::<Vec<_>>(),
+                "PortBindings": port_bindings,
+            },
+        });
+        let body = config.to_string();
+
+        // Create the container.
+        let response = self.call(
+            "POST",
+            "/containers/create",
+            &[("Content-Type", "application/json")],
+            Body::Bytes(body.as_bytes()),
+            "Unable to create container.",
+            interrupted,
+        )?;
+
+        // Extract the ID of the new container.
+        let text = response.text(interrupted)?;
+        serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|value| value["Id"].as_str().map(ToOwned::to_owned))
+            .ok_or_else(|| {
+                Failure::System(
+                    format!(
+                        "Unable to create container.\nUnexpected response: {}",
+                        text.code_str(),
+                    ),
+                    None,
+                )
+            })
+    }
+
+    // Copy files into a container. Accepts a closure which writes a tar archive to the connection.
+    pub fn copy_into_container<W: FnOnce(&mut dyn Write) -> Result<(), Failure>>(
+        &self,
+        container: &str,
+        writer: W,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        self.call(
+            "PUT",
+            &format!(
+                "/containers/{}/archive?path=%2F",
+                http::encode(container, false),
+            ),
+            &[("Content-Type", "application/x-tar")],
+            Body::Stream(Box::new(writer)),
+            "Unable to copy files into the container.",
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Copy a single path from the container to a path on the host which must not exist yet.
+    pub fn copy_from_container(
+        &self,
+        container: &str,
+        source: &Path,
+        destination: &Path,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        // Remove any trailing `.` components. Since the destination doesn't exist, copying the
+        // contents of a directory is the same as copying the directory itself.
+        let source = source.components().collect::<PathBuf>();
+
+        // Fetch an archive of the path.
+        let response = self.call(
+            "GET",
+            &format!(
+                "/containers/{}/archive?path={}",
+                http::encode(container, false),
+                http::encode(&source.to_string_lossy(), false),
+            ),
+            &[],
+            Body::Empty,
+            "Unable to copy files from the container.",
+            interrupted,
+        )?;
+
+        // Extract the archive next to the destination, so it can be renamed into place. Docker
+        // names the root of the archive after the last component of the path.
+        let parent = destination.parent().unwrap_or_else(|| Path::new("."));
+        let staging_dir = Builder::new()
+            .prefix(".toast-")
+            .tempdir_in(parent)
+            .map_err(failure::system("Unable to create temporary directory."))?;
+        let mut archive = Archive::new(response);
+        archive.set_preserve_permissions(true);
+        archive.set_unpack_xattrs(false);
+        archive.unpack(staging_dir.path()).map_err(io_failure(
+            "Unable to copy files from the container.",
+            interrupted,
+        ))?;
+        let extracted_path = source.file_name().map_or_else(
+            || staging_dir.path().to_owned(),
+            |name| staging_dir.path().join(name),
+        );
+
+        // Move the files into place.
+        rename(&extracted_path, destination).map_err(failure::system(format!(
+            "Unable to move {} to {}.",
+            extracted_path.to_string_lossy().code_str(),
+            destination.to_string_lossy().code_str(),
+        )))
+    }
+
+    // Start a container, wait for it to finish, and forward its output to ours. The returned
+    // failure doesn't contain any details, since the output has been shown to the user already.
+    pub fn start_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        // This is used to determine whether the user interrupted the program while the container
+        // was running.
+        let was_interrupted = interrupted.load(Ordering::SeqCst);
+
+        // Attach to the container before starting it, so we don't miss any output.
+        let container = http::encode(container, false);
+        let attachment = self.call(
+            "POST",
+            &format!(
+                "/containers/{}/attach?stream=1&stdout=1&stderr=1",
+                container,
+            ),
+            &[("Connection", "Upgrade"), ("Upgrade", "tcp")],
+            Body::Empty,
+            "Unable to start container.",
+            interrupted,
+        )?;
+
+        // Start the container.
+        self.call(
+            "POST",
+            &format!("/containers/{}/start", container),
+            &[],
+            Body::Empty,
+            "Unable to start container.",
+            interrupted,
+        )?;
+
+        // Forward the output until the container stops.
+        let stdout = io::stdout();
+        let stderr = io::stderr();
+        demultiplex(attachment, &mut stdout.lock(), &mut stderr.lock())
+            .map_err(io_failure("Unable to read container output.", interrupted))?;
+
+        // Find out how the command exited.
+        let text = self
+            .call(
+                "POST",
+                &format!("/containers/{}/wait", container),
+                &[],
+                Body::Empty,
+                "Unable to start container.",
+                interrupted,
+            )?
+            .text(interrupted)?;
+        let status_code = serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|value| value["StatusCode"].as_i64());
+
+        if status_code == Some(0) {
+            Ok(())
+        } else if !was_interrupted && interrupted.load(Ordering::SeqCst) {
+            Err(Failure::Interrupted)
+        } else {
+            Err(Failure::System(
+                "Unable to start container.".to_owned(),
+                None,
+            ))
+        }
+    }
+
+    // Stop a container.
+    pub fn stop_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        // The daemon responds with status 304 if the container isn't running, which is fine.
+        self.call(
+            "POST",
+            &format!("/containers/{}/stop", http::encode(container, false)),
+            &[],
+            Body::Empty,
+            "Unable to stop container.",
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Commit a container to an image. The `change` is a Dockerfile instruction to apply, as with
+    // the `--change` option of the Docker CLI.
+    pub fn commit_container(
+        &self,
+        container: &str,
+        image: &str,
+        change: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        let (repository, tag) = split_reference(image);
+
+        self.call(
+            "POST",
+            &format!(
+                "/commit?container={}&repo={}&tag={}&changes={}",
+                http::encode(container, false),
+                http::encode(repository, false),
+                http::encode(tag.unwrap_or("latest"), false),
+                http::encode(change, false),
+            ),
+            &[],
+            Body::Empty,
+            "Unable to commit container.",
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Delete a container.
+    pub fn delete_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        self.call(
+            "DELETE",
+            &format!("/containers/{}?force=1", http::encode(container, false)),
+            &[],
+            Body::Empty,
+            "Unable to delete container.",
+            interrupted,
+        )
+        .map(|_| ())
+    }
+}
+
+// Split an image reference into the repository and the tag, if there is one. The colon which
+// separates a registry from its port is not mistaken for a tag separator.
+pub fn split_reference(image: &str) -> (&str, Option<&str>) {
+    let name = image.split('@').next().unwrap_or(image);
+    let last_component = name.rfind('/').map_or(0, |index| index + 1);
+
+    match name[last_component..].rfind(':') {
+        Some(index) => (
+            &name[..last_component + index],
+            Some(&name[last_component + index + 1..]),
+        ),
+        None => (name, None),
+    }
+}
+
+// Parse a port publishing specification in the form `[[HOST_IP:]HOST_PORT:]CONTAINER_PORT[/PROTO]`
+// into the host IP, the host port, and the container port with its protocol.
+fn parse_port(port: &str) -> Result<(String, String, String), Failure> {
+    let (addresses, protocol) = match port.rfind('/') {
+        Some(index) => (&port[..index], &port[index + 1..]),
+        None => (port, "tcp"),
+    };
+
+    let mut parts = addresses.rsplitn(3, ':');
+    let container_port = parts.next().unwrap_or("");
+    let host_port = parts.next().unwrap_or("");
+    let host_ip = parts.next().unwrap_or("");
+
+    if container_port.is_empty() || !container_port.chars().all(|c| c.is_ascii_digit()) {
+        return Err(Failure::User(
+            format!(
+                "Unable to publish port {}. The Docker API client doesn't support port ranges.",
+                port.code_str(),
+            ),
+            None,
+        ));
+    }
+
+    Ok((
+        host_ip.to_owned(),
+        host_port.to_owned(),
+        format!("{}/{}", container_port, protocol),
+    ))
+}
+
+// Find the credentials for the registry which hosts a repository in the Docker CLI's configuration
+// file, and encode them for the `X-Registry-Auth` header. Credential helpers aren't supported. If
+// no credentials are found, the request is made anonymously.
+fn registry_auth(repository: &str) -> String {
+    // Determine the registry. The first component of the repository is a registry if it looks like
+    // a host name.
+    let registry = match repository.find('/') {
+        Some(index)
+            if repository[..index].contains('.')
+                || repository[..index].contains(':')
+                || &repository[..index] == "localhost" =>
+        {
+            &repository[..index]
+        }
+        _ => DEFAULT_REGISTRY,
+    };
+
+    // Look for the credentials. The keys in the configuration file may or may not have a scheme.
+    let credentials = env::var("DOCKER_CONFIG")
+        .ok()
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".docker")))
+        .and_then(|dir| read_to_string(dir.join("config.json")).ok())
+        .and_then(|data| serde_json::from_str::<Value>(&data).ok())
+        .and_then(|config| {
+            config["auths"].as_object().and_then(|auths| {
+                auths
+                    .iter()
+                    .find(|(key, _)| {
+                        *key == registry
+                            || key
+                                .trim_start_matches("https://")
+                                .trim_start_matches("http://")
+                                == registry
+                    })
+                    .and_then(|(_, auth)| auth["auth"].as_str().map(ToOwned::to_owned))
+            })
+        })
+        .and_then(|auth| base64::decode(&auth).ok())
+        .and_then(|auth| String::from_utf8(auth).ok());
+
+    let auth_config = match credentials.as_ref().and_then(|credentials| {
+        credentials
+            .find(':')
+            .map(|index| credentials.split_at(index))
+    }) {
+        Some((username, password)) => json!({
+            "username": username,
+            "password": &password[1..],
+            "serveraddress": registry,
+        }),
+        None => json!({}),
+    };
+
+    base64::encode_config(&auth_config.to_string(), base64::URL_SAFE)
+}
+
+// Pushing and pulling report their progress as a stream of JSON objects. An error may be reported
+// in the stream even if the request succeeded. This reads the stream and reports the first error,
+// if there is one.
+fn check_progress(
+    response: Response,
+    error: &str,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(), Failure> {
+    for line in BufReader::new(response).lines() {
+        let line = line.map_err(io_failure(error, interrupted))?;
+        if let Some(message) = serde_json::from_str::<Value>(&line)
+            .ok()
+            .and_then(|value| value["error"].as_str().map(ToOwned::to_owned))
+        {
+            return Err(Failure::System(format!("{}\n{}", error, message), None));
+        }
+    }
+
+    Ok(())
+}
+
+// Containers without a TTY multiplex their standard output and error streams over a single
+// connection. Each frame has an 8-byte header consisting of the stream type (`1` for standard
+// output and `2` for standard error), three bytes of padding, and the size of the payload as a
+// big-endian 32-bit integer. This function separates the streams again.
+fn demultiplex<R: Read, O: Write, E: Write>(
+    mut reader: R,
+    stdout: &mut O,
+    stderr: &mut E,
+) -> io::Result<()> {
+    let mut header = [0; 8];
+    loop {
+        // Read the next header, unless the stream is over.
+        let mut filled = 0;
+        while filled < header.len() {
+            let size = reader.read(&mut header[filled..])?;
+            if size == 0 {
+                return if filled == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Incomplete frame header.",
+                    ))
+                };
+            }
+            filled += size;
+        }
+
+        // Forward the payload to the appropriate stream.
+        let size = u64::from(u32::from_be_bytes([
+            header[4], header[5], header[6], header[7],
+        ]));
+        let mut payload = (&mut reader).take(size);
+        if header[0] == 2 {
+            io::copy(&mut payload, stderr)?;
+            stderr.flush()?;
+        } else {
+            io::copy(&mut payload, stdout)?;
+            stdout.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{demultiplex, parse_port, split_reference, Client, Mount},
+        failure::Failure,
+        http::{ChunkedReader, Endpoint},
+    };
+    use serde_json::{json, Value};
+    use std::{
+        collections::BTreeMap,
+        fs::read_to_string,
+        io::{BufRead, BufReader, Read, Write},
+        os::unix::net::UnixListener,
+        path::Path,
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+        thread::JoinHandle,
+    };
+    use tempfile::{tempdir, TempDir};
+
+    // A request received by the stand-in server
+    struct Request {
+        request_line: String,
+        body: Vec<u8>,
+    }
+
+    // Start a stand-in for the Docker daemon which gives the given responses, in order, and then
+    // returns the requests it received.
+    fn serve(responses: Vec<Vec<u8>>) -> (TempDir, Client, JoinHandle<Vec<Request>>) {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("docker.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                // Read the request line and the headers.
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                let mut chunked = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if line.starts_with("content-length:") {
+                        content_length = line["content-length:".len()..].trim().parse().unwrap();
+                    }
+                    if line == "transfer-encoding: chunked" {
+                        chunked = true;
+                    }
+                }
+
+                // Read the body.
+                let mut body = vec![];
+                if chunked {
+                    ChunkedReader::new(&mut reader)
+                        .read_to_end(&mut body)
+                        .unwrap();
+                } else {
+                    body.resize(content_length, 0);
+                    reader.read_exact(&mut body).unwrap();
+                }
+
+                // Respond.
+                reader.get_mut().write_all(&response).unwrap();
+                requests.push(Request {
+                    request_line: request_line.trim_end().to_owned(),
+                    body,
+                });
+            }
+
+            requests
+        });
+
+        (dir, Client::new(Endpoint::Unix(socket_path)), handle)
+    }
+
+    // Construct a response with a JSON body.
+    fn json_response(status: &str, body: &Value) -> Vec<u8> {
+        let body = body.to_string();
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body,
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn split_reference_tag() {
+        assert_eq!(split_reference("debian:buster"), ("debian", Some("buster")));
+    }
+
+    #[test]
+    fn split_reference_registry_port() {
+        assert_eq!(
+            split_reference("localhost:5000/toast"),
+            ("localhost:5000/toast", None),
+        );
+    }
+
+    #[test]
+    fn split_reference_digest() {
+        assert_eq!(
+            split_reference("debian:buster@sha256:abc"),
+            ("debian", Some("buster")),
+        );
+    }
+
+    #[test]
+    fn parse_port_full() {
+        assert_eq!(
+            parse_port("127.0.0.1:3000:80/udp").unwrap(),
+            (
+                "127.0.0.1".to_owned(),
+                "3000".to_owned(),
+                "80/udp".to_owned()
+            ),
+        );
+    }
+
+    #[test]
+    fn parse_port_container_only() {
+        assert_eq!(
+            parse_port("80").unwrap(),
+            (String::new(), String::new(), "80/tcp".to_owned()),
+        );
+    }
+
+    #[test]
+    fn parse_port_range() {
+        assert!(parse_port("3000-3001:80-81").is_err());
+    }
+
+    #[test]
+    fn demultiplex_streams() {
+        let mut frames = vec![1, 0, 0, 0, 0, 0, 0, 4];
+        frames.extend_from_slice(b"foo\n");
+        frames.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 4]);
+        frames.extend_from_slice(b"bar\n");
+        frames.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 4]);
+        frames.extend_from_slice(b"baz\n");
+
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        demultiplex(&frames[..], &mut stdout, &mut stderr).unwrap();
+
+        assert_eq!(stdout, b"foo\nbaz\n".to_vec());
+        assert_eq!(stderr, b"bar\n".to_vec());
+    }
+
+    #[test]
+    fn inspect_image_details() {
+        let (_dir, client, handle) = serve(vec![json_response(
+            "200 OK",
+            &json!({ "Id": "sha256:abc", "RepoDigests": [] }),
+        )]);
+
+        assert_eq!(
+            client
+                .inspect_image("foo", &Arc::new(AtomicBool::new(false)))
+                .unwrap()["Id"],
+            "sha256:abc",
+        );
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn inspect_image_missing() {
+        let (_dir, client, handle) = serve(vec![json_response(
+            "404 Not Found",
+            &json!({ "message": "No such image: foo:bar" }),
+        )]);
+
+        match client.inspect_image("foo:bar", &Arc::new(AtomicBool::new(false))) {
+            Err(Failure::System(message, _)) => {
+                assert!(message.ends_with("No such image: foo:bar"));
+            }
+            _ => panic!("The image should not exist."),
+        }
+
+        let requests = handle.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "GET /images/foo%3Abar/json HTTP/1.1",
+        );
+    }
+
+    #[test]
+    fn list_images_filter() {
+        let (_dir, client, handle) = serve(vec![json_response(
+            "200 OK",
+            &json!([{ "RepoTags": ["toast:toast-abc"], "Size": 1000, "Created": 1_560_000_000 }]),
+        )]);
+
+        let images = client
+            .list_images(
+                &json!({ "reference": ["toast"] }),
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap();
+
+        assert_eq!(images[0]["Size"], 1000);
+        let requests = handle.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "GET /images/json?filters=%7B%22reference%22%3A%5B%22toast%22%5D%7D HTTP/1.1",
+        );
+    }
+
+    #[test]
+    fn list_containers_filter() {
+        let (_dir, client, handle) = serve(vec![json_response(
+            "200 OK",
+            &json!([{ "Id": "abc123", "Labels": { "toast.pid": "42" } }]),
+        )]);
+
+        let containers = client
+            .list_containers(
+                &json!({ "label": ["toast.pid"] }),
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap();
+
+        assert_eq!(containers[0]["Labels"]["toast.pid"], "42");
+        let requests = handle.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "GET /containers/json?all=1&filters=%7B%22label%22%3A%5B%22toast.pid%22%5D%7D \
+             HTTP/1.1",
+        );
+    }
+
+    #[test]
+    fn commit_container_change() {
+        let (_dir, client, handle) = serve(vec![json_response(
+            "201 Created",
+            &json!({ "Id": "sha256:abc" }),
+        )]);
+
+        client
+            .commit_container(
+                "abc123",
+                "toast:toast-abc",
+                "LABEL \"toast.temporary\"=\"false\"",
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap();
+
+        let requests = handle.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /commit?container=abc123&repo=toast&tag=toast-abc&changes=LABEL%20%22toast.\
+             temporary%22%3D%22false%22 HTTP/1.1",
+        );
+    }
+
+    #[test]
+    fn create_container_config() {
+        let (_dir, client, handle) = serve(vec![json_response(
+            "201 Created",
+            &json!({ "Id": "abc123", "Warnings": [] }),
+        )]);
+        let mut labels = BTreeMap::new();
+        labels.insert("toast.task".to_owned(), "build".to_owned());
+
+        assert_eq!(
+            client
+                .create_container(
+                    "debian",
+                    "/scratch",
+                    &["FOO=bar".to_owned()],
+                    &[Mount {
+                        source: Path::new("/home/foo").to_owned(),
+                        target: Path::new("/scratch/foo").to_owned(),
+                        readonly: true,
+                    }],
+                    &["3000:80".to_owned()],
+                    &["/bin/su", "-c", "echo hello", "root"],
+                    &labels,
+                    &Arc::new(AtomicBool::new(false)),
+                )
+                .unwrap(),
+            "abc123",
+        );
+
+        let requests = handle.join().unwrap();
+        assert_eq!(requests[0].request_line, "POST /containers/create HTTP/1.1",);
+        let config = serde_json::from_slice::<Value>(&requests[0].body).unwrap();
+        assert_eq!(config["Image"], "debian");
+        assert_eq!(config["WorkingDir"], "/scratch");
+        assert_eq!(config["Env"], json!(["FOO=bar"]));
+        assert_eq!(
+            config["Cmd"],
+            json!(["/bin/su", "-c", "echo hello", "root"])
+        );
+        assert_eq!(config["Labels"], json!({ "toast.task": "build" }));
+        assert_eq!(config["HostConfig"]["Init"], true);
+        assert_eq!(
+            config["HostConfig"]["Mounts"],
+            json!([{
+                "Type": "bind",
+                "Source": "/home/foo",
+                "Target": "/scratch/foo",
+                "ReadOnly": true,
+            }]),
+        );
+        assert_eq!(
+            config["HostConfig"]["PortBindings"],
+            json!({ "80/tcp": [{ "HostIp": "", "HostPort": "3000" }] }),
+        );
+    }
+
+    #[test]
+    fn copy_into_container_streams_archive() {
+        let (_dir, client, handle) = serve(vec![b"HTTP/1.1 200 OK\r\n\r\n".to_vec()]);
+
+        client
+            .copy_into_container(
+                "abc123",
+                |writer| {
+                    writer.write_all(b"foo").unwrap();
+                    writer.write_all(b"bar").unwrap();
+                    Ok(())
+                },
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap();
+
+        let requests = handle.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "PUT /containers/abc123/archive?path=%2F HTTP/1.1",
+        );
+        assert_eq!(requests[0].body, b"foobar".to_vec());
+    }
+
+    #[test]
+    fn copy_from_container_extracts_archive() {
+        let mut archive = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, "dist/foo.txt", &b"foo"[..])
+            .unwrap();
+        let archive = archive.into_inner().unwrap();
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-tar\r\nContent-Length: {}\r\n\r\n",
+            archive.len(),
+        )
+        .into_bytes();
+        response.extend_from_slice(&archive);
+        let (dir, client, handle) = serve(vec![response]);
+
+        let destination = dir.path().join("output");
+        client
+            .copy_from_container(
+                "abc123",
+                Path::new("/scratch/dist/."),
+                &destination,
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap();
+
+        assert_eq!(read_to_string(destination.join("foo.txt")).unwrap(), "foo");
+
+        let requests = handle.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "GET /containers/abc123/archive?path=%2Fscratch%2Fdist HTTP/1.1",
+        );
+    }
+
+    #[test]
+    fn pull_image_error_in_stream() {
+        let (_dir, client, handle) = serve(vec![
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              1d\r\n{\"status\":\"Pulling from foo\"}\r\n\
+              19\r\n\n{\"error\":\"unauthorized\"}\r\n\
+              0\r\n\r\n"
+                .to_vec(),
+        ]);
+
+        match client.pull_image("foo", &Arc::new(AtomicBool::new(false))) {
+            Err(Failure::System(message, _)) => {
+                assert!(message.ends_with("unauthorized"));
+            }
+            _ => panic!("The pull should have failed."),
+        }
+
+        let requests = handle.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /images/create?fromImage=foo&tag=latest HTTP/1.1",
+        );
+    }
+}
@@ -63,9 +63,40 @@ pub fn hash_read<R: Read>(input: &mut R) -> Result<String, Failure> {
     Ok(hex::encode(hasher.result()))
 }
 
-// Determine the initial cache key. [ref:cache_prefix]
-pub fn initial_key(image: &str) -> String {
-    format!("toast-{}", image.crypto_hash())
+// A reader which computes a cryptographic hash of the data as it passes through. Once all the data
+// has been read, `finish` returns the same hash that `hash_read` would have computed.
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // Read any remaining data and return the hash of everything that was read.
+    pub fn finish(mut self) -> Result<String, Failure> {
+        io::copy(&mut self, &mut io::sink()).map_err(failure::system("Unable to compute hash."))?;
+        Ok(hex::encode(self.hasher.result()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.input(&buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+// Determine the initial cache key from the base image and its content digest, so the cache is
+// invalidated when a tag is published again. [ref:cache_prefix]
+pub fn initial_key(image: &str, digest: &str) -> String {
+    format!("toast-{}", combine(image, digest))
 }
 
 // Determine the cache key of a task based on the cache key of the previous task in the schedule (or
@@ -107,8 +138,16 @@ pub fn key(
     // Location
     cache_key = combine(&cache_key, &task.location);
 
-    // User
+    // User, and whether the input files are owned by the user
     cache_key = combine(&cache_key, &task.user);
+    cache_key = combine(
+        &cache_key,
+        if task.chown_input_paths {
+            "+chown"
+        } else {
+            "-chown"
+        },
+    );
 
     // Command
     cache_key = combine(&cache_key, &task.command);
@@ -122,10 +161,10 @@ pub fn key(
 #[cfg(test)]
 mod tests {
     use crate::{
-        cache::{combine, hash_read, key, CryptoHash},
-        toastfile::{Task, DEFAULT_LOCATION, DEFAULT_USER},
+        cache::{combine, hash_read, initial_key, key, CryptoHash, HashingReader},
+        toastfile::{InputPath, SpecialFiles, Task, DEFAULT_LOCATION, DEFAULT_USER},
     };
-    use std::{collections::HashMap, path::Path};
+    use std::{collections::HashMap, io::Read, path::Path};
 
     #[test]
     fn hash_str_pure() {
@@ -187,6 +226,47 @@ mod tests {
         assert_ne!(hash_read(&mut str1).unwrap(), hash_read(&mut str2).unwrap());
     }
 
+    #[test]
+    fn hashing_reader_matches_hash_read() {
+        let mut str1 = b"foo" as &[u8];
+        let mut reader = HashingReader::new(b"foo" as &[u8]);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"foo");
+        assert_eq!(reader.finish().unwrap(), hash_read(&mut str1).unwrap());
+    }
+
+    #[test]
+    fn hashing_reader_finish_reads_remaining_data() {
+        let mut str1 = b"foo" as &[u8];
+        let reader = HashingReader::new(b"foo" as &[u8]);
+        assert_eq!(reader.finish().unwrap(), hash_read(&mut str1).unwrap());
+    }
+
+    #[test]
+    fn initial_key_pure() {
+        assert_eq!(
+            initial_key("debian", "sha256:abc"),
+            initial_key("debian", "sha256:abc"),
+        );
+    }
+
+    #[test]
+    fn initial_key_image() {
+        assert_ne!(
+            initial_key("debian", "sha256:abc"),
+            initial_key("ubuntu", "sha256:abc"),
+        );
+    }
+
+    #[test]
+    fn initial_key_digest() {
+        assert_ne!(
+            initial_key("debian", "sha256:abc"),
+            initial_key("debian", "sha256:def"),
+        );
+    }
+
     #[test]
     fn key_noop() {
         let previous_key = "corge";
@@ -199,9 +279,18 @@ mod tests {
             cache: true,
             environment,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -230,10 +319,19 @@ mod tests {
             dependencies: vec![],
             cache: true,
             environment,
-            input_paths: vec![Path::new("flob").to_owned()],
+            input_paths: vec![InputPath::Path(Path::new("flob").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -262,9 +360,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -299,9 +406,18 @@ mod tests {
             cache: true,
             environment: environment1,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -314,9 +430,18 @@ mod tests {
             cache: true,
             environment: environment2,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -351,9 +476,18 @@ mod tests {
             cache: true,
             environment: environment1,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -366,9 +500,18 @@ mod tests {
             cache: true,
             environment: environment2,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -400,9 +543,18 @@ mod tests {
             cache: true,
             environment,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -431,10 +583,19 @@ mod tests {
             dependencies: vec![],
             cache: true,
             environment: HashMap::new(),
-            input_paths: vec![Path::new("flob").to_owned()],
+            input_paths: vec![InputPath::Path(Path::new("flob").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -462,9 +623,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new("/foo").to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -477,9 +647,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new("/bar").to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -506,9 +685,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: "foo".to_owned(),
@@ -521,9 +709,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: "bar".to_owned(),
@@ -540,6 +737,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn key_chown_input_paths() {
+        let previous_key = "corge";
+
+        let task1 = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: "echo wibble".to_owned(),
+        };
+
+        let task2 = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: true,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: "echo wibble".to_owned(),
+        };
+
+        let input_files_hash = "grault";
+
+        let full_environment = HashMap::new();
+
+        assert_ne!(
+            key(previous_key, &task1, input_files_hash, &full_environment),
+            key(previous_key, &task2, input_files_hash, &full_environment)
+        );
+    }
+
     #[test]
     fn key_command() {
         let previous_key = "corge";
@@ -550,9 +809,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -565,9 +833,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -0,0 +1,315 @@
+use crate::{
+    cache, failure,
+    failure::Failure,
+    format::CodeStr,
+    glob,
+    toastfile::{InputPath, Toastfile},
+};
+use std::{
+    collections::BTreeSet,
+    fs::{read_link, File, Metadata},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use walkdir::WalkDir;
+
+// Compute the hash of the contents of a file.
+fn hash_file(path: &Path) -> Result<String, Failure> {
+    cache::hash_read(&mut File::open(path).map_err(failure::system(format!(
+        "Unable to open file {}.",
+        path.to_string_lossy().code_str(),
+    )))?)
+}
+
+// Determine whether two paths could refer to some of the same files. Glob patterns are compared by
+// their literal prefixes.
+fn overlap(x: &Path, y: &Path) -> bool {
+    let x = glob::literal_prefix(x);
+    let y = glob::literal_prefix(y);
+    x.starts_with(&y) || y.starts_with(&x)
+}
+
+// Check that no task in the schedule reads the output files of an earlier task. In check mode, the
+// output files aren't written to the toastfile directory, so such a task would read stale files.
+pub fn check_schedule(schedule: &[&str], toastfile: &Toastfile) -> Result<(), Failure> {
+    for (i, task) in schedule.iter().enumerate() {
+        for input_path in toastfile.tasks[*task] // [ref:tasks_valid]
+            .input_paths
+            .iter()
+            .map(InputPath::source)
+        {
+            for earlier_task in &schedule[..i] {
+                for output_path in &toastfile.tasks[*earlier_task].output_paths {
+                    if overlap(input_path, output_path.destination()) {
+                        return Err(Failure::User(
+                            format!(
+                                "Task {} reads {}, which overlaps with the output path {} of \
+                                 task {}. The output files aren't written in check mode, so the \
+                                 task would read stale files.",
+                                task.code_str(),
+                                input_path.to_string_lossy().code_str(),
+                                output_path.destination().to_string_lossy().code_str(),
+                                earlier_task.code_str(),
+                            ),
+                            None,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Determine whether `actual` (which may not exist) matches `expected`, which is described by
+// `metadata`. Directories match if they are both directories, regardless of their contents.
+// Symbolic links match if they point to the same place. Regular files match if they have the same
+// contents.
+fn matches(expected: &Path, metadata: &Metadata, actual: &Path) -> Result<bool, Failure> {
+    let actual_metadata = match actual.symlink_metadata() {
+        Ok(actual_metadata) => actual_metadata,
+        Err(_) => return Ok(false),
+    };
+
+    if metadata.file_type().is_dir() {
+        Ok(actual_metadata.file_type().is_dir())
+    } else if metadata.file_type().is_symlink() {
+        Ok(match (read_link(expected), read_link(actual)) {
+            (Ok(expected_target), Ok(actual_target)) => expected_target == actual_target,
+            _ => false,
+        })
+    } else {
+        Ok(actual_metadata.file_type().is_file()
+            && metadata.len() == actual_metadata.len()
+            && hash_file(expected)? == hash_file(actual)?)
+    }
+}
+
+// Compare the files in `staging_dir` with their counterparts in `dir` and return the paths
+// (relative to both directories) which differ. Files which exist in `dir` but not in `staging_dir`
+// are ignored, except within `mirrored_paths`, which are expected to match exactly.
+pub fn drift(
+    staging_dir: &Path,
+    dir: &Path,
+    mirrored_paths: &[PathBuf],
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Vec<PathBuf>, Failure> {
+    // The differences are collected in a set so they are reported in order and without duplicates.
+    let mut differences = BTreeSet::new();
+
+    // Check everything which was staged against the corresponding path in `dir`.
+    let mut walker = WalkDir::new(staging_dir)
+        .min_depth(1)
+        .sort_by(|x, y| x.file_name().cmp(y.file_name()))
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        // If the user wants to stop the operation, quit now.
+        if interrupted.load(Ordering::SeqCst) {
+            return Err(Failure::Interrupted);
+        }
+
+        // Unwrap the entry.
+        let entry = entry.map_err(failure::system(format!(
+            "Unable to traverse directory {}.",
+            staging_dir.to_string_lossy().code_str(),
+        )))?;
+
+        // Fetch the metadata of the staged path.
+        let metadata = entry.metadata().map_err(failure::system(format!(
+            "Unable to fetch filesystem metadata for {}.",
+            entry.path().to_string_lossy().code_str(),
+        )))?;
+
+        // Compare the staged path with its counterpart. The `unwrap` is safe because `entry` is
+        // inside `staging_dir`.
+        let relative_path = entry.path().strip_prefix(staging_dir).unwrap();
+        if !matches(entry.path(), &metadata, &dir.join(relative_path))? {
+            differences.insert(relative_path.to_owned());
+
+            // Everything inside a mismatched directory would be reported too, which is just noise.
+            if metadata.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+        }
+    }
+
+    // Check for paths which would be deleted by mirroring.
+    for mirrored_path in mirrored_paths {
+        let mirrored_dir = dir.join(mirrored_path);
+        if mirrored_dir.symlink_metadata().is_err() {
+            continue;
+        }
+
+        let mut walker = WalkDir::new(&mirrored_dir)
+            .sort_by(|x, y| x.file_name().cmp(y.file_name()))
+            .into_iter();
+        while let Some(entry) = walker.next() {
+            // If the user wants to stop the operation, quit now.
+            if interrupted.load(Ordering::SeqCst) {
+                return Err(Failure::Interrupted);
+            }
+
+            // Unwrap the entry.
+            let entry = entry.map_err(failure::system(format!(
+                "Unable to traverse directory {}.",
+                mirrored_dir.to_string_lossy().code_str(),
+            )))?;
+
+            // Report the path if it wasn't staged. The `unwrap` is safe because `entry` is inside
+            // `mirrored_dir`, which is inside `dir`.
+            let relative_path = entry.path().strip_prefix(dir).unwrap();
+            if staging_dir.join(relative_path).symlink_metadata().is_err() {
+                differences.insert(relative_path.to_owned());
+
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+            }
+        }
+    }
+
+    Ok(differences.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        check::{check_schedule, drift},
+        toastfile,
+    };
+    use std::{
+        fs::{create_dir_all, write},
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+        sync::{atomic::AtomicBool, Arc},
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn drift_none() {
+        let staging_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+
+        for root in &[staging_dir.path(), dir.path()] {
+            create_dir_all(root.join("foo")).unwrap();
+            write(root.join("foo/bar.txt"), "bar").unwrap();
+            symlink("bar.txt", root.join("foo/baz.txt")).unwrap();
+        }
+        write(dir.path().join("foo/qux.txt"), "qux").unwrap();
+
+        assert_eq!(
+            drift(
+                staging_dir.path(),
+                dir.path(),
+                &[],
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap(),
+            Vec::<PathBuf>::new(),
+        );
+    }
+
+    #[test]
+    fn drift_changed() {
+        let staging_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+
+        create_dir_all(staging_dir.path().join("foo/bar")).unwrap();
+        write(staging_dir.path().join("foo/bar/baz.txt"), "baz").unwrap();
+        write(staging_dir.path().join("foo/qux.txt"), "qux").unwrap();
+        write(staging_dir.path().join("foo/quux.txt"), "quux").unwrap();
+        create_dir_all(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo/bar"), "").unwrap();
+        write(dir.path().join("foo/qux.txt"), "QUX").unwrap();
+
+        assert_eq!(
+            drift(
+                staging_dir.path(),
+                dir.path(),
+                &[],
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap(),
+            vec![
+                Path::new("foo/bar").to_owned(),
+                Path::new("foo/quux.txt").to_owned(),
+                Path::new("foo/qux.txt").to_owned(),
+            ],
+        );
+    }
+
+    #[test]
+    fn drift_mirrored() {
+        let staging_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+
+        for root in &[staging_dir.path(), dir.path()] {
+            create_dir_all(root.join("foo")).unwrap();
+            write(root.join("foo/bar.txt"), "bar").unwrap();
+        }
+        create_dir_all(dir.path().join("foo/baz")).unwrap();
+        write(dir.path().join("foo/baz/qux.txt"), "qux").unwrap();
+
+        assert_eq!(
+            drift(
+                staging_dir.path(),
+                dir.path(),
+                &[Path::new("foo").to_owned()],
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap(),
+            vec![Path::new("foo/baz").to_owned()],
+        );
+    }
+
+    #[test]
+    fn check_schedule_independent() {
+        let toastfile = toastfile::parse(
+            r#"
+image: debian
+tasks:
+  foo:
+    input_paths:
+      - src
+    output_paths:
+      - foo.txt
+  bar:
+    dependencies:
+      - foo
+    input_paths:
+      - src/bar
+    output_paths:
+      - out/bar.txt
+"#,
+        )
+        .unwrap();
+
+        assert!(check_schedule(&["foo", "bar"], &toastfile).is_ok());
+    }
+
+    #[test]
+    fn check_schedule_stale_input() {
+        let toastfile = toastfile::parse(
+            r#"
+image: debian
+tasks:
+  foo:
+    output_paths:
+      - out/foo.txt
+  bar:
+    dependencies:
+      - foo
+    input_paths:
+      - out/**/*.txt
+"#,
+        )
+        .unwrap();
+
+        assert!(check_schedule(&["foo"], &toastfile).is_ok());
+        assert!(check_schedule(&["foo", "bar"], &toastfile).is_err());
+    }
+}
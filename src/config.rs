@@ -2,6 +2,7 @@ use crate::{failure, failure::Failure};
 use serde::{Deserialize, Serialize};
 
 pub const REPO_DEFAULT: &str = "toast";
+pub const CONTAINER_ENGINE_DEFAULT: &str = "docker";
 pub const EMPTY_CONFIG: &str = "{}";
 
 // A program configuration
@@ -22,6 +23,24 @@ pub struct Config {
 
     #[serde(default = "default_write_remote_cache")]
     pub write_remote_cache: bool,
+
+    #[serde(default = "default_memoize_input_hashes")]
+    pub memoize_input_hashes: bool,
+
+    #[serde(default = "default_stream_input_paths")]
+    pub stream_input_paths: bool,
+
+    #[serde(default = "default_chown_output_paths")]
+    pub chown_output_paths: bool,
+
+    #[serde(default = "default_container_engine")]
+    pub container_engine: String,
+
+    #[serde(default = "default_docker_api")]
+    pub docker_api: bool,
+
+    #[serde(default = "default_pull_base_image")]
+    pub pull_base_image: bool,
 }
 
 fn default_docker_repo() -> String {
@@ -44,6 +63,30 @@ fn default_write_remote_cache() -> bool {
     false
 }
 
+fn default_memoize_input_hashes() -> bool {
+    true
+}
+
+fn default_stream_input_paths() -> bool {
+    false
+}
+
+fn default_chown_output_paths() -> bool {
+    false
+}
+
+fn default_container_engine() -> String {
+    CONTAINER_ENGINE_DEFAULT.to_owned()
+}
+
+fn default_docker_api() -> bool {
+    false
+}
+
+fn default_pull_base_image() -> bool {
+    false
+}
+
 // Parse a program configuration.
 pub fn parse(config: &str) -> Result<Config, Failure> {
     serde_yaml::from_str(config).map_err(failure::user("Syntax error."))
@@ -61,6 +104,12 @@ mod tests {
             write_local_cache: true,
             read_remote_cache: false,
             write_remote_cache: false,
+            memoize_input_hashes: true,
+            stream_input_paths: false,
+            chown_output_paths: false,
+            container_engine: "docker".to_owned(),
+            docker_api: false,
+            pull_base_image: false,
         };
 
         assert_eq!(parse(EMPTY_CONFIG).unwrap(), result);
@@ -74,6 +123,12 @@ read_local_cache: false
 write_local_cache: false
 read_remote_cache: true
 write_remote_cache: true
+memoize_input_hashes: false
+stream_input_paths: true
+chown_output_paths: true
+container_engine: podman
+docker_api: true
+pull_base_image: true
     "#
         .trim();
 
@@ -83,6 +138,12 @@ write_remote_cache: true
             write_local_cache: false,
             read_remote_cache: true,
             write_remote_cache: true,
+            memoize_input_hashes: false,
+            stream_input_paths: true,
+            chown_output_paths: true,
+            container_engine: "podman".to_owned(),
+            docker_api: true,
+            pull_base_image: true,
         };
 
         assert_eq!(parse(config).unwrap(), result);
@@ -1,191 +1,1061 @@
-use crate::{failure, failure::Failure, format::CodeStr, spinner::spin};
+use crate::{
+    api,
+    api::Mount,
+    failure,
+    failure::Failure,
+    format::CodeStr,
+    glob,
+    spinner::spin,
+    toastfile::{OutputMode, OutputPath},
+};
+use serde_json::{json, Value};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    ffi::CString,
     fs::{copy, create_dir_all, read_link, rename, symlink_metadata, Metadata},
     io,
-    io::Read,
-    os::unix::fs::symlink,
+    io::{Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, MetadataExt},
+    },
     path::{Path, PathBuf},
-    process::{ChildStdin, Command, Stdio},
+    process::{Command, Stdio},
     string::ToString,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
 };
-use tempfile::tempdir;
+use tempfile::{tempdir, Builder};
 use uuid::Uuid;
 use walkdir::WalkDir;
 
-// Construct a random image tag.
-pub fn random_tag() -> String {
-    Uuid::new_v4()
-        .to_simple()
-        .encode_lower(&mut Uuid::encode_buffer())
-        .to_owned()
+// The container engines Toast knows about. They all have command-line interfaces which are mostly
+// compatible with Docker's, and the differences are handled by the functions in this module.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Flavor {
+    Docker,
+    Podman,
 }
 
-// Query whether an image exists locally.
-pub fn image_exists(image: &str, interrupted: &Arc<AtomicBool>) -> Result<bool, Failure> {
-    debug!("Checking existence of image {}\u{2026}", image.code_str());
+// A container engine, identified by the binary which implements its command-line interface. If
+// there is an API client, most operations use it instead of the command-line interface.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Engine {
+    pub binary: String,
+    pub flavor: Flavor,
+    pub api: Option<api::Client>,
+}
 
-    match run_quiet(
-        "Checking existence of image\u{2026}",
-        "The image doesn't exist.",
-        &["image", "inspect", image],
-        interrupted,
-    ) {
-        Ok(_) => Ok(true),
-        Err(Failure::Interrupted) => Err(Failure::Interrupted),
-        Err(Failure::System(_, _)) | Err(Failure::User(_, _)) => Ok(false),
+impl Engine {
+    // Determine the flavor of an engine from the name of its binary, e.g., `/usr/bin/podman`.
+    // Anything unrecognized (such as `nerdctl`) is assumed to behave like Docker.
+    pub fn new(binary: &str) -> Self {
+        let flavor = if Path::new(binary)
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().starts_with("podman"))
+        {
+            Flavor::Podman
+        } else {
+            Flavor::Docker
+        };
+
+        Engine {
+            binary: binary.to_owned(),
+            flavor,
+            api: None,
+        }
     }
 }
 
-// Push an image.
-pub fn push_image(image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
-    debug!("Pushing image {}\u{2026}", image.code_str());
+// A tagged image in the local image store
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImageSummary {
+    // The name of the image, including the tag
+    pub name: String,
 
-    run_quiet(
-        "Pushing image\u{2026}",
-        "Unable to push image.",
-        &["image", "push", image],
-        interrupted,
-    )
-    .map(|_| ())
+    // The size of the image in bytes
+    pub size: u64,
+
+    // When the image was created, in seconds since the Unix epoch, if the engine reported it
+    pub created: Option<u64>,
 }
 
-// Pull an image.
-pub fn pull_image(image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
-    debug!("Pulling image {}\u{2026}", image.code_str());
+// A container or image with its labels
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Labeled {
+    // The ID of the container or image
+    pub id: String,
 
-    run_quiet(
-        "Pulling image\u{2026}",
-        "Unable to pull image.",
-        &["image", "pull", image],
-        interrupted,
-    )
-    .map(|_| ())
-}
+    // The names of the image, including the tags (always empty for containers)
+    pub names: Vec<String>,
 
-// Delete an image.
-pub fn delete_image(image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
-    debug!("Deleting image {}\u{2026}", image.code_str());
+    // The labels, which may have been inherited from the image
+    pub labels: BTreeMap<String, String>,
+}
 
-    run_quiet(
-        "Deleting image\u{2026}",
-        "Unable to delete image.",
-        &["image", "rm", "--force", image],
-        interrupted,
-    )
-    .map(|_| ())
+// The operations Toast performs on images and containers. `Engine` implements them with a real
+// container engine, and the tests use an in-memory fake instead.
+pub trait ContainerBackend: Send + Sync {
+    // Query whether an image exists locally.
+    fn image_exists(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<bool, Failure>;
+
+    // Determine the content digest of a local image.
+    fn image_digest(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<String, Failure>;
+
+    // Push an image.
+    fn push_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure>;
+
+    // Pull an image.
+    fn pull_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure>;
+
+    // Delete an image.
+    fn delete_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure>;
+
+    // List the tagged images in a repository.
+    fn list_images(
+        &self,
+        repository: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<ImageSummary>, Failure>;
+
+    // List the tagged images which have all the given labels. Each filter is either the name of a
+    // label or a `name=value` pair.
+    fn list_labeled_images(
+        &self,
+        filters: &[String],
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Labeled>, Failure>;
+
+    // List the containers, running or not, which have all the given labels. The filters are the
+    // same as for `list_labeled_images`.
+    fn list_labeled_containers(
+        &self,
+        filters: &[String],
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Labeled>, Failure>;
+
+    // Create a container with some labels and return its ID. Before the command runs, the owner of
+    // any `chown_paths` will be changed to `user`.
+    #[allow(clippy::too_many_arguments)]
+    fn create_container(
+        &self,
+        image: &str,
+        source_dir: &Path,
+        environment: &HashMap<String, String>,
+        mount_paths: &[PathBuf],
+        mount_readonly: bool,
+        ports: &[String],
+        location: &Path,
+        user: &str,
+        command: &str,
+        chown_paths: &[PathBuf],
+        labels: &BTreeMap<String, String>,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<String, Failure>;
+
+    // Copy files into a container. The `writer` writes a tar archive of the files to the given
+    // stream.
+    fn stream_into_container(
+        &self,
+        container: &str,
+        writer: &mut dyn FnMut(&mut dyn Write) -> Result<(), Failure>,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure>;
+
+    // Copy a single path from a container to a path on the host which must not exist yet.
+    fn copy_path_from_container(
+        &self,
+        container: &str,
+        source: &Path,
+        destination: &Path,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure>;
+
+    // Start a container and wait for its command to finish.
+    fn start_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure>;
+
+    // Stop a container.
+    fn stop_container(&self, container: &str, interrupted: &Arc<AtomicBool>)
+        -> Result<(), Failure>;
+
+    // Commit a container to an image with some labels.
+    fn commit_container(
+        &self,
+        container: &str,
+        image: &str,
+        labels: &BTreeMap<String, String>,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure>;
+
+    // Delete a container.
+    fn delete_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure>;
+
+    // Run an interactive shell.
+    fn spawn_shell(
+        &self,
+        image: &str,
+        environment: &HashMap<String, String>,
+        location: &Path,
+        user: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure>;
 }
 
-// Create a container and return its ID.
-#[allow(clippy::too_many_arguments)]
-pub fn create_container(
-    image: &str,
-    source_dir: &Path,
-    environment: &HashMap<String, String>,
-    mount_paths: &[PathBuf],
-    mount_readonly: bool,
-    ports: &[String],
-    location: &Path,
-    user: &str,
-    command: &str,
-    interrupted: &Arc<AtomicBool>,
-) -> Result<String, Failure> {
-    debug!("Creating container from image {}\u{2026}", image.code_str(),);
+impl ContainerBackend for Engine {
+    // Query whether an image exists locally.
+    fn image_exists(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<bool, Failure> {
+        debug!("Checking existence of image {}\u{2026}", image.code_str());
 
-    let workdir = location.to_string_lossy();
+        let result = if let Some(client) = &self.api {
+            let _guard = spin("Checking existence of image\u{2026}");
+            client.inspect_image(image, interrupted).map(|_| ())
+        } else {
+            run_quiet(
+                self,
+                "Checking existence of image\u{2026}",
+                "The image doesn't exist.",
+                &["image", "inspect", image],
+                interrupted,
+            )
+            .map(|_| ())
+        };
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(Failure::Interrupted) => Err(Failure::Interrupted),
+            Err(Failure::System(_, _)) | Err(Failure::User(_, _)) => Ok(false),
+        }
+    }
 
-    let mut environment_pairs = Vec::new();
+    // Determine the content digest of a local image.
+    fn image_digest(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<String, Failure> {
+        debug!("Inspecting image {}\u{2026}", image.code_str());
 
-    for (variable, value) in environment {
-        environment_pairs.push(format!("{}={}", variable, value)); // [ref:env_var_equals]
+        let details = if let Some(client) = &self.api {
+            let _guard = spin("Inspecting image\u{2026}");
+            client.inspect_image(image, interrupted)?
+        } else {
+            // The command prints a list with an entry for each image.
+            let output = run_quiet(
+                self,
+                "Inspecting image\u{2026}",
+                "Unable to inspect image.",
+                &["image", "inspect", image],
+                interrupted,
+            )?;
+            serde_json::from_str::<Value>(&output)
+                .map_err(failure::system("Unable to inspect image."))?[0]
+                .take()
+        };
+
+        digest(image, &details).ok_or_else(|| {
+            Failure::System(
+                format!(
+                    "Unable to determine the digest of image {}.",
+                    image.code_str()
+                ),
+                None,
+            )
+        })
     }
 
-    let mut mount_options = Vec::new();
-    for path in mount_paths {
-        // [ref:mount_paths_no_commas]
-        if mount_readonly {
-            mount_options.push(format!(
-                "type=bind,source={},target={},readonly",
-                source_dir.join(path).to_string_lossy(),
-                location.join(path).to_string_lossy()
-            ));
-        } else {
-            mount_options.push(format!(
-                "type=bind,source={},target={}",
-                source_dir.join(path).to_string_lossy(),
-                location.join(path).to_string_lossy()
-            ));
+    // Push an image.
+    fn push_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        debug!("Pushing image {}\u{2026}", image.code_str());
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Pushing image\u{2026}");
+            return client.push_image(image, interrupted);
+        }
+
+        run_quiet(
+            self,
+            "Pushing image\u{2026}",
+            "Unable to push image.",
+            &["image", "push", image],
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Pull an image.
+    fn pull_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        debug!("Pulling image {}\u{2026}", image.code_str());
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Pulling image\u{2026}");
+            return client.pull_image(image, interrupted);
+        }
+
+        run_quiet(
+            self,
+            "Pulling image\u{2026}",
+            "Unable to pull image.",
+            &["image", "pull", image],
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Delete an image.
+    fn delete_image(&self, image: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        debug!("Deleting image {}\u{2026}", image.code_str());
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Deleting image\u{2026}");
+            return client.delete_image(image, interrupted);
+        }
+
+        run_quiet(
+            self,
+            "Deleting image\u{2026}",
+            "Unable to delete image.",
+            &["image", "rm", "--force", image],
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // List the tagged images in a repository.
+    fn list_images(
+        &self,
+        repository: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<ImageSummary>, Failure> {
+        debug!(
+            "Listing images in repository {}\u{2026}",
+            repository.code_str()
+        );
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Listing images\u{2026}");
+
+            // An image can have several tags, and not all of them are necessarily in the
+            // repository.
+            let mut images = vec![];
+            for details in client.list_images(&json!({ "reference": [repository] }), interrupted)? {
+                for name in details["RepoTags"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                    .filter(|name| api::split_reference(name).0 == repository)
+                {
+                    images.push(ImageSummary {
+                        name: name.to_owned(),
+                        size: details["Size"].as_u64().unwrap_or(0),
+                        created: details["Created"].as_u64(),
+                    });
+                }
+            }
+
+            return Ok(images);
         }
+
+        // The command prints the name of each image on its own line. Images without a tag are
+        // listed with a tag of `<none>`.
+        let names = run_quiet(
+            self,
+            "Listing images\u{2026}",
+            "Unable to list images.",
+            &[
+                "image",
+                "ls",
+                "--format",
+                "{{.Repository}}:{{.Tag}}",
+                repository,
+            ],
+            interrupted,
+        )?
+        .lines()
+        .filter(|name| !name.ends_with(":<none>"))
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+
+        if names.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // Inspect all the images at once to find their sizes and creation times. The command prints
+        // a list with an entry for each image, in the order they were given.
+        let mut args = vec!["image", "inspect"];
+        args.extend(names.iter().map(String::as_str));
+        let output = run_quiet(
+            self,
+            "Inspecting images\u{2026}",
+            "Unable to inspect images.",
+            &args,
+            interrupted,
+        )?;
+        let details = serde_json::from_str::<Vec<Value>>(&output)
+            .map_err(failure::system("Unable to inspect images."))?;
+
+        Ok(names
+            .into_iter()
+            .zip(details)
+            .map(|(name, details)| ImageSummary {
+                name,
+                size: details["Size"].as_u64().unwrap_or(0),
+                created: details["Created"].as_str().and_then(parse_timestamp),
+            })
+            .collect())
     }
 
-    // Why `--init`? (1) PID 1 is supposed to reap orphaned zombie processes, otherwise they can
-    // accumulate. Bash does this, but we run `/bin/sh` in the container, which may or may not be
-    // Bash. So `--init` runs Tini (https://github.com/krallin/tini) as PID 1, which properly reaps
-    // orphaned zombies. (2) PID 1 also does not exhibit the default behavior (crashing) for signals
-    // like SIGINT and SIGTERM. However, PID 1 can still handle these signals by explicitly trapping
-    // them. Tini traps these signals and forwards them to the child process. Then the default
-    // signal handling behavior of the child process (in our case, `/bin/sh`) works normally.
-    // [tag:--init]
-    let mut args = vec!["container", "create", "--init", "--workdir", &workdir];
+    // List the tagged images which have all the given labels.
+    fn list_labeled_images(
+        &self,
+        filters: &[String],
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Labeled>, Failure> {
+        debug!("Listing labeled images\u{2026}");
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Listing images\u{2026}");
+            return Ok(client
+                .list_images(&json!({ "label": filters }), interrupted)?
+                .iter()
+                .map(|details| Labeled {
+                    id: details["Id"].as_str().unwrap_or("").to_owned(),
+                    names: strings(&details["RepoTags"]),
+                    labels: labels(&details["Labels"]),
+                })
+                .collect());
+        }
 
-    args.extend(
-        environment_pairs
+        // The command prints the ID of each image once for every tag, and `image inspect` reports
+        // the tags and labels.
+        let mut ids = run_list(self, "image", filters, interrupted)?;
+        ids.sort();
+        ids.dedup();
+        Ok(inspect_labeled(self, "image", &ids, interrupted)?
             .iter()
-            .flat_map(|pair| vec!["--env", pair])
-            .collect::<Vec<_>>(),
-    );
+            .map(|details| Labeled {
+                id: details["Id"].as_str().unwrap_or("").to_owned(),
+                names: strings(&details["RepoTags"]),
+                labels: labels(&details["Config"]["Labels"]),
+            })
+            .collect())
+    }
+
+    // List the containers, running or not, which have all the given labels.
+    fn list_labeled_containers(
+        &self,
+        filters: &[String],
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Labeled>, Failure> {
+        debug!("Listing labeled containers\u{2026}");
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Listing containers\u{2026}");
+            return Ok(client
+                .list_containers(&json!({ "label": filters }), interrupted)?
+                .iter()
+                .map(|details| Labeled {
+                    id: details["Id"].as_str().unwrap_or("").to_owned(),
+                    names: vec![],
+                    labels: labels(&details["Labels"]),
+                })
+                .collect());
+        }
 
-    args.extend(
-        mount_options
+        let ids = run_list(self, "container", filters, interrupted)?;
+        Ok(inspect_labeled(self, "container", &ids, interrupted)?
             .iter()
-            .flat_map(|options| vec!["--mount", options])
-            .collect::<Vec<_>>(),
-    );
+            .map(|details| Labeled {
+                id: details["Id"].as_str().unwrap_or("").to_owned(),
+                names: vec![],
+                labels: labels(&details["Config"]["Labels"]),
+            })
+            .collect())
+    }
+
+    // Create a container with some labels and return its ID. Before the command runs, the owner of
+    // any `chown_paths` will be changed to `user`.
+    #[allow(clippy::too_many_arguments)]
+    fn create_container(
+        &self,
+        image: &str,
+        source_dir: &Path,
+        environment: &HashMap<String, String>,
+        mount_paths: &[PathBuf],
+        mount_readonly: bool,
+        ports: &[String],
+        location: &Path,
+        user: &str,
+        command: &str,
+        chown_paths: &[PathBuf],
+        labels: &BTreeMap<String, String>,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<String, Failure> {
+        debug!("Creating container from image {}\u{2026}", image.code_str(),);
+
+        let workdir = location.to_string_lossy();
+
+        let mut environment_pairs = Vec::new();
+
+        for (variable, value) in environment {
+            environment_pairs.push(format!("{}={}", variable, value)); // [ref:env_var_equals]
+        }
 
-    for port in ports {
-        args.extend(vec!["--publish", port]);
+        let mounts = mount_paths
+            .iter()
+            .map(|path| Mount {
+                source: source_dir.join(path),
+                target: location.join(path),
+                readonly: mount_readonly,
+            })
+            .collect::<Vec<_>>();
+
+        let chown_paths = chown_paths
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>();
+        let mut container_command = vec![];
+        if chown_paths.is_empty() {
+            container_command.extend(vec!["/bin/su", "-c", command, user]);
+        } else {
+            container_command.extend(vec![
+                "/bin/sh",
+                "-c",
+                CHOWN_AND_RUN_SCRIPT,
+                "/bin/sh",
+                user,
+                command,
+            ]);
+            container_command.extend(chown_paths.iter().map(AsRef::as_ref));
+        }
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Creating container\u{2026}");
+            return client.create_container(
+                image,
+                &workdir,
+                &environment_pairs,
+                &mounts,
+                ports,
+                &container_command,
+                labels,
+                interrupted,
+            );
+        }
+
+        let mut mount_options = Vec::new();
+        for mount in &mounts {
+            // [ref:mount_paths_no_commas]
+            if mount.readonly {
+                mount_options.push(format!(
+                    "type=bind,source={},target={},readonly",
+                    mount.source.to_string_lossy(),
+                    mount.target.to_string_lossy()
+                ));
+            } else {
+                mount_options.push(format!(
+                    "type=bind,source={},target={}",
+                    mount.source.to_string_lossy(),
+                    mount.target.to_string_lossy()
+                ));
+            }
+        }
+
+        // Why `--init`? (1) PID 1 is supposed to reap orphaned zombie processes, otherwise they can
+        // accumulate. Bash does this, but we run `/bin/sh` in the container, which may or may not
+        // be Bash. So `--init` runs Tini (https://github.com/krallin/tini) as PID 1, which properly
+        // reaps orphaned zombies. (2) PID 1 also does not exhibit the default behavior (crashing)
+        // for signals like SIGINT and SIGTERM. However, PID 1 can still handle these signals by
+        // explicitly trapping them. Tini traps these signals and forwards them to the child
+        // process. Then the default signal handling behavior of the child process (in our case,
+        // `/bin/sh`) works normally.
+        // [tag:--init]
+        let mut args = vec!["container", "create", "--init", "--workdir", &workdir];
+
+        args.extend(
+            environment_pairs
+                .iter()
+                .flat_map(|pair| vec!["--env", pair])
+                .collect::<Vec<_>>(),
+        );
+
+        args.extend(
+            mount_options
+                .iter()
+                .flat_map(|options| vec!["--mount", options])
+                .collect::<Vec<_>>(),
+        );
+
+        for port in ports {
+            args.extend(vec!["--publish", port]);
+        }
+
+        let label_pairs = labels
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>();
+        for pair in &label_pairs {
+            args.extend(vec!["--label", pair]);
+        }
+
+        args.push(image);
+        args.extend(container_command);
+
+        Ok(run_quiet(
+            self,
+            "Creating container\u{2026}",
+            "Unable to create container.",
+            &args,
+            interrupted,
+        )?
+        .trim()
+        .to_owned())
     }
 
-    args.extend(vec![image, "/bin/su", "-c", command, user]);
+    // Copy files into a container. Accepts a closure which writes a tar archive to a pipe (or to
+    // the connection to the Docker daemon), so the archive doesn't need to be stored anywhere
+    // first.
+    fn stream_into_container(
+        &self,
+        container: &str,
+        writer: &mut dyn FnMut(&mut dyn Write) -> Result<(), Failure>,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        debug!(
+            "Copying files into container {}\u{2026}",
+            container.code_str()
+        );
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Copying files into container\u{2026}");
+            return client.copy_into_container(container, writer, interrupted);
+        }
+
+        run_quiet_stdin(
+            self,
+            "Copying files into container\u{2026}",
+            "Unable to copy files into the container.",
+            &["container", "cp", "-", &format!("{}:/", container)],
+            writer,
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Copy a single path from a container to a path on the host which must not exist yet.
+    fn copy_path_from_container(
+        &self,
+        container: &str,
+        source: &Path,
+        destination: &Path,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        if let Some(client) = &self.api {
+            let _guard = spin("Copying files from the container\u{2026}");
+            return client.copy_from_container(container, source, destination, interrupted);
+        }
+
+        run_quiet(
+            self,
+            "Copying files from the container\u{2026}",
+            "Unable to copy files from the container.",
+            &[
+                "container",
+                "cp",
+                &format!("{}:{}", container, source.to_string_lossy()),
+                &destination.to_string_lossy(),
+            ],
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Start a container.
+    fn start_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        debug!("Starting container {}\u{2026}", container.code_str());
+
+        if let Some(client) = &self.api {
+            return client.start_container(container, interrupted);
+        }
+
+        run_loud(
+            self,
+            "Unable to start container.",
+            &["container", "start", "--attach", container],
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Stop a container.
+    fn stop_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        debug!("Stopping container {}\u{2026}", container.code_str());
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Stopping container\u{2026}");
+            return client.stop_container(container, interrupted);
+        }
+
+        run_quiet(
+            self,
+            "Stopping container\u{2026}",
+            "Unable to stop container.",
+            &["container", "stop", container],
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Commit a container to an image with some labels.
+    fn commit_container(
+        &self,
+        container: &str,
+        image: &str,
+        labels: &BTreeMap<String, String>,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        debug!(
+            "Committing container {} to image {}\u{2026}",
+            container.code_str(),
+            image.code_str()
+        );
+
+        // The labels are applied with a Dockerfile instruction.
+        let change = label_instruction(labels);
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Committing container\u{2026}");
+            return client.commit_container(container, image, &change, interrupted);
+        }
+
+        // Podman commits images in the OCI format by default, which can't represent some of the
+        // configuration in Docker images (e.g., `SHELL`). We ask for the Docker format to preserve
+        // it.
+        let mut args = vec!["container", "commit", "--change", &change];
+        if self.flavor == Flavor::Podman {
+            args.extend(vec!["--format", "docker"]);
+        }
+        args.extend(vec![container, image]);
+
+        run_quiet(
+            self,
+            "Committing container\u{2026}",
+            "Unable to commit container.",
+            &args,
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Delete a container.
+    fn delete_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        debug!("Deleting container {}\u{2026}", container.code_str());
+
+        if let Some(client) = &self.api {
+            let _guard = spin("Deleting container\u{2026}");
+            return client.delete_container(container, interrupted);
+        }
+
+        run_quiet(
+            self,
+            "Deleting container\u{2026}",
+            "Unable to delete container.",
+            &["container", "rm", "--force", container],
+            interrupted,
+        )
+        .map(|_| ())
+    }
+
+    // Run an interactive shell. This always uses the command-line interface, since it takes care of
+    // the terminal.
+    fn spawn_shell(
+        &self,
+        image: &str,
+        environment: &HashMap<String, String>,
+        location: &Path,
+        user: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        debug!(
+            "Spawning an interactive shell for image {}\u{2026}",
+            image.code_str()
+        );
+
+        let workdir = location.to_string_lossy();
+
+        let mut environment_pairs = Vec::new();
+
+        for (variable, value) in environment {
+            environment_pairs.push(format!("{}={}", variable, value)); // [ref:env_var_equals]
+        }
+
+        let mut args = vec![
+            "container",
+            "run",
+            "--rm",
+            "--init", // [ref:--init]
+            "--interactive",
+            "--tty",
+            "--workdir",
+            &workdir,
+        ];
+
+        args.extend(
+            environment_pairs
+                .iter()
+                .flat_map(|pair| vec!["--env", pair])
+                .collect::<Vec<_>>(),
+        );
+
+        args.extend(vec![image, "/bin/su", user]);
+
+        run_attach(self, "The shell exited with a failure.", &args, interrupted)
+    }
+}
+
+// Extract the content digest of an image from its details, as reported by `docker image inspect`.
+// The repository digest is preferred, since it identifies the image in a registry. Images which
+// were never pushed or pulled only have an ID, which is the digest of their configuration.
+fn digest(image: &str, details: &Value) -> Option<String> {
+    // Docker omits the default registry and namespace from repository names, but Podman doesn't.
+    let normalize = |repository: &str| {
+        repository
+            .trim_start_matches("docker.io/")
+            .trim_start_matches("library/")
+            .to_owned()
+    };
+    let repository = normalize(api::split_reference(image).0);
+
+    // Find a repository digest (e.g., `debian@sha256:...`) for the same repository.
+    let repo_digest = details["RepoDigests"].as_array().and_then(|repo_digests| {
+        repo_digests
+            .iter()
+            .filter_map(Value::as_str)
+            .filter_map(|repo_digest| {
+                let index = repo_digest.rfind('@')?;
+                Some((&repo_digest[..index], &repo_digest[index + 1..]))
+            })
+            .find(|(digest_repository, _)| normalize(digest_repository) == repository)
+            .map(|(_, digest)| digest.to_owned())
+    });
+
+    // Fall back to the image ID.
+    repo_digest.or_else(|| details["Id"].as_str().map(ToOwned::to_owned))
+}
+
+// List the IDs of the containers or images (depending on `kind`) which have all the given labels.
+fn run_list(
+    engine: &Engine,
+    kind: &str,
+    filters: &[String],
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Vec<String>, Failure> {
+    let filters = filters
+        .iter()
+        .map(|filter| format!("label={}", filter))
+        .collect::<Vec<_>>();
+    let mut args = vec![kind, "ls", "--quiet", "--no-trunc"];
+    if kind == "container" {
+        args.push("--all");
+    }
+    for filter in &filters {
+        args.extend(vec!["--filter", filter]);
+    }
 
     Ok(run_quiet(
-        "Creating container\u{2026}",
-        "Unable to create container.",
+        engine,
+        &format!("Listing {}s\u{2026}", kind),
+        &format!("Unable to list {}s.", kind),
         &args,
         interrupted,
     )?
-    .trim()
-    .to_owned())
+    .lines()
+    .map(ToOwned::to_owned)
+    .collect())
+}
+
+// Inspect some containers or images (depending on `kind`) at once. The command prints a list with
+// an entry for each of them.
+fn inspect_labeled(
+    engine: &Engine,
+    kind: &str,
+    ids: &[String],
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Vec<Value>, Failure> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut args = vec![kind, "inspect"];
+    args.extend(ids.iter().map(String::as_str));
+    let output = run_quiet(
+        engine,
+        &format!("Inspecting {}s\u{2026}", kind),
+        &format!("Unable to inspect {}s.", kind),
+        &args,
+        interrupted,
+    )?;
+
+    serde_json::from_str::<Vec<Value>>(&output)
+        .map_err(failure::system(format!("Unable to inspect {}s.", kind)))
+}
+
+// Construct a Dockerfile `LABEL` instruction which sets some labels. The values are quoted, and
+// backslashes, quotes, and dollar signs (which would otherwise refer to environment variables) are
+// escaped.
+fn label_instruction(labels: &BTreeMap<String, String>) -> String {
+    let quote = |string: &str| {
+        format!(
+            "\"{}\"",
+            string
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('$', "\\$"),
+        )
+    };
+
+    format!(
+        "LABEL {}",
+        labels
+            .iter()
+            .map(|(name, value)| format!("{}={}", quote(name), quote(value)))
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+// Extract the strings from a JSON array. Anything else counts as an empty array.
+fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+// Extract the labels from a JSON object, as reported by the container engine. The engine reports
+// `null` when there are none.
+fn labels(value: &Value) -> BTreeMap<String, String> {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, value)| value.as_str().map(|value| (name.clone(), value.to_owned())))
+        .collect()
+}
+
+// Parse an RFC 3339 timestamp (e.g., `2019-06-09T12:34:56.789Z`), as reported by `docker image
+// inspect`, into seconds since the Unix epoch.
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let field = |range: std::ops::Range<usize>| timestamp.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+
+    // Skip the fractional seconds, if any, and determine the offset from UTC.
+    let zone = timestamp
+        .get(19..)?
+        .trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match zone.get(0..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours = zone.get(1..3)?.parse::<i64>().ok()?;
+            let minutes = zone.get(4..6)?.parse::<i64>().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    // Count the days since the epoch using the proleptic Gregorian calendar. Shifting the start of
+    // the year to March puts the leap day at the end. See
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil for details.
+    let shifted_year = if month <= 2 { year - 1 } else { year };
+    let era = if shifted_year >= 0 {
+        shifted_year
+    } else {
+        shifted_year - 399
+    } / 400;
+    let year_of_era = shifted_year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    u64::try_from(days * 86_400 + hour * 3600 + minute * 60 + second - offset).ok()
 }
 
+// Construct a random image tag.
+pub fn random_tag() -> String {
+    Uuid::new_v4()
+        .to_simple()
+        .encode_lower(&mut Uuid::encode_buffer())
+        .to_owned()
+}
+
+// This script changes the owner of some paths (and any of their ancestors inside the working
+// directory) to a given user before running a command as that user. The arguments are the user, the
+// command, and the paths. Paths which don't exist are skipped. Besides `/bin/su`, it needs
+// `/bin/sh`, `id`, `chown`, and `dirname` in the image, as documented in the README.
+const CHOWN_AND_RUN_SCRIPT: &str = r#"
+set -eu
+user="$1"
+command="$2"
+shift 2
+owner="$(id -u "$user"):$(id -g "$user")"
+for path in "$@"; do
+  if [ ! -e "$path" ] && [ ! -L "$path" ]; then
+    continue
+  fi
+  chown -hR "$owner" "$path"
+  parent="$(dirname "$path")"
+  while [ "${parent#"$PWD"/}" != "$parent" ]; do
+    chown -h "$owner" "$parent"
+    parent="$(dirname "$parent")"
+  done
+done
+exec /bin/su -c "$command" "$user"
+"#;
+
 // Copy files into a container.
 pub fn copy_into_container<R: Read>(
+    backend: &dyn ContainerBackend,
     container: &str,
     mut tar: R,
     interrupted: &Arc<AtomicBool>,
 ) -> Result<(), Failure> {
-    debug!(
-        "Copying files into container {}\u{2026}",
-        container.code_str()
-    );
-
-    run_quiet_stdin(
-        "Copying files into container\u{2026}",
-        "Unable to copy files into the container.",
-        &["container", "cp", "-", &format!("{}:/", container)],
-        |mut stdin| {
-            io::copy(&mut tar, &mut stdin)
+    backend.stream_into_container(
+        container,
+        &mut |stdin| {
+            io::copy(&mut tar, stdin)
                 .map_err(failure::system("Unable to copy files into the container."))?;
 
             Ok(())
         },
         interrupted,
     )
-    .map(|_| ())
 }
 
 // This is a helper function for the `copy_from_container` function. The `source_path` is expected
@@ -228,19 +1098,172 @@ fn rename_or_copy_file_or_symlink(
     Ok(())
 }
 
-// Copy files from a container.
+// This is a helper function for the `copy_from_container` function. The `source_path` points to a
+// file, symlink, or directory which was copied out of the container. This function moves or copies
+// it to its final destination, merging directories with any that already exist.
+fn move_into_place(source_path: &Path, destination_path: &Path) -> Result<(), Failure> {
+    // Fetch filesystem metadata for `source_path`.
+    let source_metadata = symlink_metadata(source_path).map_err(failure::system(format!(
+        "Unable to fetch filesystem metadata for {}.",
+        source_path.to_string_lossy().code_str(),
+    )))?;
+
+    // Determine what we got from the container.
+    if source_metadata.is_dir() {
+        // It's a directory. Traverse it.
+        for entry in WalkDir::new(source_path) {
+            // If we run into an error traversing the filesystem, report it.
+            let entry = entry.map_err(failure::system(format!(
+                "Unable to traverse directory {}.",
+                source_path.to_string_lossy().code_str(),
+            )))?;
+
+            // Fetch the metadata for this entry.
+            let entry_metadata = entry.metadata().map_err(failure::system(format!(
+                "Unable to fetch filesystem metadata for {}.",
+                entry.path().to_string_lossy().code_str(),
+            )))?;
+
+            // Figure out what needs to go where. The `unwrap` is safe because `entry` is
+            // guaranteed to be inside `source_path` (or equal to it).
+            let entry_source_path = entry.path();
+            let entry_destination_path =
+                destination_path.join(entry_source_path.strip_prefix(source_path).unwrap());
+
+            // Check if the entry is a file or a directory.
+            if entry.file_type().is_dir() {
+                // It's a directory. Create a directory at the destination.
+                create_dir_all(&entry_destination_path).map_err(failure::system(format!(
+                    "Unable to create directory {}.",
+                    entry_destination_path.to_string_lossy().code_str(),
+                )))?;
+            } else {
+                // It's a file or symlink. Move or copy it to the destination.
+                rename_or_copy_file_or_symlink(
+                    entry_source_path,
+                    &entry_destination_path,
+                    &entry_metadata,
+                )?;
+            }
+        }
+    } else {
+        // It's a file or symlink. Determine the destination directory. The `unwrap` is safe
+        // because the root of the filesystem cannot be a file or symlink.
+        let destination_parent = destination_path.parent().unwrap().to_owned();
+
+        // Make sure the destination directory exists.
+        create_dir_all(&destination_parent).map_err(failure::system(format!(
+            "Unable to create directory {}.",
+            destination_parent.to_string_lossy().code_str(),
+        )))?;
+
+        // Move or copy it to the destination.
+        rename_or_copy_file_or_symlink(source_path, destination_path, &source_metadata)?;
+    }
+
+    Ok(())
+}
+
+// Change the owner of a path. If it's a symbolic link, the link itself is changed rather than what
+// it points to.
+fn lchown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // The `unsafe` is needed for calling a C function. The path is a valid C string for the
+    // duration of the call.
+    if unsafe { libc::lchown(path.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// This is a helper function for the `copy_from_container` function. It changes the owner of `path`
+// on the host, along with everything inside it if it's a directory and any ancestors up to (but not
+// including) `destination_dir`. Symbolic links themselves are changed rather than their targets.
+fn change_owner(
+    destination_dir: &Path,
+    path: &Path,
+    owner: Option<(u32, u32)>,
+) -> Result<(), Failure> {
+    // Check if there's anything to do.
+    let (uid, gid) = if let Some(owner) = owner {
+        owner
+    } else {
+        return Ok(());
+    };
+
+    // Change the owner of a single filesystem object if necessary.
+    let change = |path: &Path, metadata: &Metadata| {
+        if metadata.uid() == uid && metadata.gid() == gid {
+            return Ok(());
+        }
+
+        lchown(path, uid, gid).map_err(failure::system(format!(
+            "Unable to change the owner of {}.",
+            path.to_string_lossy().code_str(),
+        )))
+    };
+
+    // Handle the ancestors, which may have been created when the path was copied to the host.
+    if let Some(parent) = path.parent() {
+        for ancestor in parent.ancestors() {
+            if ancestor == destination_dir || !ancestor.starts_with(destination_dir) {
+                break;
+            }
+
+            change(
+                ancestor,
+                &symlink_metadata(ancestor).map_err(failure::system(format!(
+                    "Unable to fetch filesystem metadata for {}.",
+                    ancestor.to_string_lossy().code_str(),
+                )))?,
+            )?;
+        }
+    }
+
+    // Handle the path itself and its contents. The traversal doesn't follow symbolic links.
+    for entry in WalkDir::new(path) {
+        let entry = entry.map_err(failure::system(format!(
+            "Unable to traverse directory {}.",
+            path.to_string_lossy().code_str(),
+        )))?;
+
+        change(
+            entry.path(),
+            &entry.metadata().map_err(failure::system(format!(
+                "Unable to fetch filesystem metadata for {}.",
+                entry.path().to_string_lossy().code_str(),
+            )))?,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Copy files from a container. Paths may be glob patterns, which are expanded against the
+// filesystem of the container, or mappings from paths in the container to destinations on the host.
+// The sources of mappings are relative to `source_dir` unless they are absolute. If an `owner` (a
+// user ID and group ID) is given, the files are given to that user on the host. Returns the paths
+// on the host which were copied.
 pub fn copy_from_container(
+    backend: &dyn ContainerBackend,
     container: &str,
-    paths: &[PathBuf],
+    paths: &[OutputPath],
     source_dir: &Path,
     destination_dir: &Path,
+    owner: Option<(u32, u32)>,
     interrupted: &Arc<AtomicBool>,
-) -> Result<(), Failure> {
+) -> Result<Vec<PathBuf>, Failure> {
+    // This will be the list of paths copied to the host.
+    let mut copied_paths = vec![];
+
     // Copy each path from the container to the host.
-    for path in paths {
+    for output_path in paths {
         debug!(
             "Copying {} from container {}\u{2026}",
-            path.to_string_lossy().code_str(),
+            output_path.destination().to_string_lossy().code_str(),
             container.code_str()
         );
 
@@ -254,195 +1277,163 @@ pub fn copy_from_container(
         // copy/move that path to the final destination.
         let temp_dir =
             tempdir().map_err(failure::system("Unable to create temporary directory."))?;
-
-        // Figure out what needs to go where.
-        let source = source_dir.join(path);
-        let intermediate = temp_dir.path().join("data");
-        let destination = destination_dir.join(path);
-
-        // Get the path from the container.
-        run_quiet(
-            "Copying files from the container\u{2026}",
-            "Unable to copy files from the container.",
-            &[
-                "container",
-                "cp",
-                &format!("{}:{}", container, source.to_string_lossy()),
-                &intermediate.to_string_lossy(),
-            ],
-            interrupted,
-        )
-        .map(|_| ())?;
-
-        // Fetch filesystem metadata for `input_path`.
-        let intermediate_metadata =
-            symlink_metadata(&intermediate).map_err(failure::system(format!(
-                "Unable to fetch filesystem metadata for {}.",
-                intermediate.to_string_lossy().code_str(),
-            )))?;
-
-        // Determine what we got from the container.
-        if intermediate_metadata.is_dir() {
-            // It's a directory. Traverse it.
-            for entry in WalkDir::new(&intermediate) {
-                // If we run into an error traversing the filesystem, report it.
-                let entry = entry.map_err(failure::system(format!(
-                    "Unable to traverse directory {}.",
-                    intermediate.to_string_lossy().code_str(),
+        let intermediate_dir = temp_dir.path().join("data");
+
+        match output_path {
+            OutputPath::Path(path) if glob::is_glob(path) => {
+                // Docker can't expand glob patterns, so we copy the literal prefix of the pattern
+                // out of the container and expand the pattern against that copy instead.
+                let prefix = glob::literal_prefix(path);
+
+                // Figure out what needs to go where. The `unwrap` is safe because `intermediate` is
+                // inside `temp_dir`.
+                let source = source_dir.join(&prefix);
+                let intermediate = intermediate_dir.join(&prefix);
+                let intermediate_parent = intermediate.parent().unwrap();
+
+                // Make sure the parent of the intermediate path exists.
+                create_dir_all(intermediate_parent).map_err(failure::system(format!(
+                    "Unable to create directory {}.",
+                    intermediate_parent.to_string_lossy().code_str(),
                 )))?;
 
-                // Fetch the metadata for this entry.
-                let entry_metadata = entry.metadata().map_err(failure::system(format!(
-                    "Unable to fetch filesystem metadata for {}.",
-                    entry.path().to_string_lossy().code_str(),
-                )))?;
+                // Get the prefix from the container.
+                backend.copy_path_from_container(container, &source, &intermediate, interrupted)?;
 
-                // Figure out what needs to go where. The `unwrap` is safe because `entry` is
-                // guaranteed to be inside `intermediate` (or equal to it).
-                let entry_source_path = entry.path();
-                let entry_destination_path =
-                    destination.join(entry_source_path.strip_prefix(&intermediate).unwrap());
-
-                // Check if the entry is a file or a directory.
-                if entry.file_type().is_dir() {
-                    // It's a directory. Create a directory at the destination.
-                    create_dir_all(&entry_destination_path).map_err(failure::system(format!(
-                        "Unable to create directory {}.",
-                        entry_destination_path.to_string_lossy().code_str(),
-                    )))?;
-                } else {
-                    // It's a file or symlink. Move or copy it to the destination.
-                    rename_or_copy_file_or_symlink(
-                        entry_source_path,
-                        &entry_destination_path,
-                        &entry_metadata,
-                    )?;
+                // Move each match to its destination.
+                for matched_path in glob::expand(&intermediate_dir, path, interrupted)? {
+                    let destination = destination_dir.join(&matched_path);
+                    move_into_place(&intermediate_dir.join(&matched_path), &destination)?;
+                    change_owner(destination_dir, &destination, owner)?;
+                    copied_paths.push(destination);
                 }
             }
-        } else {
-            // It's a file or symlink. Determine the destination directory. The `unwrap` is safe
-            // because the root of the filesystem cannot be a file or symlink.
-            let destination_parent = destination.parent().unwrap().to_owned();
-
-            // Make sure the destination directory exists.
-            create_dir_all(&destination_parent).map_err(failure::system(format!(
-                "Unable to create directory {}.",
-                destination_parent.to_string_lossy().code_str(),
-            )))?;
+            OutputPath::Path(path) => {
+                // Get the path from the container and move it to its destination.
+                backend.copy_path_from_container(
+                    container,
+                    &source_dir.join(path),
+                    &intermediate_dir,
+                    interrupted,
+                )?;
+                let destination = destination_dir.join(path);
+                move_into_place(&intermediate_dir, &destination)?;
+                change_owner(destination_dir, &destination, owner)?;
+                copied_paths.push(destination);
+            }
+            OutputPath::Mapping { source, mode, .. } => {
+                // The source is not a glob pattern [ref:output_paths_mapping_literal]. If it's
+                // absolute, `join` returns it unchanged.
+                let source = source_dir.join(source);
+                let destination = destination_dir.join(output_path.destination());
+
+                match mode {
+                    OutputMode::Merge => {
+                        backend.copy_path_from_container(
+                            container,
+                            &source,
+                            &intermediate_dir,
+                            interrupted,
+                        )?;
+                        move_into_place(&intermediate_dir, &destination)?;
+                    }
+                    OutputMode::Mirror => {
+                        mirror_from_container(
+                            backend,
+                            container,
+                            &source,
+                            &destination,
+                            interrupted,
+                        )?;
+                    }
+                }
 
-            // Move or copy it to the destination.
-            rename_or_copy_file_or_symlink(&intermediate, &destination, &intermediate_metadata)?;
+                change_owner(destination_dir, &destination, owner)?;
+                copied_paths.push(destination);
+            }
         }
     }
 
-    Ok(())
-}
-
-// Start a container.
-pub fn start_container(container: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
-    debug!("Starting container {}\u{2026}", container.code_str());
-
-    run_loud(
-        "Unable to start container.",
-        &["container", "start", "--attach", container],
-        interrupted,
-    )
-    .map(|_| ())
-}
-
-// Stop a container.
-pub fn stop_container(container: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
-    debug!("Stopping container {}\u{2026}", container.code_str());
-
-    run_quiet(
-        "Stopping container\u{2026}",
-        "Unable to stop container.",
-        &["container", "stop", container],
-        interrupted,
-    )
-    .map(|_| ())
+    Ok(copied_paths)
 }
 
-// Commit a container to an image.
-pub fn commit_container(
+// This is a helper function for the `copy_from_container` function. It replaces `destination` with
+// a copy of `source` from the container. The copy is staged in a sibling directory of `destination`
+// and then renamed into place, so a failed copy never leaves a partially written output behind.
+fn mirror_from_container(
+    backend: &dyn ContainerBackend,
     container: &str,
-    image: &str,
+    source: &Path,
+    destination: &Path,
     interrupted: &Arc<AtomicBool>,
 ) -> Result<(), Failure> {
-    debug!(
-        "Committing container {} to image {}\u{2026}",
-        container.code_str(),
-        image.code_str()
-    );
-
-    run_quiet(
-        "Committing container\u{2026}",
-        "Unable to commit container.",
-        &["container", "commit", container, image],
-        interrupted,
-    )
-    .map(|_| ())
-}
-
-// Delete a container.
-pub fn delete_container(container: &str, interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
-    debug!("Deleting container {}\u{2026}", container.code_str());
-
-    run_quiet(
-        "Deleting container\u{2026}",
-        "Unable to delete container.",
-        &["container", "rm", "--force", container],
-        interrupted,
-    )
-    .map(|_| ())
-}
+    // Make sure the parent of the destination exists. The `unwrap` is safe because the destination
+    // is never the toastfile directory itself [ref:output_paths_mirror_not_root].
+    let destination_parent = destination.parent().unwrap();
+    create_dir_all(destination_parent).map_err(failure::system(format!(
+        "Unable to create directory {}.",
+        destination_parent.to_string_lossy().code_str(),
+    )))?;
 
-// Run an interactive shell.
-pub fn spawn_shell(
-    image: &str,
-    environment: &HashMap<String, String>,
-    location: &Path,
-    user: &str,
-    interrupted: &Arc<AtomicBool>,
-) -> Result<(), Failure> {
-    debug!(
-        "Spawning an interactive shell for image {}\u{2026}",
-        image.code_str()
-    );
+    // Create the staging directory next to the destination, so they're on the same filesystem and
+    // the final renames can't fail for that reason. It's deleted when it goes out of scope, along
+    // with anything left in it.
+    let staging_dir = Builder::new()
+        .prefix(".toast-")
+        .tempdir_in(destination_parent)
+        .map_err(failure::system(format!(
+            "Unable to create temporary directory in {}.",
+            destination_parent.to_string_lossy().code_str(),
+        )))?;
+    let new_path = staging_dir.path().join("new");
+    let old_path = staging_dir.path().join("old");
 
-    let workdir = location.to_string_lossy();
+    // Get the path from the container.
+    backend.copy_path_from_container(container, source, &new_path, interrupted)?;
 
-    let mut environment_pairs = Vec::new();
+    // Swap the new copy into place.
+    swap_into_place(&new_path, &old_path, destination)
+}
 
-    for (variable, value) in environment {
-        environment_pairs.push(format!("{}={}", variable, value)); // [ref:env_var_equals]
+// Replace `destination` (if it exists) with `new_path`, moving the original to `old_path`. If the
+// new path can't be moved into place, the original is put back, so `destination` is never left
+// missing or partially replaced.
+fn swap_into_place(new_path: &Path, old_path: &Path, destination: &Path) -> Result<(), Failure> {
+    // Move the existing destination, if there is one, out of the way.
+    let replacing = symlink_metadata(destination).is_ok();
+    if replacing {
+        rename(destination, old_path).map_err(failure::system(format!(
+            "Unable to move {} to {}.",
+            destination.to_string_lossy().code_str(),
+            old_path.to_string_lossy().code_str(),
+        )))?;
     }
 
-    let mut args = vec![
-        "container",
-        "run",
-        "--rm",
-        "--init", // [ref:--init]
-        "--interactive",
-        "--tty",
-        "--workdir",
-        &workdir,
-    ];
-
-    args.extend(
-        environment_pairs
-            .iter()
-            .flat_map(|pair| vec!["--env", pair])
-            .collect::<Vec<_>>(),
-    );
+    // Move the new copy into place. If that fails, try to put the old one back.
+    rename(new_path, destination).map_err(|e| {
+        if replacing {
+            if let Err(e) = rename(old_path, destination) {
+                error!(
+                    "Unable to restore {}: {}",
+                    destination.to_string_lossy().code_str(),
+                    e,
+                );
+            }
+        }
 
-    args.extend(vec![image, "/bin/su", user]);
+        failure::system(format!(
+            "Unable to move {} to {}.",
+            new_path.to_string_lossy().code_str(),
+            destination.to_string_lossy().code_str(),
+        ))(e)
+    })?;
 
-    run_attach("The shell exited with a failure.", &args, interrupted)
+    Ok(())
 }
 
 // Run a command and return its standard output.
 fn run_quiet(
+    engine: &Engine,
     spinner_message: &str,
     error: &str,
     args: &[&str],
@@ -456,10 +1447,13 @@ fn run_quiet(
     let was_interrupted = interrupted.load(Ordering::SeqCst);
 
     // Run the child process.
-    let child = command(args).output().map_err(failure::system(format!(
-        "{} Perhaps you don't have Docker installed.",
-        error
-    )))?;
+    let child = command(engine, args)
+        .output()
+        .map_err(failure::system(format!(
+            "{} Perhaps you don't have {} installed.",
+            error,
+            engine.binary.code_str(),
+        )))?;
 
     // Handle the result.
     if child.status.success() {
@@ -483,7 +1477,8 @@ fn run_quiet(
 
 // Run a command and return its standard output. Accepts a closure which receives a pipe to the
 // standard input stream of the child process.
-fn run_quiet_stdin<W: FnOnce(&mut ChildStdin) -> Result<(), Failure>>(
+fn run_quiet_stdin<W: FnOnce(&mut dyn Write) -> Result<(), Failure>>(
+    engine: &Engine,
     spinner_message: &str,
     error: &str,
     args: &[&str],
@@ -498,23 +1493,32 @@ fn run_quiet_stdin<W: FnOnce(&mut ChildStdin) -> Result<(), Failure>>(
     let was_interrupted = interrupted.load(Ordering::SeqCst);
 
     // Run the child process.
-    let mut child = command(args)
+    let mut child = command(engine, args)
         .stdin(Stdio::piped()) // [tag:run_quiet_stdin_piped]
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .map_err(failure::system(format!(
-            "{} Perhaps you don't have Docker installed.",
-            error
+            "{} Perhaps you don't have {} installed.",
+            error,
+            engine.binary.code_str(),
         )))?;
 
-    // Pipe data to the child's standard input stream.
-    writer(child.stdin.as_mut().unwrap())?; // [ref:run_quiet_stdin_piped]
+    // Pipe data to the child's standard input stream. If the data can't be produced, kill the child
+    // before it sees the end of the stream, and wait for it so it's done before the caller cleans
+    // up.
+    if let Err(e) = writer(child.stdin.as_mut().unwrap()) {
+        // [ref:run_quiet_stdin_piped]
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e);
+    }
 
     // Wait for the child to terminate.
     let output = child.wait_with_output().map_err(failure::system(format!(
-        "{} Perhaps you don't have Docker installed.",
-        error
+        "{} Perhaps you don't have {} installed.",
+        error,
+        engine.binary.code_str(),
     )))?;
 
     // Handle the result.
@@ -538,24 +1542,31 @@ fn run_quiet_stdin<W: FnOnce(&mut ChildStdin) -> Result<(), Failure>>(
 }
 
 // Run a command and inherit standard output and error streams.
-fn run_loud(error: &str, args: &[&str], interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+fn run_loud(
+    engine: &Engine,
+    error: &str,
+    args: &[&str],
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(), Failure> {
     // This is used to determine whether the user interrupted the program during the execution of
     // the child process.
     let was_interrupted = interrupted.load(Ordering::SeqCst);
 
     // Run the child process.
-    let mut child = command(args)
+    let mut child = command(engine, args)
         .stdin(Stdio::null())
         .spawn()
         .map_err(failure::system(format!(
-            "{} Perhaps you don't have Docker installed.",
-            error
+            "{} Perhaps you don't have {} installed.",
+            error,
+            engine.binary.code_str(),
         )))?;
 
     // Wait for the child to terminate.
     let status = child.wait().map_err(failure::system(format!(
-        "{} Perhaps you don't have Docker installed.",
-        error
+        "{} Perhaps you don't have {} installed.",
+        error,
+        engine.binary.code_str(),
     )))?;
 
     // Handle the result.
@@ -574,16 +1585,24 @@ fn run_loud(error: &str, args: &[&str], interrupted: &Arc<AtomicBool>) -> Result
 }
 
 // Run a command and inherit standard input, output, and error streams.
-fn run_attach(error: &str, args: &[&str], interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+fn run_attach(
+    engine: &Engine,
+    error: &str,
+    args: &[&str],
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(), Failure> {
     // This is used to determine whether the user interrupted the program during the execution of
     // the child process.
     let was_interrupted = interrupted.load(Ordering::SeqCst);
 
     // Run the child process.
-    let child = command(args).status().map_err(failure::system(format!(
-        "{} Perhaps you don't have Docker installed.",
-        error
-    )))?;
+    let child = command(engine, args)
+        .status()
+        .map_err(failure::system(format!(
+            "{} Perhaps you don't have {} installed.",
+            error,
+            engine.binary.code_str(),
+        )))?;
 
     // Handle the result.
     if child.success() {
@@ -601,8 +1620,8 @@ fn run_attach(error: &str, args: &[&str], interrupted: &Arc<AtomicBool>) -> Resu
 }
 
 // Construct a Docker `Command` from an array of arguments.
-fn command(args: &[&str]) -> Command {
-    let mut command = Command::new("docker");
+fn command(engine: &Engine, args: &[&str]) -> Command {
+    let mut command = Command::new(&engine.binary);
     for arg in args {
         command.arg(arg);
     }
@@ -611,10 +1630,194 @@ fn command(args: &[&str]) -> Command {
 
 #[cfg(test)]
 mod tests {
-    use crate::docker::random_tag;
+    use crate::docker::{
+        change_owner, digest, label_instruction, labels, parse_timestamp, random_tag,
+        swap_into_place, Engine, Flavor,
+    };
+    use serde_json::json;
+    use std::{
+        collections::BTreeMap,
+        fs::{create_dir_all, metadata, read_to_string, symlink_metadata, write},
+        os::unix::fs::{symlink, MetadataExt},
+    };
+    use tempfile::tempdir;
 
     #[test]
     fn random_impure() {
         assert_ne!(random_tag(), random_tag());
     }
+
+    #[test]
+    fn digest_repo_digest() {
+        assert_eq!(
+            digest(
+                "debian:buster",
+                &json!({
+                    "Id": "sha256:abc",
+                    "RepoDigests": ["foo/debian@sha256:def", "debian@sha256:ghi"],
+                }),
+            ),
+            Some("sha256:ghi".to_owned()),
+        );
+    }
+
+    #[test]
+    fn digest_repo_digest_podman() {
+        assert_eq!(
+            digest(
+                "debian",
+                &json!({
+                    "Id": "sha256:abc",
+                    "RepoDigests": ["docker.io/library/debian@sha256:def"],
+                }),
+            ),
+            Some("sha256:def".to_owned()),
+        );
+    }
+
+    #[test]
+    fn digest_id() {
+        assert_eq!(
+            digest(
+                "localhost:5000/toast:foo",
+                &json!({ "Id": "sha256:abc", "RepoDigests": [] }),
+            ),
+            Some("sha256:abc".to_owned()),
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_epoch() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+    }
+
+    #[test]
+    fn parse_timestamp_fractional() {
+        assert_eq!(
+            parse_timestamp("2019-06-09T12:34:56.789012345Z"),
+            Some(1_560_083_696),
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_offset() {
+        assert_eq!(
+            parse_timestamp("2019-06-09T14:34:56+02:00"),
+            Some(1_560_083_696),
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_leap_day() {
+        assert_eq!(parse_timestamp("2000-02-29T00:00:00Z"), Some(951_782_400));
+    }
+
+    #[test]
+    fn parse_timestamp_invalid() {
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn label_instruction_escaped() {
+        let mut labels = BTreeMap::new();
+        labels.insert("toast.task".to_owned(), "build".to_owned());
+        labels.insert(
+            "toast.toastfile".to_owned(),
+            "/home/\"me\"/$HOME\\toast.yml".to_owned(),
+        );
+
+        assert_eq!(
+            label_instruction(&labels),
+            "LABEL \"toast.task\"=\"build\" \
+             \"toast.toastfile\"=\"/home/\\\"me\\\"/\\$HOME\\\\toast.yml\"",
+        );
+    }
+
+    #[test]
+    fn labels_null() {
+        assert!(labels(&json!(null)).is_empty());
+    }
+
+    #[test]
+    fn labels_object() {
+        let mut expected = BTreeMap::new();
+        expected.insert("toast.pid".to_owned(), "42".to_owned());
+
+        assert_eq!(labels(&json!({ "toast.pid": "42" })), expected);
+    }
+
+    #[test]
+    fn engine_docker() {
+        assert_eq!(Engine::new("docker").flavor, Flavor::Docker);
+    }
+
+    #[test]
+    fn engine_podman() {
+        assert_eq!(Engine::new("/usr/bin/podman").flavor, Flavor::Podman);
+    }
+
+    #[test]
+    fn engine_unknown() {
+        assert_eq!(Engine::new("nerdctl").flavor, Flavor::Docker);
+    }
+
+    #[test]
+    fn change_owner_dangling_symlink() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo/bar")).unwrap();
+        write(dir.path().join("foo/bar/baz.txt"), "baz").unwrap();
+        symlink("missing.txt", dir.path().join("foo/bar/qux.txt")).unwrap();
+
+        let dir_metadata = metadata(dir.path()).unwrap();
+        let owner = (dir_metadata.uid(), dir_metadata.gid());
+        change_owner(dir.path(), &dir.path().join("foo/bar"), Some(owner)).unwrap();
+
+        let link_metadata = symlink_metadata(dir.path().join("foo/bar/qux.txt")).unwrap();
+        assert_eq!((link_metadata.uid(), link_metadata.gid()), owner);
+    }
+
+    #[test]
+    fn swap_into_place_replaces() {
+        let dir = tempdir().unwrap();
+        let destination = dir.path().join("dist");
+        create_dir_all(&destination).unwrap();
+        write(destination.join("stale.txt"), "stale").unwrap();
+        create_dir_all(dir.path().join("new")).unwrap();
+        write(dir.path().join("new/fresh.txt"), "fresh").unwrap();
+
+        swap_into_place(
+            &dir.path().join("new"),
+            &dir.path().join("old"),
+            &destination,
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_to_string(destination.join("fresh.txt")).unwrap(),
+            "fresh"
+        );
+        assert!(!destination.join("stale.txt").exists());
+    }
+
+    #[test]
+    fn swap_into_place_restores() {
+        let dir = tempdir().unwrap();
+        let destination = dir.path().join("dist");
+        create_dir_all(&destination).unwrap();
+        write(destination.join("stale.txt"), "stale").unwrap();
+
+        // The new copy doesn't exist, so it can't be moved into place.
+        assert!(swap_into_place(
+            &dir.path().join("missing"),
+            &dir.path().join("old"),
+            &destination,
+        )
+        .is_err());
+
+        assert_eq!(
+            read_to_string(destination.join("stale.txt")).unwrap(),
+            "stale"
+        );
+        assert!(!dir.path().join("old").exists());
+    }
 }
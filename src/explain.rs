@@ -0,0 +1,424 @@
+use crate::{
+    cache, cache::CryptoHash, failure, failure::Failure, format::CodeStr, tar, toastfile::Task,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{create_dir_all, read_to_string},
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+
+// An explanation lists everything that went into the cache key of a task.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Explanation {
+    // The cache key itself
+    pub key: String,
+
+    // The cache key of the previous task in the schedule, or of the base image for the first task
+    pub previous_key: String,
+
+    // The hashes of the values of the environment variables. The values themselves aren't
+    // recorded, since they may be secrets.
+    pub environment: BTreeMap<String, String>,
+
+    // The hash of each input file, keyed by its path in the container
+    pub input_files: BTreeMap<PathBuf, String>,
+
+    // The hash of all the input files, which also depends on where they were copied from
+    pub input_files_hash: String,
+
+    pub location: PathBuf,
+    pub user: String,
+    pub chown_input_paths: bool,
+    pub command: String,
+}
+
+impl Explanation {
+    // Explain the cache key of a task. The arguments are the same as for `cache::key`, except that
+    // the hashes of the individual input files are needed too.
+    pub fn new(
+        previous_key: &str,
+        task: &Task,
+        input_files: tar::Hashes,
+        environment: &HashMap<String, String>,
+    ) -> Self {
+        Explanation {
+            key: cache::key(previous_key, task, &input_files.total, environment),
+            previous_key: previous_key.to_owned(),
+            environment: task
+                .environment
+                .keys()
+                .map(|variable| {
+                    // [ref:environment_valid]
+                    (variable.to_owned(), environment[variable].crypto_hash())
+                })
+                .collect(),
+            input_files: input_files
+                .entries
+                .into_iter()
+                .map(|(path, hash)| (Path::new("/").join(path), hash))
+                .collect(),
+            input_files_hash: input_files.total,
+            location: task.location.clone(),
+            user: task.user.clone(),
+            chown_input_paths: task.chown_input_paths,
+            command: task.command.clone(),
+        }
+    }
+
+    // Print the components of the cache key.
+    pub fn print(&self) {
+        println!("  Cache key: {}", self.key.code_str());
+        println!("  Previous cache key: {}", self.previous_key.code_str());
+
+        if self.environment.is_empty() {
+            println!("  Environment: (none)");
+        } else {
+            println!("  Environment (hashes of the values):");
+            for (variable, hash) in &self.environment {
+                println!("    {}: {}", variable.code_str(), hash.code_str());
+            }
+        }
+
+        if self.input_files.is_empty() {
+            println!("  Input files: (none)");
+        } else {
+            println!("  Input files:");
+            for (path, hash) in &self.input_files {
+                println!(
+                    "    {}: {}",
+                    path.to_string_lossy().code_str(),
+                    hash.code_str(),
+                );
+            }
+        }
+
+        println!("  Location: {}", self.location.to_string_lossy().code_str());
+        println!("  User: {}", self.user.code_str());
+        println!("  Chown input paths: {}", self.chown_input_paths);
+        println!("  Command: {}", self.command.code_str());
+    }
+
+    // Describe what changed since an earlier explanation for the same task. If nothing changed, the
+    // result is empty.
+    pub fn changes(&self, earlier: &Self) -> Vec<String> {
+        let mut changes = vec![];
+
+        if self.previous_key != earlier.previous_key {
+            changes.push(
+                "The cache key of the previous task (or of the base image) changed.".to_owned(),
+            );
+        }
+
+        changes.extend(
+            diff(&earlier.environment, &self.environment)
+                .into_iter()
+                .map(|(variable, change)| {
+                    format!("Environment variable {} {}.", variable.code_str(), change)
+                }),
+        );
+
+        let file_changes = diff(&earlier.input_files, &self.input_files);
+        if file_changes.is_empty() && self.input_files_hash != earlier.input_files_hash {
+            changes.push("The input files are copied from different locations.".to_owned());
+        }
+        changes.extend(file_changes.into_iter().map(|(path, change)| {
+            format!(
+                "Input file {} {}.",
+                path.to_string_lossy().code_str(),
+                change,
+            )
+        }));
+
+        if self.location != earlier.location {
+            changes.push(format!(
+                "The location changed from {} to {}.",
+                earlier.location.to_string_lossy().code_str(),
+                self.location.to_string_lossy().code_str(),
+            ));
+        }
+
+        if self.user != earlier.user {
+            changes.push(format!(
+                "The user changed from {} to {}.",
+                earlier.user.code_str(),
+                self.user.code_str(),
+            ));
+        }
+
+        if self.chown_input_paths != earlier.chown_input_paths {
+            changes.push(format!(
+                "{} changed from {} to {}.",
+                "chown_input_paths".code_str(),
+                earlier.chown_input_paths,
+                self.chown_input_paths,
+            ));
+        }
+
+        if self.command != earlier.command {
+            changes.push(format!(
+                "The command changed from {} to {}.",
+                earlier.command.code_str(),
+                self.command.code_str(),
+            ));
+        }
+
+        changes
+    }
+}
+
+// Compare two maps of hashes and describe how each entry changed, in key order.
+fn diff<'a, K: Ord>(
+    earlier: &'a BTreeMap<K, String>,
+    later: &'a BTreeMap<K, String>,
+) -> Vec<(&'a K, &'static str)> {
+    let mut changes = earlier
+        .iter()
+        .filter_map(|(key, hash)| match later.get(key) {
+            Some(later_hash) if later_hash == hash => None,
+            Some(_) => Some((key, "changed")),
+            None => Some((key, "was removed")),
+        })
+        .chain(
+            later
+                .keys()
+                .filter(|key| !earlier.contains_key(key))
+                .map(|key| (key, "was added")),
+        )
+        .collect::<Vec<_>>();
+    changes.sort_by_key(|(key, _)| *key);
+    changes
+}
+
+// A record remembers the explanation from the last successful run of each task in a toastfile. It's
+// persisted on disk between runs.
+pub struct Record {
+    path: Option<PathBuf>,
+    explanations: BTreeMap<String, Explanation>,
+}
+
+impl Record {
+    // Load a record from a file. If the file doesn't exist or can't be read, start with an empty
+    // record. If `path` is `None`, the record won't be persisted.
+    pub fn load(path: Option<&Path>) -> Self {
+        let explanations = path
+            .and_then(|path| {
+                debug!(
+                    "Attempting to load cache key explanations {}\u{2026}",
+                    path.to_string_lossy().code_str()
+                );
+
+                read_to_string(path).ok()
+            })
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_else(|| {
+                debug!("Cache key explanations not found. Starting with an empty record.");
+                BTreeMap::new()
+            });
+
+        Record {
+            path: path.map(ToOwned::to_owned),
+            explanations,
+        }
+    }
+
+    // Look up the explanation from the last successful run of a task.
+    pub fn get(&self, task: &str) -> Option<&Explanation> {
+        self.explanations.get(task)
+    }
+
+    // Remember the explanation from a successful run of a task.
+    pub fn insert(&mut self, task: &str, explanation: Explanation) {
+        self.explanations.insert(task.to_owned(), explanation);
+    }
+
+    // Write the record back to disk. Like the memo, it's written to a temporary file first and then
+    // renamed, so concurrent runs never observe a partially written record.
+    pub fn save(&self) -> Result<(), Failure> {
+        // If the record isn't backed by a file, there's nothing to do.
+        let path = if let Some(path) = &self.path {
+            path
+        } else {
+            return Ok(());
+        };
+
+        // Make sure the parent directory exists. The `unwrap` is safe because `path` is a file.
+        let parent = path.parent().unwrap();
+        create_dir_all(parent).map_err(failure::system(format!(
+            "Unable to create directory {}.",
+            parent.to_string_lossy().code_str(),
+        )))?;
+
+        // Write the record to a temporary file in the same directory.
+        let temp_file = NamedTempFile::new_in(parent).map_err(failure::system(format!(
+            "Unable to create temporary file in {}.",
+            parent.to_string_lossy().code_str(),
+        )))?;
+        serde_json::to_writer(&temp_file, &self.explanations).map_err(failure::system(format!(
+            "Unable to write file {}.",
+            temp_file.path().to_string_lossy().code_str(),
+        )))?;
+
+        // Move the temporary file into place.
+        temp_file.persist(path).map_err(failure::system(format!(
+            "Unable to write file {}.",
+            path.to_string_lossy().code_str(),
+        )))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        explain::{Explanation, Record},
+        tar::Hashes,
+        toastfile::{InputPath, SpecialFiles, Task, DEFAULT_LOCATION, DEFAULT_USER},
+    };
+    use std::{
+        collections::{BTreeMap, HashMap},
+        path::{Path, PathBuf},
+    };
+    use tempfile::tempdir;
+
+    fn task() -> Task {
+        let mut environment = HashMap::new();
+        environment.insert("foo".to_owned(), None);
+
+        Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment,
+            input_paths: vec![InputPath::Path(Path::new("bar.txt").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            allow_external_paths: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: "echo wibble".to_owned(),
+        }
+    }
+
+    fn explain(task: &Task, files: &[(&str, &str)], value: &str) -> Explanation {
+        let mut entries = BTreeMap::new();
+        for (path, hash) in files {
+            entries.insert(PathBuf::from(path), (*hash).to_owned());
+        }
+
+        let mut environment = HashMap::new();
+        environment.insert("foo".to_owned(), value.to_owned());
+
+        Explanation::new(
+            "toast-base",
+            task,
+            Hashes {
+                total: format!("{:?}", files),
+                entries,
+            },
+            &environment,
+        )
+    }
+
+    #[test]
+    fn explanation_new() {
+        let explanation = explain(&task(), &[("scratch/bar.txt", "abc")], "qux");
+
+        assert_eq!(explanation.previous_key, "toast-base");
+        assert_eq!(
+            explanation.input_files.keys().collect::<Vec<_>>(),
+            vec![Path::new("/scratch/bar.txt")],
+        );
+        assert!(!explanation.environment["foo"].contains("qux"));
+        assert_eq!(explanation.command, "echo wibble");
+    }
+
+    #[test]
+    fn changes_none() {
+        let earlier = explain(&task(), &[("scratch/bar.txt", "abc")], "qux");
+        let later = explain(&task(), &[("scratch/bar.txt", "abc")], "qux");
+
+        assert_eq!(later.key, earlier.key);
+        assert!(later.changes(&earlier).is_empty());
+    }
+
+    #[test]
+    fn changes_input_files() {
+        let earlier = explain(
+            &task(),
+            &[("scratch/bar.txt", "abc"), ("scratch/baz.txt", "def")],
+            "qux",
+        );
+        let later = explain(
+            &task(),
+            &[("scratch/bar.txt", "ghi"), ("scratch/qux.txt", "jkl")],
+            "qux",
+        );
+
+        assert_ne!(later.key, earlier.key);
+        assert_eq!(
+            later.changes(&earlier),
+            vec![
+                "Input file `/scratch/bar.txt` changed.",
+                "Input file `/scratch/baz.txt` was removed.",
+                "Input file `/scratch/qux.txt` was added.",
+            ],
+        );
+    }
+
+    #[test]
+    fn changes_environment() {
+        let earlier = explain(&task(), &[], "qux");
+        let later = explain(&task(), &[], "corge");
+
+        assert_ne!(later.key, earlier.key);
+        assert_eq!(
+            later.changes(&earlier),
+            vec!["Environment variable `foo` changed."],
+        );
+    }
+
+    #[test]
+    fn changes_command() {
+        let earlier = explain(&task(), &[], "qux");
+        let mut task = task();
+        task.command = "echo wobble".to_owned();
+        let later = explain(&task, &[], "qux");
+
+        assert_ne!(later.key, earlier.key);
+        assert_eq!(
+            later.changes(&earlier),
+            vec!["The command changed from `echo wibble` to `echo wobble`."],
+        );
+    }
+
+    #[test]
+    fn record_persist() {
+        let dir = tempdir().unwrap();
+        let record_path = dir.path().join("explanations/toast.json");
+        let explanation = explain(&task(), &[("scratch/bar.txt", "abc")], "qux");
+
+        let mut record = Record::load(Some(&record_path));
+        assert!(record.get("foo").is_none());
+        record.insert("foo", explanation.clone());
+        record.save().unwrap();
+
+        assert_eq!(
+            Record::load(Some(&record_path)).get("foo"),
+            Some(&explanation),
+        );
+    }
+}
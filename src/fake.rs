@@ -0,0 +1,638 @@
+use crate::{
+    api,
+    docker::{ContainerBackend, ImageSummary, Labeled},
+    failure,
+    failure::Failure,
+    Settings,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{create_dir_all, write},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+use tar::{Archive, EntryType};
+
+// The filesystem of an image or container, keyed by absolute path. Only regular files are modeled,
+// so directories exist implicitly whenever there are files inside them.
+type Files = BTreeMap<PathBuf, Vec<u8>>;
+
+// The labels of an image or container
+type Labels = BTreeMap<String, String>;
+
+// An image in the local cache or the remote registry
+#[derive(Clone)]
+struct Image {
+    digest: String,
+    files: Files,
+    labels: Labels,
+}
+
+// Determine whether a reference identifies an image with the given name. A reference may be a name,
+// a repository and a digest (e.g., `debian@sha256:...`), or just a digest.
+fn identifies(reference: &str, name: &str, image: &Image) -> bool {
+    match reference.rfind('@') {
+        Some(index) => {
+            api::split_reference(reference).0 == api::split_reference(name).0
+                && reference[index + 1..] == image.digest
+        }
+        None if reference.starts_with("sha256:") => reference == image.digest,
+        None => reference == name,
+    }
+}
+
+// Determine whether some labels match all the given filters, each of which is either the name of a
+// label or a `name=value` pair.
+fn matches(labels: &Labels, filters: &[String]) -> bool {
+    filters.iter().all(|filter| match filter.find('=') {
+        Some(index) => {
+            labels.get(&filter[..index]).map(String::as_str) == Some(&filter[index + 1..])
+        }
+        None => labels.contains_key(filter),
+    })
+}
+
+// Find the image identified by a reference.
+fn find<'a, I: IntoIterator<Item = (&'a String, &'a Image)>>(
+    images: I,
+    reference: &str,
+) -> Option<&'a Image> {
+    images
+        .into_iter()
+        .find(|(name, image)| identifies(reference, name, image))
+        .map(|(_, image)| image)
+}
+
+// An operation which was performed on the fake backend
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Call {
+    ImageExists(String),
+    ImageDigest(String),
+    PushImage(String),
+    PullImage(String),
+    DeleteImage(String),
+    ListImages(String),
+    ListLabeledImages(Vec<String>),
+    ListLabeledContainers(Vec<String>),
+    CreateContainer(String, String), // The image and the command
+    StreamIntoContainer(String),
+    CopyPathFromContainer(String, PathBuf),
+    StartContainer(String),
+    StopContainer(String),
+    CommitContainer(String, String),
+    DeleteContainer(String),
+    SpawnShell(String),
+}
+
+// What happens when a container runs a command
+#[derive(Clone, Debug)]
+pub enum Behavior {
+    // The command writes some files (given by absolute paths) and succeeds.
+    Succeed(Vec<(PathBuf, String)>),
+
+    // The command writes some files (given by absolute paths) and fails.
+    Fail(Vec<(PathBuf, String)>),
+
+    // The user interrupts Toast while the command is running.
+    Interrupt,
+}
+
+// A container which hasn't been deleted yet
+struct Container {
+    command: String,
+    files: Files,
+    labels: Labels,
+}
+
+// The mutable state of the fake backend. The registry keeps every image which was ever published to
+// it, with the most recent last.
+struct State {
+    local_images: HashMap<String, Image>,
+    remote_images: Vec<(String, Image)>,
+    containers: HashMap<String, Container>,
+    behaviors: HashMap<String, Behavior>,
+    before_stream: Option<Box<dyn FnMut() + Send>>,
+    calls: Vec<Call>,
+    next_container: usize,
+    next_digest: usize,
+}
+
+impl State {
+    // Construct an image with a digest which hasn't been used before.
+    fn new_image(&mut self, files: Files, labels: Labels) -> Image {
+        self.next_digest += 1;
+        Image {
+            digest: format!("sha256:{:064x}", self.next_digest),
+            files,
+            labels,
+        }
+    }
+}
+
+// An in-memory stand-in for a container engine. It records every operation so tests can check what
+// Toast did. Commands succeed without doing anything unless they are given a `Behavior`.
+pub struct Fake {
+    state: Mutex<State>,
+}
+
+impl Fake {
+    // Create a fake backend with no images or containers.
+    pub fn new() -> Self {
+        Fake {
+            state: Mutex::new(State {
+                local_images: HashMap::new(),
+                remote_images: vec![],
+                containers: HashMap::new(),
+                behaviors: HashMap::new(),
+                before_stream: None,
+                calls: vec![],
+                next_container: 0,
+                next_digest: 0,
+            }),
+        }
+    }
+
+    // Add an empty image to the local cache. If the image already exists, it's replaced by one with
+    // a new digest.
+    pub fn add_local_image(&self, image: &str) {
+        let mut state = self.state.lock().unwrap();
+        let new_image = state.new_image(Files::new(), Labels::new());
+        state.local_images.insert(image.to_owned(), new_image);
+    }
+
+    // Add an empty image to the remote registry. If the image already exists, it's replaced by one
+    // with a new digest, as if the tag had been published again.
+    pub fn add_remote_image(&self, image: &str) {
+        let mut state = self.state.lock().unwrap();
+        let new_image = state.new_image(Files::new(), Labels::new());
+        state.remote_images.push((image.to_owned(), new_image));
+    }
+
+    // Decide what happens when a container runs `command`.
+    pub fn on_command(&self, command: &str, behavior: Behavior) {
+        let mut state = self.state.lock().unwrap();
+        state.behaviors.insert(command.to_owned(), behavior);
+    }
+
+    // Do something just before the next archive is streamed into a container, such as changing the
+    // input files.
+    pub fn before_stream<F: FnMut() + Send + 'static>(&self, callback: F) {
+        self.state.lock().unwrap().before_stream = Some(Box::new(callback));
+    }
+
+    // Return the operations which have been performed so far.
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    // Return the names of the images in the local cache in sorted order.
+    pub fn local_images(&self) -> Vec<String> {
+        let mut images = self
+            .state
+            .lock()
+            .unwrap()
+            .local_images
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        images.sort();
+        images
+    }
+
+    // Return the labels of an image in the local cache.
+    pub fn image_labels(&self, image: &str) -> Option<BTreeMap<String, String>> {
+        self.state
+            .lock()
+            .unwrap()
+            .local_images
+            .get(image)
+            .map(|image| image.labels.clone())
+    }
+
+    // Return the IDs of the containers which haven't been deleted in sorted order.
+    pub fn containers(&self) -> Vec<String> {
+        let mut containers = self
+            .state
+            .lock()
+            .unwrap()
+            .containers
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        containers.sort();
+        containers
+    }
+
+    // Return the names of the images in the remote registry in sorted order.
+    pub fn remote_images(&self) -> Vec<String> {
+        let mut images = self
+            .state
+            .lock()
+            .unwrap()
+            .remote_images
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        images.sort();
+        images.dedup();
+        images
+    }
+
+    // Record an operation and return the state for carrying it out.
+    fn record(&self, call: Call) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(call);
+        state
+    }
+}
+
+impl ContainerBackend for Fake {
+    fn image_exists(&self, image: &str, _interrupted: &Arc<AtomicBool>) -> Result<bool, Failure> {
+        let state = self.record(Call::ImageExists(image.to_owned()));
+        Ok(find(&state.local_images, image).is_some())
+    }
+
+    fn image_digest(&self, image: &str, _interrupted: &Arc<AtomicBool>) -> Result<String, Failure> {
+        let state = self.record(Call::ImageDigest(image.to_owned()));
+        find(&state.local_images, image)
+            .map(|image| image.digest.clone())
+            .ok_or_else(|| Failure::System("Unable to inspect image.".to_owned(), None))
+    }
+
+    fn push_image(&self, image: &str, _interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        let mut state = self.record(Call::PushImage(image.to_owned()));
+        let pushed_image = state
+            .local_images
+            .get(image)
+            .cloned()
+            .ok_or_else(|| Failure::System("Unable to push image.".to_owned(), None))?;
+        state.remote_images.push((image.to_owned(), pushed_image));
+        Ok(())
+    }
+
+    fn pull_image(&self, image: &str, _interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        let mut state = self.record(Call::PullImage(image.to_owned()));
+        let pulled_image = find(
+            state
+                .remote_images
+                .iter()
+                .rev()
+                .map(|(name, image)| (name, image)),
+            image,
+        )
+        .cloned()
+        .ok_or_else(|| Failure::System("Unable to pull image.".to_owned(), None))?;
+        state.local_images.insert(image.to_owned(), pulled_image);
+        Ok(())
+    }
+
+    fn delete_image(&self, image: &str, _interrupted: &Arc<AtomicBool>) -> Result<(), Failure> {
+        let mut state = self.record(Call::DeleteImage(image.to_owned()));
+        state
+            .local_images
+            .remove(image)
+            .map(|_| ())
+            .ok_or_else(|| Failure::System("Unable to delete image.".to_owned(), None))
+    }
+
+    fn list_images(
+        &self,
+        repository: &str,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<ImageSummary>, Failure> {
+        let state = self.record(Call::ListImages(repository.to_owned()));
+        let mut images = state
+            .local_images
+            .iter()
+            .filter(|(name, _)| api::split_reference(name).0 == repository)
+            .map(|(name, image)| ImageSummary {
+                name: name.clone(),
+                size: image.files.values().map(|data| data.len() as u64).sum(),
+                created: None,
+            })
+            .collect::<Vec<_>>();
+        images.sort_by(|x, y| x.name.cmp(&y.name));
+        Ok(images)
+    }
+
+    fn list_labeled_images(
+        &self,
+        filters: &[String],
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Labeled>, Failure> {
+        let state = self.record(Call::ListLabeledImages(filters.to_vec()));
+        let mut images = state
+            .local_images
+            .iter()
+            .filter(|(_, image)| matches(&image.labels, filters))
+            .map(|(name, image)| Labeled {
+                id: image.digest.clone(),
+                names: vec![name.clone()],
+                labels: image.labels.clone(),
+            })
+            .collect::<Vec<_>>();
+        images.sort_by(|x, y| x.names.cmp(&y.names));
+        Ok(images)
+    }
+
+    fn list_labeled_containers(
+        &self,
+        filters: &[String],
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<Vec<Labeled>, Failure> {
+        let state = self.record(Call::ListLabeledContainers(filters.to_vec()));
+        let mut containers = state
+            .containers
+            .iter()
+            .filter(|(_, container)| matches(&container.labels, filters))
+            .map(|(id, container)| Labeled {
+                id: id.clone(),
+                names: vec![],
+                labels: container.labels.clone(),
+            })
+            .collect::<Vec<_>>();
+        containers.sort_by(|x, y| x.id.cmp(&y.id));
+        Ok(containers)
+    }
+
+    fn create_container(
+        &self,
+        image: &str,
+        _source_dir: &Path,
+        _environment: &HashMap<String, String>,
+        _mount_paths: &[PathBuf],
+        _mount_readonly: bool,
+        _ports: &[String],
+        _location: &Path,
+        _user: &str,
+        command: &str,
+        _chown_paths: &[PathBuf],
+        labels: &BTreeMap<String, String>,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<String, Failure> {
+        let mut state = self.record(Call::CreateContainer(image.to_owned(), command.to_owned()));
+
+        // The container inherits the files and labels of the image. The given labels take
+        // precedence.
+        let (files, mut container_labels) = find(&state.local_images, image)
+            .map(|image| (image.files.clone(), image.labels.clone()))
+            .ok_or_else(|| Failure::System("Unable to create container.".to_owned(), None))?;
+        container_labels.extend(labels.clone());
+        state.next_container += 1;
+        let container = format!("container-{}", state.next_container);
+        state.containers.insert(
+            container.clone(),
+            Container {
+                command: command.to_owned(),
+                files,
+                labels: container_labels,
+            },
+        );
+        Ok(container)
+    }
+
+    fn stream_into_container(
+        &self,
+        container: &str,
+        writer: &mut dyn FnMut(&mut dyn Write) -> Result<(), Failure>,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        let before_stream = {
+            let mut state = self.state.lock().unwrap();
+            state
+                .calls
+                .push(Call::StreamIntoContainer(container.to_owned()));
+            state.before_stream.take()
+        };
+        if let Some(mut before_stream) = before_stream {
+            before_stream();
+        }
+
+        // Buffer the archive. The lock isn't held while it's written, since that reads files.
+        let mut data = vec![];
+        writer(&mut data)?;
+
+        // Extract the regular files from the archive into the container.
+        let mut files = Files::new();
+        for entry in Archive::new(&data[..])
+            .entries()
+            .map_err(failure::system("Unable to read archive."))?
+        {
+            let mut entry = entry.map_err(failure::system("Unable to read archive."))?;
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+
+            let path = Path::new("/").join(
+                entry
+                    .path()
+                    .map_err(failure::system("Unable to read archive."))?,
+            );
+            let mut contents = vec![];
+            entry
+                .read_to_end(&mut contents)
+                .map_err(failure::system("Unable to read archive."))?;
+            files.insert(path, contents);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state
+            .containers
+            .get_mut(container)
+            .ok_or_else(|| {
+                Failure::System("Unable to copy files into the container.".to_owned(), None)
+            })?
+            .files
+            .extend(files);
+        Ok(())
+    }
+
+    fn copy_path_from_container(
+        &self,
+        container: &str,
+        source: &Path,
+        destination: &Path,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        let state = self.record(Call::CopyPathFromContainer(
+            container.to_owned(),
+            source.to_owned(),
+        ));
+
+        // Find the files at or under `source`, relative to `source`.
+        let files = state
+            .containers
+            .get(container)
+            .map(|container| {
+                container
+                    .files
+                    .iter()
+                    .filter_map(|(path, contents)| {
+                        path.strip_prefix(source).ok().map(|relative_path| {
+                            if relative_path.as_os_str().is_empty() {
+                                (destination.to_owned(), contents)
+                            } else {
+                                (destination.join(relative_path), contents)
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if files.is_empty() {
+            return Err(Failure::System(
+                "Unable to copy files from the container.".to_owned(),
+                None,
+            ));
+        }
+
+        // Write the files to the host.
+        for (path, contents) in files {
+            // The `unwrap` is safe because `path` is inside `destination`, or equal to it.
+            create_dir_all(path.parent().unwrap())
+                .map_err(failure::system("Unable to create directory."))?;
+            write(&path, contents).map_err(failure::system("Unable to write file."))?;
+        }
+
+        Ok(())
+    }
+
+    fn start_container(
+        &self,
+        container: &str,
+        interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        let mut state = self.record(Call::StartContainer(container.to_owned()));
+        let command = state
+            .containers
+            .get(container)
+            .ok_or_else(|| Failure::System("Unable to start container.".to_owned(), None))?
+            .command
+            .clone();
+
+        // Carry out the behavior for the command, if there is one.
+        let (files, success) = match state.behaviors.get(&command).cloned() {
+            None => (vec![], true),
+            Some(Behavior::Succeed(files)) => (files, true),
+            Some(Behavior::Fail(files)) => (files, false),
+            Some(Behavior::Interrupt) => {
+                interrupted.store(true, Ordering::SeqCst);
+                return Err(Failure::Interrupted);
+            }
+        };
+
+        // The `unwrap` is safe because we just looked up the container.
+        let container = state.containers.get_mut(container).unwrap();
+        for (path, contents) in files {
+            container.files.insert(path, contents.into_bytes());
+        }
+
+        if success {
+            Ok(())
+        } else {
+            Err(Failure::System(
+                "Unable to start container.".to_owned(),
+                None,
+            ))
+        }
+    }
+
+    fn stop_container(
+        &self,
+        container: &str,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .push(Call::StopContainer(container.to_owned()));
+        Ok(())
+    }
+
+    fn commit_container(
+        &self,
+        container: &str,
+        image: &str,
+        labels: &BTreeMap<String, String>,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        let mut state = self.record(Call::CommitContainer(
+            container.to_owned(),
+            image.to_owned(),
+        ));
+
+        // The image inherits the files and labels of the container. The given labels take
+        // precedence.
+        let (files, mut image_labels) = state
+            .containers
+            .get(container)
+            .map(|container| (container.files.clone(), container.labels.clone()))
+            .ok_or_else(|| Failure::System("Unable to commit container.".to_owned(), None))?;
+        image_labels.extend(labels.clone());
+        let new_image = state.new_image(files, image_labels);
+        state.local_images.insert(image.to_owned(), new_image);
+        Ok(())
+    }
+
+    fn delete_container(
+        &self,
+        container: &str,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        let mut state = self.record(Call::DeleteContainer(container.to_owned()));
+        state
+            .containers
+            .remove(container)
+            .map(|_| ())
+            .ok_or_else(|| Failure::System("Unable to delete container.".to_owned(), None))
+    }
+
+    fn spawn_shell(
+        &self,
+        image: &str,
+        _environment: &HashMap<String, String>,
+        _location: &Path,
+        _user: &str,
+        _interrupted: &Arc<AtomicBool>,
+    ) -> Result<(), Failure> {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .push(Call::SpawnShell(image.to_owned()));
+        Ok(())
+    }
+}
+
+// Construct the settings for running the tasks in a toastfile with a fake backend. Only the local
+// cache is enabled.
+pub fn settings(toastfile_path: &Path, backend: &Arc<Fake>) -> Settings {
+    Settings {
+        toastfile_path: toastfile_path.to_owned(),
+        docker_repo: "toast".to_owned(),
+        backend: backend.clone(),
+        read_local_cache: true,
+        write_local_cache: true,
+        read_remote_cache: false,
+        write_remote_cache: false,
+        memoize_input_hashes: true,
+        stream_input_paths: false,
+        chown_output_paths: false,
+        pull_base_image: false,
+        locked: false,
+        update_lock: false,
+        output_manifest: None,
+        list: false,
+        check: false,
+        explain: false,
+        clean: None,
+        dry_run: false,
+        spawn_shell: false,
+        tasks: None,
+    }
+}
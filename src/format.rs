@@ -41,9 +41,29 @@ pub fn series(items: &[String]) -> String {
     }
 }
 
+// This function formats a number of bytes with a decimal unit, as Docker does. For example, 1500
+// becomes "1.5 kB".
+#[allow(clippy::cast_precision_loss)]
+pub fn bytes(n: u64) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB", "TB"];
+
+    if n < 1000 {
+        return format!("{} B", n);
+    }
+
+    let mut size = n as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::format::{number, series};
+    use crate::format::{bytes, number, series};
 
     #[test]
     fn number_zero() {
@@ -82,4 +102,18 @@ mod tests {
             "foo, bar, and baz"
         );
     }
+    #[test]
+    fn bytes_small() {
+        assert_eq!(bytes(999), "999 B");
+    }
+
+    #[test]
+    fn bytes_kilobytes() {
+        assert_eq!(bytes(1500), "1.5 kB");
+    }
+
+    #[test]
+    fn bytes_gigabytes() {
+        assert_eq!(bytes(2_340_000_000), "2.3 GB");
+    }
 }
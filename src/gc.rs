@@ -0,0 +1,334 @@
+use crate::{api, docker::ImageSummary, failure, failure::Failure, format::CodeStr};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{create_dir_all, read_to_string},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+
+// The number of seconds in a day
+const DAY: u64 = 60 * 60 * 24;
+
+// The prefix of the policy which deletes images by age, e.g., `unused:30`
+const UNUSED_PREFIX: &str = "unused:";
+
+// A policy determines which cached images to delete.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Policy {
+    // Delete every cached image.
+    All,
+
+    // Delete the images which aren't used by the schedule for the current toastfile.
+    Unreachable,
+
+    // Delete the images which haven't been used in the given number of days.
+    Unused(u64),
+
+    // Delete the containers and temporary images left behind by Toast processes which were killed.
+    // This is handled by the reaper rather than `select` [ref:orphans_reaped].
+    Orphans,
+}
+
+// Parse a policy from the command line: `all`, `unreachable`, `unused:DAYS`, or `orphans`.
+pub fn parse_policy(policy: &str) -> Result<Policy, Failure> {
+    match policy {
+        "all" => Ok(Policy::All),
+        "unreachable" => Ok(Policy::Unreachable),
+        "orphans" => Ok(Policy::Orphans),
+        _ => Some(policy)
+            .filter(|policy| policy.starts_with(UNUSED_PREFIX))
+            .and_then(|policy| policy[UNUSED_PREFIX.len()..].parse::<u64>().ok())
+            .map(Policy::Unused)
+            .ok_or_else(|| {
+                Failure::User(
+                    format!(
+                        "Invalid cleaning policy {}. The options are {}, {}, {}, and {}.",
+                        policy.code_str(),
+                        "all".code_str(),
+                        "unreachable".code_str(),
+                        "unused:DAYS".code_str(),
+                        "orphans".code_str(),
+                    ),
+                    None,
+                )
+            }),
+    }
+}
+
+// Determine the current time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// Determine the tag of an image, which for cached images is the cache key.
+pub fn tag(image: &str) -> &str {
+    api::split_reference(image).1.unwrap_or("latest")
+}
+
+// Usage remembers when each cached image was last used, keyed by its tag. It's shared by all
+// toastfiles and persisted on disk between runs.
+pub struct Usage {
+    path: Option<PathBuf>,
+    last_used: BTreeMap<String, u64>,
+    forgotten: BTreeMap<String, u64>,
+}
+
+impl Usage {
+    // Load the usage from a file. If the file doesn't exist or can't be read, start from scratch.
+    // If `path` is `None`, the usage won't be persisted.
+    pub fn load(path: Option<&Path>) -> Self {
+        Usage {
+            path: path.map(ToOwned::to_owned),
+            last_used: path.map_or_else(BTreeMap::new, read),
+            forgotten: BTreeMap::new(),
+        }
+    }
+
+    // Look up when an image was last used.
+    pub fn get(&self, image: &str) -> Option<u64> {
+        self.last_used.get(tag(image)).cloned()
+    }
+
+    // Remember that an image was used at the given time.
+    pub fn touch(&mut self, image: &str, time: u64) {
+        self.last_used.insert(tag(image).to_owned(), time);
+    }
+
+    // Forget about an image at the given time, e.g., because it was deleted.
+    pub fn forget(&mut self, image: &str, time: u64) {
+        self.last_used.remove(tag(image));
+        self.forgotten.insert(tag(image).to_owned(), time);
+    }
+
+    // Write the usage back to disk. Other runs may have updated the file in the meantime, so their
+    // entries are merged in first, keeping the most recent time for each image. Images which were
+    // forgotten stay forgotten unless another run used them afterward.
+    pub fn save(&mut self) -> Result<(), Failure> {
+        // If the usage isn't backed by a file, there's nothing to do.
+        let path = if let Some(path) = &self.path {
+            path
+        } else {
+            return Ok(());
+        };
+
+        // Merge in the changes from other runs.
+        for (tag, time) in read(path) {
+            if self
+                .forgotten
+                .get(&tag)
+                .map_or(true, |forgotten_time| time > *forgotten_time)
+            {
+                let entry = self.last_used.entry(tag).or_insert(time);
+                *entry = (*entry).max(time);
+            }
+        }
+
+        // Make sure the parent directory exists. The `unwrap` is safe because `path` is a file.
+        let parent = path.parent().unwrap();
+        create_dir_all(parent).map_err(failure::system(format!(
+            "Unable to create directory {}.",
+            parent.to_string_lossy().code_str(),
+        )))?;
+
+        // Write the usage to a temporary file in the same directory.
+        let temp_file = NamedTempFile::new_in(parent).map_err(failure::system(format!(
+            "Unable to create temporary file in {}.",
+            parent.to_string_lossy().code_str(),
+        )))?;
+        serde_json::to_writer(&temp_file, &self.last_used).map_err(failure::system(format!(
+            "Unable to write file {}.",
+            temp_file.path().to_string_lossy().code_str(),
+        )))?;
+
+        // Move the temporary file into place.
+        temp_file.persist(path).map_err(failure::system(format!(
+            "Unable to write file {}.",
+            path.to_string_lossy().code_str(),
+        )))?;
+
+        Ok(())
+    }
+}
+
+// Read a usage file, ignoring any errors.
+fn read(path: &Path) -> BTreeMap<String, u64> {
+    read_to_string(path)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+// Select the images to delete according to a policy. The `reachable` set contains the tags of the
+// images used by the current schedule. Images which were never used since Toast started keeping
+// track are considered to have been used when they were created. If that isn't known either, the
+// image is kept.
+pub fn select<'a>(
+    images: &'a [ImageSummary],
+    policy: Policy,
+    reachable: &HashSet<String>,
+    usage: &Usage,
+    now: u64,
+) -> Vec<&'a ImageSummary> {
+    images
+        .iter()
+        .filter(|image| match policy {
+            Policy::All => true,
+            Policy::Unreachable => !reachable.contains(tag(&image.name)),
+            Policy::Unused(days) => usage
+                .get(&image.name)
+                .max(image.created)
+                .map_or(false, |last_used| {
+                    now.saturating_sub(last_used) > days * DAY
+                }),
+            Policy::Orphans => false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        docker::ImageSummary,
+        failure::Failure,
+        gc::{parse_policy, select, Policy, Usage, DAY},
+    };
+    use std::collections::HashSet;
+    use tempfile::tempdir;
+
+    fn images() -> Vec<ImageSummary> {
+        vec![
+            ImageSummary {
+                name: "toast:toast-foo".to_owned(),
+                size: 1000,
+                created: Some(0),
+            },
+            ImageSummary {
+                name: "toast:toast-bar".to_owned(),
+                size: 2000,
+                created: Some(10 * DAY),
+            },
+        ]
+    }
+
+    fn names(images: &[&ImageSummary]) -> Vec<String> {
+        images.iter().map(|image| image.name.clone()).collect()
+    }
+
+    #[test]
+    fn parse_policy_all() {
+        assert_eq!(parse_policy("all").unwrap(), Policy::All);
+    }
+
+    #[test]
+    fn parse_policy_unreachable() {
+        assert_eq!(parse_policy("unreachable").unwrap(), Policy::Unreachable);
+    }
+
+    #[test]
+    fn parse_policy_unused() {
+        assert_eq!(parse_policy("unused:7").unwrap(), Policy::Unused(7));
+    }
+
+    #[test]
+    fn parse_policy_orphans() {
+        assert_eq!(parse_policy("orphans").unwrap(), Policy::Orphans);
+    }
+
+    #[test]
+    fn parse_policy_invalid() {
+        match parse_policy("unused:seven") {
+            Err(Failure::User(message, _)) => assert!(message.contains("unused:seven")),
+            _ => panic!("The policy should have been rejected."),
+        }
+    }
+
+    #[test]
+    fn select_all() {
+        let images = images();
+
+        assert_eq!(
+            names(&select(
+                &images,
+                Policy::All,
+                &HashSet::new(),
+                &Usage::load(None),
+                0,
+            )),
+            vec!["toast:toast-foo", "toast:toast-bar"],
+        );
+    }
+
+    #[test]
+    fn select_unreachable() {
+        let images = images();
+        let mut reachable = HashSet::new();
+        reachable.insert("toast-bar".to_owned());
+
+        assert_eq!(
+            names(&select(
+                &images,
+                Policy::Unreachable,
+                &reachable,
+                &Usage::load(None),
+                0,
+            )),
+            vec!["toast:toast-foo"],
+        );
+    }
+
+    #[test]
+    fn select_unused_created() {
+        let images = images();
+
+        assert_eq!(
+            names(&select(
+                &images,
+                Policy::Unused(7),
+                &HashSet::new(),
+                &Usage::load(None),
+                12 * DAY,
+            )),
+            vec!["toast:toast-foo"],
+        );
+    }
+
+    #[test]
+    fn select_unused_last_used() {
+        let images = images();
+        let mut usage = Usage::load(None);
+        usage.touch("toast:toast-foo", 11 * DAY);
+
+        assert!(select(
+            &images,
+            Policy::Unused(7),
+            &HashSet::new(),
+            &usage,
+            12 * DAY
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn usage_persist_merge() {
+        let dir = tempdir().unwrap();
+        let usage_path = dir.path().join("usage/usage.json");
+
+        let mut first_usage = Usage::load(Some(&usage_path));
+        let mut second_usage = Usage::load(Some(&usage_path));
+        first_usage.touch("toast:toast-foo", 1);
+        first_usage.save().unwrap();
+        second_usage.touch("toast:toast-foo", 2);
+        second_usage.touch("toast:toast-bar", 3);
+        second_usage.save().unwrap();
+        first_usage.forget("toast:toast-bar", 4);
+        first_usage.save().unwrap();
+
+        let usage = Usage::load(Some(&usage_path));
+        assert_eq!(usage.get("toast:toast-foo"), Some(2));
+        assert_eq!(usage.get("toast:toast-bar"), None);
+    }
+}
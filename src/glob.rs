@@ -0,0 +1,266 @@
+use crate::{failure, failure::Failure, format::CodeStr};
+use globset::{GlobBuilder, GlobMatcher};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use walkdir::WalkDir;
+
+// These characters have special meaning in a glob pattern.
+const GLOB_METACHARACTERS: &[char] = &['*', '?', '[', '{'];
+
+// Determine whether a path component contains any glob metacharacters.
+fn is_glob_component(component: &Component) -> bool {
+    component
+        .as_os_str()
+        .to_string_lossy()
+        .contains(GLOB_METACHARACTERS)
+}
+
+// Determine whether a path should be interpreted as a glob pattern rather than a literal path.
+pub fn is_glob(path: &Path) -> bool {
+    path.components()
+        .any(|component| is_glob_component(&component))
+}
+
+// Split a pattern into the longest prefix of components that contain no metacharacters. For
+// example, the literal prefix of `src/**/*.rs` is `src`. The prefix may be empty.
+pub fn literal_prefix(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|component| !is_glob_component(component))
+        .collect()
+}
+
+// Compile a pattern into a matcher. Wildcards (`*` and `?`) never match `/`, but `**` matches any
+// number of directories.
+fn compile(pattern: &Path) -> Result<GlobMatcher, Failure> {
+    Ok(GlobBuilder::new(&pattern.to_string_lossy())
+        .literal_separator(true)
+        .build()
+        .map_err(failure::user(format!(
+            "Invalid glob pattern {}.",
+            pattern.to_string_lossy().code_str(),
+        )))?
+        .compile_matcher())
+}
+
+// Check that a pattern is valid.
+pub fn validate(pattern: &Path) -> Result<(), Failure> {
+    compile(pattern).map(|_| ())
+}
+
+// Find all the paths under `dir` which match `pattern`. Both the pattern and the returned paths are
+// relative to `dir`. When a directory matches, its contents are not reported separately, since the
+// callers handle directories recursively. The results are sorted.
+pub fn expand(
+    dir: &Path,
+    pattern: &Path,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Vec<PathBuf>, Failure> {
+    // Remove any `.` components, since the paths we traverse won't have them.
+    let pattern = pattern
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect::<PathBuf>();
+
+    // Compile the pattern.
+    let matcher = compile(&pattern)?;
+
+    // Only the part of the tree under the literal prefix can possibly match.
+    let root = dir.join(literal_prefix(&pattern));
+    if root.symlink_metadata().is_err() {
+        return Ok(vec![]);
+    }
+
+    // Traverse the tree and collect the matches.
+    let mut matched_paths = vec![];
+    let mut walker = WalkDir::new(&root)
+        .sort_by(|x, y| x.file_name().cmp(y.file_name()))
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        // If the user wants to stop the operation, quit now.
+        if interrupted.load(Ordering::SeqCst) {
+            return Err(Failure::Interrupted);
+        }
+
+        // Unwrap the entry.
+        let entry = entry.map_err(failure::user(format!(
+            "Unable to traverse directory {}.",
+            root.to_string_lossy().code_str(),
+        )))?;
+
+        // Relativize the path. The `unwrap` is safe because `entry` is guaranteed to be inside
+        // `root`, which is inside `dir`.
+        let relative_path = entry.path().strip_prefix(dir).unwrap();
+
+        // Check the path against the pattern.
+        if matcher.is_match(relative_path) {
+            matched_paths.push(relative_path.to_owned());
+
+            // There's no need to look inside a matching directory.
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+        }
+    }
+
+    // Return the matches. They are already sorted, since the traversal visits the entries of each
+    // directory in order.
+    Ok(matched_paths)
+}
+
+// Expand any glob patterns in a list of paths relative to `dir`. Paths which aren't glob patterns
+// are passed through unchanged, even if they don't exist.
+pub fn expand_all(
+    dir: &Path,
+    paths: &[PathBuf],
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Vec<PathBuf>, Failure> {
+    let mut expanded_paths = vec![];
+
+    for path in paths {
+        if is_glob(path) {
+            expanded_paths.extend(expand(dir, path, interrupted)?);
+        } else {
+            expanded_paths.push(path.to_owned());
+        }
+    }
+
+    Ok(expanded_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::{expand, expand_all, is_glob, literal_prefix, validate};
+    use std::{
+        fs::{create_dir_all, write},
+        path::Path,
+        sync::{atomic::AtomicBool, Arc},
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn is_glob_literal() {
+        assert!(!is_glob(Path::new("foo/bar.txt")));
+    }
+
+    #[test]
+    fn is_glob_wildcard() {
+        assert!(is_glob(Path::new("foo/*.txt")));
+    }
+
+    #[test]
+    fn literal_prefix_nested() {
+        assert_eq!(
+            literal_prefix(Path::new("foo/bar/**/*.rs")),
+            Path::new("foo/bar")
+        );
+    }
+
+    #[test]
+    fn literal_prefix_empty() {
+        assert_eq!(literal_prefix(Path::new("*.rs")), Path::new(""));
+    }
+
+    #[test]
+    fn validate_invalid() {
+        assert!(validate(Path::new("foo/[bar")).is_err());
+    }
+
+    #[test]
+    fn expand_recursive() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("src/foo")).unwrap();
+        write(dir.path().join("src/main.rs"), "").unwrap();
+        write(dir.path().join("src/foo/bar.rs"), "").unwrap();
+        write(dir.path().join("src/foo/baz.txt"), "").unwrap();
+
+        assert_eq!(
+            expand(
+                dir.path(),
+                Path::new("src/**/*.rs"),
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap(),
+            vec![
+                Path::new("src/foo/bar.rs").to_owned(),
+                Path::new("src/main.rs").to_owned(),
+            ],
+        );
+    }
+
+    #[test]
+    fn expand_single_level() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("dist/nested")).unwrap();
+        write(dir.path().join("dist/foo.whl"), "").unwrap();
+        write(dir.path().join("dist/nested/bar.whl"), "").unwrap();
+
+        assert_eq!(
+            expand(
+                dir.path(),
+                Path::new("dist/*.whl"),
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap(),
+            vec![Path::new("dist/foo.whl").to_owned()],
+        );
+    }
+
+    #[test]
+    fn expand_missing_prefix() {
+        let dir = tempdir().unwrap();
+
+        assert!(expand(
+            dir.path(),
+            Path::new("dist/*.whl"),
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn expand_escaped_brace() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("{foo}.txt"), "").unwrap();
+        write(dir.path().join("foo.txt"), "").unwrap();
+
+        assert_eq!(
+            expand(
+                dir.path(),
+                Path::new("[{]foo[}].txt"),
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap(),
+            vec![Path::new("{foo}.txt").to_owned()],
+        );
+    }
+
+    #[test]
+    fn expand_all_mixed() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("dist")).unwrap();
+        write(dir.path().join("dist/foo.whl"), "").unwrap();
+
+        assert_eq!(
+            expand_all(
+                dir.path(),
+                &[
+                    Path::new("README.md").to_owned(),
+                    Path::new("dist/*.whl").to_owned(),
+                ],
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap(),
+            vec![
+                Path::new("README.md").to_owned(),
+                Path::new("dist/foo.whl").to_owned(),
+            ],
+        );
+    }
+}
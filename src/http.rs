@@ -0,0 +1,466 @@
+use crate::{failure, failure::Failure, format::CodeStr};
+use std::{
+    io,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+// Blocking socket operations wake up this often to check whether the user wants to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Request bodies are sent in chunks of (at most) this many bytes.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Where to connect to a server
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Endpoint {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+// A closure which writes the body of a request
+pub type BodyWriter<'a> = Box<dyn FnOnce(&mut dyn Write) -> Result<(), Failure> + 'a>;
+
+// The body of a request. A streamed body is written by a closure and sent with chunked transfer
+// encoding, so its size doesn't need to be known in advance.
+pub enum Body<'a> {
+    Empty,
+    Bytes(&'a [u8]),
+    Stream(BodyWriter<'a>),
+}
+
+// A response whose body can be read incrementally
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    // Look up a header by its (case-insensitive) name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_ref())
+    }
+
+    // Read the rest of the body as text.
+    pub fn text(mut self, interrupted: &Arc<AtomicBool>) -> Result<String, Failure> {
+        let mut text = String::new();
+        self.body
+            .read_to_string(&mut text)
+            .map_err(io_failure("Unable to read response.", interrupted))?;
+        Ok(text)
+    }
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+// Any bidirectional byte stream
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+// This wraps a connection so that blocking operations give up when the user wants to stop. The
+// underlying socket must have read and write timeouts.
+struct Interruptible {
+    connection: Box<dyn Connection>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Interruptible {
+    // Retry an operation which timed out, unless the user wants to stop.
+    fn retry<T, F: FnMut(&mut dyn Connection) -> io::Result<T>>(
+        &mut self,
+        mut operation: F,
+    ) -> io::Result<T> {
+        loop {
+            match operation(&mut *self.connection) {
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    // Note that we don't use `io::ErrorKind::Interrupted` here, since the standard
+                    // library retries operations which fail with that.
+                    if self.interrupted.load(Ordering::SeqCst) {
+                        return Err(io::Error::new(io::ErrorKind::Other, "Interrupted."));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Read for Interruptible {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.retry(|connection| connection.read(buf))
+    }
+}
+
+impl Write for Interruptible {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|connection| connection.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|connection| connection.flush())
+    }
+}
+
+// This decodes a body sent with chunked transfer encoding.
+pub struct ChunkedReader<R: BufRead> {
+    reader: R,
+    remaining: usize, // The number of bytes left in the current chunk
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(reader: R) -> Self {
+        ChunkedReader {
+            reader,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Start a new chunk if necessary.
+        if self.remaining == 0 {
+            let line = read_line(&mut self.reader)?;
+            let size = line.split(';').next().unwrap_or("").trim(); // Ignore any extensions.
+            self.remaining = usize::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk size."))?;
+
+            // The last chunk is empty, and it's followed by optional trailers and a blank line.
+            if self.remaining == 0 {
+                while !read_line(&mut self.reader)?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        // Read from the current chunk.
+        let limit = buf.len().min(self.remaining);
+        let size = self.reader.read(&mut buf[..limit])?;
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Incomplete chunk.",
+            ));
+        }
+        self.remaining -= size;
+
+        // Each chunk is terminated by a line break.
+        if self.remaining == 0 {
+            read_line(&mut self.reader)?;
+        }
+
+        Ok(size)
+    }
+}
+
+// This encodes a body with chunked transfer encoding. Call `finish` to write the last chunk.
+struct ChunkedWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(b"0\r\n\r\n")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.writer, "{:x}\r\n", buf.len())?;
+            self.writer.write_all(buf)?;
+            self.writer.write_all(b"\r\n")?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Read a line terminated by CRLF (or just LF) and return it without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed unexpectedly.",
+        ));
+    }
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+// Convert an I/O error into a failure. If the user wants to stop, the error was probably caused by
+// that, so it's reported as an interruption.
+pub fn io_failure<'a>(
+    message: &'a str,
+    interrupted: &'a Arc<AtomicBool>,
+) -> impl FnOnce(io::Error) -> Failure + 'a {
+    move |error| {
+        if interrupted.load(Ordering::SeqCst) {
+            Failure::Interrupted
+        } else {
+            failure::system(message)(error)
+        }
+    }
+}
+
+// Open a connection to an endpoint.
+fn connect(endpoint: &Endpoint, interrupted: &Arc<AtomicBool>) -> Result<Interruptible, Failure> {
+    let connection: Box<dyn Connection> = match endpoint {
+        Endpoint::Unix(path) => {
+            let stream = UnixStream::connect(path).map_err(failure::system(format!(
+                "Unable to connect to {}.",
+                path.to_string_lossy().code_str(),
+            )))?;
+            stream
+                .set_read_timeout(Some(POLL_INTERVAL))
+                .and_then(|()| stream.set_write_timeout(Some(POLL_INTERVAL)))
+                .map_err(failure::system("Unable to configure socket."))?;
+            Box::new(stream)
+        }
+        Endpoint::Tcp(address) => {
+            let stream = TcpStream::connect(address).map_err(failure::system(format!(
+                "Unable to connect to {}.",
+                address.code_str(),
+            )))?;
+            stream
+                .set_read_timeout(Some(POLL_INTERVAL))
+                .and_then(|()| stream.set_write_timeout(Some(POLL_INTERVAL)))
+                .map_err(failure::system("Unable to configure socket."))?;
+            Box::new(stream)
+        }
+    };
+
+    Ok(Interruptible {
+        connection,
+        interrupted: interrupted.clone(),
+    })
+}
+
+// Send a request and return the response. This is a minimal HTTP/1.1 client, just enough to talk to
+// the Docker Engine API. Each request uses a new connection, which is closed once the response has
+// been read. The `path` includes the query string, if any. If the server switches protocols (e.g.,
+// to attach to a container), the body of the response is whatever the server sends until it closes
+// the connection.
+pub fn request(
+    endpoint: &Endpoint,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Body,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Response, Failure> {
+    debug!("Sending request {} {}\u{2026}", method, path.code_str());
+
+    // Connect to the server.
+    let mut connection = connect(endpoint, interrupted)?;
+
+    // Write the request line and the headers.
+    let mut lines = vec![
+        format!("{} {} HTTP/1.1", method, path),
+        format!(
+            "Host: {}",
+            match endpoint {
+                Endpoint::Unix(_) => "localhost",
+                Endpoint::Tcp(address) => address,
+            },
+        ),
+    ];
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+    {
+        lines.push("Connection: close".to_owned());
+    }
+    for (name, value) in headers {
+        lines.push(format!("{}: {}", name, value));
+    }
+    lines.push(match &body {
+        Body::Empty => "Content-Length: 0".to_owned(),
+        Body::Bytes(bytes) => format!("Content-Length: {}", bytes.len()),
+        Body::Stream(_) => "Transfer-Encoding: chunked".to_owned(),
+    });
+    let head = format!("{}\r\n\r\n", lines.join("\r\n"));
+    connection
+        .write_all(head.as_bytes())
+        .map_err(io_failure("Unable to send request.", interrupted))?;
+
+    // Write the body.
+    match body {
+        Body::Empty => {}
+        Body::Bytes(bytes) => {
+            connection
+                .write_all(bytes)
+                .map_err(io_failure("Unable to send request.", interrupted))?;
+        }
+        Body::Stream(writer) => {
+            let mut chunked_writer = BufWriter::with_capacity(
+                CHUNK_SIZE,
+                ChunkedWriter {
+                    writer: &mut connection,
+                },
+            );
+            writer(&mut chunked_writer)?;
+            chunked_writer
+                .into_inner()
+                .map_err(io::IntoInnerError::into_error)
+                .and_then(ChunkedWriter::finish)
+                .map_err(io_failure("Unable to send request.", interrupted))?;
+        }
+    }
+    connection
+        .flush()
+        .map_err(io_failure("Unable to send request.", interrupted))?;
+
+    // Read the status line.
+    let mut reader = BufReader::new(connection);
+    let status_line =
+        read_line(&mut reader).map_err(io_failure("Unable to read response.", interrupted))?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| {
+            Failure::System(
+                format!("Invalid response status line {}.", status_line.code_str()),
+                None,
+            )
+        })?;
+
+    // Read the headers.
+    let mut response_headers = vec![];
+    loop {
+        let line =
+            read_line(&mut reader).map_err(io_failure("Unable to read response.", interrupted))?;
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(index) = line.find(':') {
+            response_headers.push((
+                line[..index].trim().to_owned(),
+                line[index + 1..].trim().to_owned(),
+            ));
+        }
+    }
+
+    // Figure out where the body ends.
+    let mut response = Response {
+        status,
+        headers: response_headers,
+        body: Box::new(io::empty()),
+    };
+    response.body = if status == 204 || status == 304 {
+        Box::new(io::empty())
+    } else if status == 101 {
+        Box::new(reader)
+    } else if response
+        .header("Transfer-Encoding")
+        .map_or(false, |encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        Box::new(ChunkedReader::new(reader))
+    } else if let Some(length) = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<u64>().ok())
+    {
+        Box::new(reader.take(length))
+    } else {
+        Box::new(reader)
+    };
+
+    Ok(response)
+}
+
+// Percent-encode a string for use in a URL. Only unreserved characters are left alone, unless
+// `keep_slashes` is set.
+pub fn encode(s: &str, keep_slashes: bool) -> String {
+    s.bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric()
+                || b"-._~".contains(&byte)
+                || (keep_slashes && byte == b'/')
+            {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{encode, ChunkedReader, ChunkedWriter};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn encode_reserved() {
+        assert_eq!(encode("foo/bar:baz qux", false), "foo%2Fbar%3Abaz%20qux");
+    }
+
+    #[test]
+    fn encode_keep_slashes() {
+        assert_eq!(encode("foo/bar:baz", true), "foo/bar%3Abaz");
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        let mut chunked_writer = ChunkedWriter { writer: vec![] };
+        chunked_writer.write_all(b"Hello, ").unwrap();
+        chunked_writer.write_all(b"").unwrap();
+        chunked_writer.write_all(b"World!").unwrap();
+        let encoded = chunked_writer.finish().unwrap();
+        assert_eq!(
+            encoded,
+            b"7\r\nHello, \r\n6\r\nWorld!\r\n0\r\n\r\n".to_vec()
+        );
+
+        let mut decoded = String::new();
+        ChunkedReader::new(&encoded[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "Hello, World!");
+    }
+
+    #[test]
+    fn chunked_reader_extensions_and_trailers() {
+        let encoded = b"5;foo=bar\r\nHello\r\n0\r\nTrailer: baz\r\n\r\n";
+
+        let mut decoded = String::new();
+        ChunkedReader::new(&encoded[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "Hello");
+    }
+}
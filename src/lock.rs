@@ -0,0 +1,113 @@
+use crate::{failure, failure::Failure, format::CodeStr};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+// This is written at the top of every lockfile.
+const HEADER: &str = "# This file is generated by Toast. Run `toast --update-lock` to update it.\n";
+
+// A lockfile pins the base image of a toastfile to a particular content digest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    // The base image, as given in the toastfile
+    pub image: String,
+
+    // The content digest of the base image
+    pub digest: String,
+}
+
+// Determine where the lockfile for a toastfile lives, e.g., `toast.lock` for `toast.yml`.
+pub fn path(toastfile_path: &Path) -> PathBuf {
+    toastfile_path.with_extension("lock")
+}
+
+// Read a lockfile, if it exists.
+pub fn load(path: &Path) -> Result<Option<Lockfile>, Failure> {
+    let data = match read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(failure::system(format!(
+                "Unable to read file {}.",
+                path.to_string_lossy().code_str(),
+            ))(e))
+        }
+    };
+
+    serde_yaml::from_str(&data)
+        .map(Some)
+        .map_err(failure::user(format!(
+            "Unable to parse file {}.",
+            path.to_string_lossy().code_str(),
+        )))
+}
+
+// Write a lockfile.
+pub fn save(path: &Path, lockfile: &Lockfile) -> Result<(), Failure> {
+    let data = serde_yaml::to_string(lockfile)
+        .map_err(failure::system("Unable to serialize the lockfile."))?;
+
+    write(
+        path,
+        format!(
+            "{}{}\n",
+            HEADER,
+            data.trim_start_matches("---\n").trim_end(),
+        ),
+    )
+    .map_err(failure::system(format!(
+        "Unable to write file {}.",
+        path.to_string_lossy().code_str(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::{load, path, save, Lockfile};
+    use std::{fs::read_to_string, path::Path};
+    use tempfile::tempdir;
+
+    #[test]
+    fn path_toast_yml() {
+        assert_eq!(
+            path(Path::new("foo/toast.yml")),
+            Path::new("foo/toast.lock")
+        );
+    }
+
+    #[test]
+    fn path_no_extension() {
+        assert_eq!(path(Path::new("Toastfile")), Path::new("Toastfile.lock"));
+    }
+
+    #[test]
+    fn load_missing() {
+        let dir = tempdir().unwrap();
+
+        assert_eq!(load(&dir.path().join("toast.lock")).unwrap(), None);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let dir = tempdir().unwrap();
+        let lockfile_path = dir.path().join("toast.lock");
+        let lockfile = Lockfile {
+            image: "debian:buster".to_owned(),
+            digest: "sha256:abc".to_owned(),
+        };
+
+        save(&lockfile_path, &lockfile).unwrap();
+
+        assert_eq!(
+            read_to_string(&lockfile_path).unwrap(),
+            "# This file is generated by Toast. Run `toast --update-lock` to update it.\n\
+             image: \"debian:buster\"\n\
+             digest: \"sha256:abc\"\n",
+        );
+        assert_eq!(load(&lockfile_path).unwrap(), Some(lockfile));
+    }
+}
@@ -1,15 +1,36 @@
+mod api;
 mod cache;
+mod check;
 mod config;
 mod docker;
+mod explain;
 mod failure;
+#[cfg(test)]
+mod fake;
 mod format;
+mod gc;
+mod glob;
+mod http;
+mod lock;
+mod manifest;
+mod memo;
+mod reaper;
 mod runner;
 mod schedule;
 mod spinner;
 mod tar;
 mod toastfile;
 
-use crate::{failure::Failure, format::CodeStr};
+use crate::{
+    cache::CryptoHash,
+    explain::{Explanation, Record},
+    failure::Failure,
+    format::CodeStr,
+    gc::Usage,
+    manifest::Manifest,
+    memo::Memo,
+    toastfile::{OutputMode, OutputPath},
+};
 use atty::Stream;
 use clap::{App, AppSettings, Arg};
 use env_logger::{fmt::Color, Builder};
@@ -30,6 +51,7 @@ use std::{
         Arc, Mutex,
     },
 };
+use tempfile::{tempdir, TempDir};
 
 #[macro_use]
 extern crate lazy_static;
@@ -46,6 +68,9 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 // Defaults
 const TOASTFILE_DEFAULT_NAME: &str = "toast.yml";
 const CONFIG_FILE_XDG_PATH: &str = "toast/toast.yml";
+const HASH_MEMO_XDG_DIR: &str = "toast/hashes";
+const EXPLANATIONS_XDG_DIR: &str = "toast/explanations";
+const USAGE_XDG_PATH: &str = "toast/usage.json";
 const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
 
 // Command-line argument and option names
@@ -55,8 +80,21 @@ const READ_LOCAL_CACHE_ARG: &str = "read-local-cache";
 const WRITE_LOCAL_CACHE_ARG: &str = "write-local-cache";
 const READ_REMOTE_CACHE_ARG: &str = "read-remote-cache";
 const WRITE_REMOTE_CACHE_ARG: &str = "write-remote-cache";
+const MEMOIZE_INPUT_HASHES_ARG: &str = "memoize-input-hashes";
+const STREAM_INPUT_PATHS_ARG: &str = "stream-input-paths";
+const CHOWN_OUTPUT_PATHS_ARG: &str = "chown-output-paths";
+const OUTPUT_MANIFEST_ARG: &str = "output-manifest";
 const REPO_ARG: &str = "repo";
+const CONTAINER_ENGINE_ARG: &str = "container-engine";
+const DOCKER_API_ARG: &str = "docker-api";
+const PULL_BASE_IMAGE_ARG: &str = "pull-base-image";
+const LOCKED_ARG: &str = "locked";
+const UPDATE_LOCK_ARG: &str = "update-lock";
 const LIST_ARG: &str = "list";
+const CHECK_ARG: &str = "check";
+const EXPLAIN_ARG: &str = "explain";
+const CLEAN_ARG: &str = "clean";
+const DRY_RUN_ARG: &str = "dry-run";
 const SHELL_ARG: &str = "shell";
 const TASKS_ARG: &str = "tasks";
 
@@ -100,6 +138,7 @@ fn set_up_logging() {
 
 // Set up the signal handlers.
 fn set_up_signal_handlers(
+    backend: Arc<dyn docker::ContainerBackend>,
     interrupted: Arc<AtomicBool>,
     active_containers: Arc<Mutex<HashSet<String>>>,
 ) -> Result<(), Failure> {
@@ -113,7 +152,7 @@ fn set_up_signal_handlers(
         if interrupted.swap(true, Ordering::SeqCst) {
             // Stop any active containers. The `unwrap` will only fail if a panic already occurred.
             for container in &*active_containers.lock().unwrap() {
-                if let Err(e) = docker::stop_container(&container, &interrupted) {
+                if let Err(e) = backend.stop_container(container, &interrupted) {
                     error!("{}", e);
                 }
             }
@@ -143,11 +182,23 @@ fn parse_bool(s: &str) -> Result<bool, Failure> {
 pub struct Settings {
     toastfile_path: PathBuf,
     docker_repo: String,
+    backend: Arc<dyn docker::ContainerBackend>,
     read_local_cache: bool,
     write_local_cache: bool,
     read_remote_cache: bool,
     write_remote_cache: bool,
+    memoize_input_hashes: bool,
+    stream_input_paths: bool,
+    chown_output_paths: bool,
+    pull_base_image: bool,
+    locked: bool,
+    update_lock: bool,
+    output_manifest: Option<PathBuf>,
     list: bool,
+    check: bool,
+    explain: bool,
+    clean: Option<gc::Policy>,
+    dry_run: bool,
     spawn_shell: bool,
     tasks: Option<Vec<String>>,
 }
@@ -206,6 +257,34 @@ fn settings() -> Result<Settings, Failure> {
                 .help("Sets whether remote cache writing is enabled")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name(MEMOIZE_INPUT_HASHES_ARG)
+                .long(MEMOIZE_INPUT_HASHES_ARG)
+                .value_name("BOOL")
+                .help("Sets whether the hashes of input files are remembered between runs")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(STREAM_INPUT_PATHS_ARG)
+                .long(STREAM_INPUT_PATHS_ARG)
+                .value_name("BOOL")
+                .help("Sets whether input files are streamed directly into containers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(CHOWN_OUTPUT_PATHS_ARG)
+                .long(CHOWN_OUTPUT_PATHS_ARG)
+                .value_name("BOOL")
+                .help("Sets whether output files are owned by the user who invoked Toast")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(OUTPUT_MANIFEST_ARG)
+                .long(OUTPUT_MANIFEST_ARG)
+                .value_name("PATH")
+                .help("Writes a manifest of the output files to a JSON file")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name(REPO_ARG)
                 .short("r")
@@ -214,12 +293,72 @@ fn settings() -> Result<Settings, Failure> {
                 .help("Sets the Docker repository")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name(CONTAINER_ENGINE_ARG)
+                .long(CONTAINER_ENGINE_ARG)
+                .value_name("BINARY")
+                .help("Sets the container engine (e.g., docker or podman)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(DOCKER_API_ARG)
+                .long(DOCKER_API_ARG)
+                .value_name("BOOL")
+                .help(
+                    "Sets whether Toast talks to the Docker daemon directly instead of via the CLI",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(PULL_BASE_IMAGE_ARG)
+                .long(PULL_BASE_IMAGE_ARG)
+                .value_name("BOOL")
+                .help("Sets whether Toast pulls the latest version of the base image")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(LOCKED_ARG)
+                .long(LOCKED_ARG)
+                .conflicts_with(UPDATE_LOCK_ARG)
+                .help("Fails if the lockfile is missing or out of date"),
+        )
+        .arg(
+            Arg::with_name(UPDATE_LOCK_ARG)
+                .long(UPDATE_LOCK_ARG)
+                .help("Pins the latest version of the base image in the lockfile"),
+        )
         .arg(
             Arg::with_name(LIST_ARG)
                 .short("l")
                 .long(LIST_ARG)
                 .help("Lists the tasks in the toastfile"),
         )
+        .arg(
+            Arg::with_name(CHECK_ARG)
+                .long(CHECK_ARG)
+                .help("Verifies that the output files are up to date without changing them"),
+        )
+        .arg(
+            Arg::with_name(EXPLAIN_ARG)
+                .long(EXPLAIN_ARG)
+                .help("Explains the cache key of each task without running anything"),
+        )
+        .arg(
+            Arg::with_name(CLEAN_ARG)
+                .long(CLEAN_ARG)
+                .value_name("POLICY")
+                .help(
+                    "Deletes cached images according to a policy: all, unreachable, unused:DAYS, \
+                     or orphans",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(DRY_RUN_ARG)
+                .long(DRY_RUN_ARG)
+                .requires(CLEAN_ARG)
+                .help("Lists the images that --clean would delete without deleting them"),
+        )
         .arg(
             Arg::with_name(SHELL_ARG)
                 .short("s")
@@ -312,15 +451,70 @@ fn settings() -> Result<Settings, Failure> {
         .value_of(WRITE_REMOTE_CACHE_ARG)
         .map_or(Ok(config.write_remote_cache), |s| parse_bool(s))?;
 
+    // Read the memoization switch.
+    let memoize_input_hashes = matches
+        .value_of(MEMOIZE_INPUT_HASHES_ARG)
+        .map_or(Ok(config.memoize_input_hashes), |s| parse_bool(s))?;
+
+    // Read the streaming switch.
+    let stream_input_paths = matches
+        .value_of(STREAM_INPUT_PATHS_ARG)
+        .map_or(Ok(config.stream_input_paths), |s| parse_bool(s))?;
+
+    // Read the output ownership switch.
+    let chown_output_paths = matches
+        .value_of(CHOWN_OUTPUT_PATHS_ARG)
+        .map_or(Ok(config.chown_output_paths), |s| parse_bool(s))?;
+
+    // Read the output manifest path.
+    let output_manifest = matches.value_of(OUTPUT_MANIFEST_ARG).map(PathBuf::from);
+
     // Read the Docker repo.
     let docker_repo = matches
         .value_of(REPO_ARG)
         .unwrap_or(&config.docker_repo)
         .to_owned();
 
+    // Read the container engine.
+    let mut container_engine = docker::Engine::new(
+        matches
+            .value_of(CONTAINER_ENGINE_ARG)
+            .unwrap_or(&config.container_engine),
+    );
+
+    // Read the Docker API switch.
+    if matches
+        .value_of(DOCKER_API_ARG)
+        .map_or(Ok(config.docker_api), |s| parse_bool(s))?
+    {
+        container_engine.api = Some(api::Client::from_env()?);
+    }
+
+    // Read the base image pulling switch.
+    let pull_base_image = matches
+        .value_of(PULL_BASE_IMAGE_ARG)
+        .map_or(Ok(config.pull_base_image), |s| parse_bool(s))?;
+
+    // Read the lockfile switches.
+    let locked = matches.is_present(LOCKED_ARG);
+    let update_lock = matches.is_present(UPDATE_LOCK_ARG);
+
     // Read the list switch.
     let list = matches.is_present(LIST_ARG);
 
+    // Read the check switch.
+    let check = matches.is_present(CHECK_ARG);
+
+    // Read the explain switch.
+    let explain = matches.is_present(EXPLAIN_ARG);
+
+    // Read the cleaning policy and the dry run switch.
+    let clean = matches
+        .value_of(CLEAN_ARG)
+        .map(gc::parse_policy)
+        .transpose()?;
+    let dry_run = matches.is_present(DRY_RUN_ARG);
+
     // Read the shell switch.
     let spawn_shell = matches.is_present(SHELL_ARG);
 
@@ -337,13 +531,57 @@ fn settings() -> Result<Settings, Failure> {
         write_local_cache,
         read_remote_cache,
         write_remote_cache,
+        memoize_input_hashes,
+        stream_input_paths,
+        chown_output_paths,
+        pull_base_image,
+        locked,
+        update_lock,
+        output_manifest,
         docker_repo,
+        backend: Arc::new(container_engine),
         list,
+        check,
+        explain,
+        clean,
+        dry_run,
         spawn_shell,
         tasks,
     })
 }
 
+// Determine where to keep a file in the cache directory for a particular toastfile.
+fn cache_path(xdg_dir: &str, toastfile_path: &Path) -> Option<PathBuf> {
+    fs::canonicalize(toastfile_path)
+        .ok()
+        .and_then(|toastfile_path| {
+            dirs::cache_dir().map(|path| {
+                path.join(xdg_dir)
+                    .join(format!("{}.json", toastfile_path.crypto_hash()))
+            })
+        })
+}
+
+// Load the memoized hashes of the input files. Each toastfile gets its own memo. If memoization is
+// disabled, the memo starts out empty and isn't saved.
+fn load_memo(settings: &Settings) -> Result<Memo, Failure> {
+    if !settings.memoize_input_hashes {
+        return Memo::load(None);
+    }
+
+    Memo::load(
+        cache_path(HASH_MEMO_XDG_DIR, &settings.toastfile_path)
+            .as_ref()
+            .map(AsRef::as_ref),
+    )
+}
+
+// Determine where to keep the record of when each cached image was last used. Unlike the memo, it's
+// shared by all toastfiles.
+fn usage_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|path| path.join(USAGE_XDG_PATH))
+}
+
 // Parse a toastfile.
 fn parse_toastfile(toastfile_path: &Path) -> Result<toastfile::Toastfile, Failure> {
     // Read the file from disk.
@@ -449,7 +687,8 @@ fn fetch_environment(
     Ok(env)
 }
 
-// Run some tasks and return the final context and the last attempted task.
+// Run some tasks and return the final context and the last attempted task. Output files are copied
+// into `output_dir` if it's given, or the toastfile directory otherwise.
 #[allow(clippy::too_many_arguments)]
 fn run_tasks(
     schedule: &[&str],
@@ -458,22 +697,43 @@ fn run_tasks(
     environment: &HashMap<String, String>,
     interrupted: &Arc<AtomicBool>,
     active_containers: &Arc<Mutex<HashSet<String>>>,
+    memo: &mut Memo,
+    record: &mut Record,
+    usage: &mut Usage,
+    output_dir: Option<&Path>,
+    mut manifest: Option<&mut Manifest>,
 ) -> (Result<(), Failure>, runner::Context, Option<String>) {
     // This variable will be `true` as long as we're executing tasks that have `cache: true`. As
     // soon as we encounter a task with `cache: false`, this variable will be permanently set to
     // `false`.
     let mut caching_enabled = true;
 
-    // This is the cache key for the current task.
-    let mut cache_key = cache::initial_key(&toastfile.image);
+    // All relative paths are relative to where the toastfile lives.
+    let toastfile_dir = settings
+        .toastfile_path
+        .parent()
+        .filter(|parent| parent.components().next().is_some())
+        .unwrap_or_else(|| Path::new("."));
+    let output_dir = output_dir.unwrap_or(toastfile_dir);
 
     // We start with the base image.
     let mut context = runner::Context {
         image: toastfile.image.clone(),
         persist: true,
+        backend: settings.backend.clone(),
         interrupted: interrupted.clone(),
     };
 
+    // This is the cache key for the current task. It starts with the content digest of the base
+    // image, so the cache is invalidated when the lockfile pins a new version of the image.
+    let mut cache_key = match runner::base_image(settings, &toastfile.image, interrupted) {
+        Ok((image, digest)) => {
+            context.image = image;
+            cache::initial_key(&toastfile.image, &digest)
+        }
+        Err(e) => return (Err(e), context, None),
+    };
+
     // Run each task in the schedule.
     for task in schedule {
         // Fetch the data for the current task.
@@ -488,13 +748,24 @@ fn run_tasks(
             return (Err(Failure::Interrupted), context, Some((*task).to_owned()));
         }
 
+        // Make sure the task's paths don't escape the toastfile directory via symbolic links.
+        // [ref:paths_resolve_inside]
+        if let Err(e) = toastfile::check_task_paths_resolve(task, task_data, &toastfile_dir) {
+            return (Err(e), context, Some((*task).to_owned()));
+        }
+
         // Run the task.
         info!("Running task {}\u{2026}", task.code_str());
+        let mut exports = runner::Exports::default();
         let (result, new_context) = runner::run(
             settings,
             &environment,
             &interrupted,
             &active_containers,
+            memo,
+            &mut exports,
+            output_dir,
+            task,
             task_data,
             &cache_key,
             caching_enabled,
@@ -504,11 +775,33 @@ fn run_tasks(
         // Remember the context for the next task.
         context = new_context;
 
-        // Retrieve the cache key from the result.
+        // Record the output files in the manifest, if there is one. This happens even if the task
+        // failed, since its `output_paths_on_failure` may have been copied.
+        if let Some(manifest) = &mut manifest {
+            if let Err(e) = manifest.add(output_dir, task, &exports.cache_key, &exports.paths) {
+                if result.is_ok() {
+                    return (Err(e), context, Some((*task).to_owned()));
+                }
+
+                error!("{}", e);
+            }
+        }
+
+        // Retrieve the cache key from the result, and remember how it was computed.
         cache_key = match result {
-            Ok(new_cache_key) => new_cache_key,
+            Ok(explanation) => {
+                let new_cache_key = explanation.key.clone();
+                record.insert(task, explanation);
+                new_cache_key
+            }
             Err(e) => return (Err(e), context, Some((*task).to_owned())),
         };
+
+        // Remember when the image was used so it isn't cleaned up too soon. Temporary images will
+        // be deleted anyway.
+        if context.persist {
+            usage.touch(&context.image, gc::now());
+        }
     }
 
     // Everything succeeded.
@@ -519,6 +812,254 @@ fn run_tasks(
     )
 }
 
+// Compute the cache key of each task in the schedule without running anything, and explain what
+// went into it. The cache keys start with `digest`, the content digest of the base image.
+fn explain_schedule(
+    schedule: &[&str],
+    settings: &Settings,
+    toastfile: &toastfile::Toastfile,
+    environment: &HashMap<String, String>,
+    digest: &str,
+    interrupted: &Arc<AtomicBool>,
+    memo: &mut Memo,
+) -> Result<Vec<Explanation>, Failure> {
+    // All relative paths are relative to where the toastfile lives.
+    let toastfile_dir = settings
+        .toastfile_path
+        .parent()
+        .filter(|parent| parent.components().next().is_some())
+        .unwrap_or_else(|| Path::new("."));
+
+    // The cache keys start with the content digest of the base image, just like in `run_tasks`.
+    let mut cache_key = cache::initial_key(&toastfile.image, digest);
+
+    // Explain each task in the schedule.
+    let mut explanations = vec![];
+    for task in schedule {
+        // Fetch the data for the current task.
+        let task_data = &toastfile.tasks[*task]; // [ref:tasks_valid]
+
+        // Make sure the task's paths don't escape the toastfile directory via symbolic links.
+        // [ref:paths_resolve_inside]
+        toastfile::check_task_paths_resolve(task, task_data, toastfile_dir)?;
+
+        // Compute the cache key.
+        let explanation = runner::explain(
+            settings,
+            environment,
+            interrupted,
+            memo,
+            task_data,
+            &cache_key,
+        )?;
+        cache_key.clone_from(&explanation.key);
+        explanations.push(explanation);
+    }
+
+    Ok(explanations)
+}
+
+// Explain the cache key of each task in the schedule without running anything, and compare it with
+// the one from the last successful run of the task.
+fn explain_tasks(
+    schedule: &[&str],
+    settings: &Settings,
+    toastfile: &toastfile::Toastfile,
+    environment: &HashMap<String, String>,
+    interrupted: &Arc<AtomicBool>,
+    memo: &mut Memo,
+    record: &Record,
+) -> Result<(), Failure> {
+    let (_, digest) = runner::base_image(settings, &toastfile.image, interrupted)?;
+    let explanations = explain_schedule(
+        schedule,
+        settings,
+        toastfile,
+        environment,
+        &digest,
+        interrupted,
+        memo,
+    )?;
+    let mut caching_enabled = true;
+
+    for (task, explanation) in schedule.iter().zip(explanations) {
+        // Once caching is disabled, it stays disabled for the rest of the schedule.
+        caching_enabled = caching_enabled && toastfile.tasks[*task].cache; // [ref:tasks_valid]
+
+        // Determine whether the task would be skipped. Only the local cache is checked, since
+        // checking the remote cache would require pulling the image.
+        let status = if !caching_enabled {
+            "caching disabled"
+        } else if settings.read_local_cache
+            && settings.backend.image_exists(
+                &format!("{}:{}", settings.docker_repo, explanation.key),
+                interrupted,
+            )?
+        {
+            "cached locally"
+        } else {
+            "not cached locally"
+        };
+
+        // Print the components of the cache key.
+        println!("* {} ({})", task.code_str(), status);
+        explanation.print();
+
+        // Compare them with the last successful run.
+        match record.get(task) {
+            Some(earlier) => {
+                let changes = explanation.changes(earlier);
+                if changes.is_empty() {
+                    println!("  Nothing changed since the last successful run.");
+                } else {
+                    println!("  Changes since the last successful run:");
+                    for change in changes {
+                        println!("    - {}", change);
+                    }
+                }
+            }
+            None => println!("  There is no record of a previous successful run."),
+        }
+    }
+
+    Ok(())
+}
+
+// Delete the cached images selected by a policy, or just list them in a dry run.
+fn clean(
+    settings: &Settings,
+    toastfile: &toastfile::Toastfile,
+    policy: gc::Policy,
+    usage: &mut Usage,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(), Failure> {
+    // Orphans are found by their labels rather than by their names. [tag:orphans_reaped]
+    if policy == gc::Policy::Orphans {
+        return reaper::reap(&*settings.backend, settings.dry_run, interrupted);
+    }
+
+    // Determine which images the schedule for the toastfile uses, if the policy depends on that.
+    // Cleaning up shouldn't pull the base image or create the lockfile.
+    let reachable = if policy == gc::Policy::Unreachable {
+        let schedule = schedule::compute(toastfile, &get_roots(settings, toastfile)?);
+        let environment = fetch_environment(&schedule, &toastfile.tasks)?;
+        let digest = runner::local_base_image_digest(settings, &toastfile.image, interrupted)?;
+        explain_schedule(
+            &schedule,
+            settings,
+            toastfile,
+            &environment,
+            &digest,
+            interrupted,
+            &mut load_memo(settings)?,
+        )?
+        .into_iter()
+        .map(|explanation| explanation.key)
+        .collect()
+    } else {
+        HashSet::new()
+    };
+
+    // Select the images to delete.
+    let images = settings
+        .backend
+        .list_images(&settings.docker_repo, interrupted)?;
+    let selected = gc::select(&images, policy, &reachable, usage, gc::now());
+    let total_size = selected.iter().map(|image| image.size).sum();
+    for image in &selected {
+        println!(
+            "* {} ({})",
+            image.name.code_str(),
+            format::bytes(image.size)
+        );
+    }
+
+    // In a dry run, we're done.
+    if settings.dry_run {
+        info!(
+            "{} would be deleted, totaling {}.",
+            format::number(selected.len(), "image"),
+            format::bytes(total_size),
+        );
+        return Ok(());
+    }
+
+    // Delete the images.
+    let mut result = Ok(());
+    for image in &selected {
+        result = settings.backend.delete_image(&image.name, interrupted);
+        if result.is_err() {
+            break;
+        }
+        usage.forget(&image.name, gc::now());
+    }
+
+    // Forget about the deleted images. This only affects future cleanups, so failure isn't fatal.
+    if let Err(e) = usage.save() {
+        warn!("Unable to save the image usage. Details: {}", e);
+    }
+
+    result?;
+    info!(
+        "Deleted {}, totaling {}.",
+        format::number(selected.len(), "image"),
+        format::bytes(total_size),
+    );
+
+    Ok(())
+}
+
+// Compare the output files in `staging_dir` with the files in the toastfile directory, and fail if
+// any of them differ.
+fn check_outputs(
+    schedule: &[&str],
+    settings: &Settings,
+    toastfile: &toastfile::Toastfile,
+    staging_dir: &Path,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(), Failure> {
+    // All relative paths are relative to where the toastfile lives.
+    let toastfile_dir = settings
+        .toastfile_path
+        .parent()
+        .filter(|parent| parent.components().next().is_some())
+        .unwrap_or_else(|| Path::new("."));
+
+    // Mirrored paths would also lose any files which the tasks didn't produce.
+    let mirrored_paths = schedule
+        .iter()
+        .flat_map(|task| &toastfile.tasks[*task].output_paths) // [ref:tasks_valid]
+        .filter_map(|output_path| match output_path {
+            OutputPath::Mapping {
+                mode: OutputMode::Mirror,
+                ..
+            } => Some(output_path.destination().to_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // Find the files which are out of date.
+    let differences = check::drift(staging_dir, toastfile_dir, &mirrored_paths, interrupted)?;
+    if differences.is_empty() {
+        info!("The output files are up to date.");
+        Ok(())
+    } else {
+        Err(Failure::User(
+            format!(
+                "The following output files are out of date: {}.",
+                format::series(
+                    differences
+                        .iter()
+                        .map(|path| format!("{}", path.to_string_lossy().code_str()))
+                        .collect::<Vec<_>>()
+                        .as_ref()
+                )
+            ),
+            None,
+        ))
+    }
+}
+
 // Program entrypoint
 fn entry() -> Result<(), Failure> {
     // Determine whether to print colored output.
@@ -531,12 +1072,16 @@ fn entry() -> Result<(), Failure> {
     let interrupted = Arc::new(AtomicBool::new(false));
     let active_containers = Arc::new(Mutex::new(HashSet::<String>::new()));
 
-    // Set up the signal handlers.
-    set_up_signal_handlers(interrupted.clone(), active_containers.clone())?;
-
     // Parse the command-line arguments;
     let settings = settings()?;
 
+    // Set up the signal handlers.
+    set_up_signal_handlers(
+        settings.backend.clone(),
+        interrupted.clone(),
+        active_containers.clone(),
+    )?;
+
     // Parse the toastfile.
     let toastfile = parse_toastfile(&settings.toastfile_path)?;
 
@@ -574,6 +1119,23 @@ fn entry() -> Result<(), Failure> {
         return Ok(());
     }
 
+    // If the user just wants to update the lockfile, do that and quit.
+    if settings.update_lock {
+        runner::update_lockfile(&settings, &toastfile.image, &interrupted)?;
+        return Ok(());
+    }
+
+    // If the user just wants to clean up the cache, do that and quit.
+    if let Some(policy) = settings.clean {
+        return clean(
+            &settings,
+            &toastfile,
+            policy,
+            &mut Usage::load(usage_path().as_ref().map(AsRef::as_ref)),
+            &interrupted,
+        );
+    }
+
     // Determine which tasks the user wants to run.
     let root_tasks = get_roots(&settings, &toastfile)?;
 
@@ -596,6 +1158,53 @@ fn entry() -> Result<(), Failure> {
     // Fetch all the environment variables used by the tasks in the schedule.
     let environment = fetch_environment(&schedule, &toastfile.tasks)?;
 
+    // Load the memoized hashes of the input files.
+    let mut memo = load_memo(&settings)?;
+
+    // Load the explanations of the cache keys from the last successful runs. Each toastfile gets
+    // its own record.
+    let mut record = Record::load(
+        cache_path(EXPLANATIONS_XDG_DIR, &settings.toastfile_path)
+            .as_ref()
+            .map(AsRef::as_ref),
+    );
+
+    // Load the record of when each cached image was last used.
+    let mut usage = Usage::load(usage_path().as_ref().map(AsRef::as_ref));
+
+    // If the user just wants to know about the cache keys, explain them and quit.
+    if settings.explain {
+        return explain_tasks(
+            &schedule,
+            &settings,
+            &toastfile,
+            &environment,
+            &interrupted,
+            &mut memo,
+            &record,
+        );
+    }
+
+    // Clean up after earlier runs which were killed before they could do so themselves. Failing to
+    // do so isn't fatal.
+    match reaper::reap(&*settings.backend, false, &interrupted) {
+        Ok(()) => {}
+        Err(Failure::Interrupted) => return Err(Failure::Interrupted),
+        Err(e) => warn!("Unable to clean up after earlier runs. Details: {}", e),
+    }
+
+    // Prepare a manifest of the output files if the user wants one.
+    let mut manifest = settings.output_manifest.as_ref().map(|_| Manifest::new());
+
+    // In check mode, the output files are copied into a staging directory rather than the toastfile
+    // directory so they can be compared with the working tree.
+    let staging_dir = if settings.check {
+        check::check_schedule(&schedule, &toastfile)?;
+        Some(tempdir().map_err(failure::system("Unable to create temporary directory."))?)
+    } else {
+        None
+    };
+
     // Execute the schedule.
     let (result, context, last_task) = run_tasks(
         &schedule,
@@ -604,8 +1213,57 @@ fn entry() -> Result<(), Failure> {
         &environment,
         &interrupted,
         &active_containers,
+        &mut memo,
+        &mut record,
+        &mut usage,
+        staging_dir.as_ref().map(TempDir::path),
+        manifest.as_mut(),
     );
 
+    // In check mode, compare the staged output files with the working tree.
+    let result = match (result, &staging_dir) {
+        (Ok(()), Some(staging_dir)) => check_outputs(
+            &schedule,
+            &settings,
+            &toastfile,
+            staging_dir.path(),
+            &interrupted,
+        ),
+        (result, _) => result,
+    };
+
+    // Save the memoized hashes for next time. This is only an optimization, so failure isn't fatal.
+    if let Err(e) = memo.save() {
+        warn!(
+            "Unable to save the memoized input file hashes. Details: {}",
+            e
+        );
+    }
+
+    // Save the explanations of the cache keys for next time. This is only for diagnostics, so
+    // failure isn't fatal either.
+    if let Err(e) = record.save() {
+        warn!("Unable to save the cache key explanations. Details: {}", e);
+    }
+
+    // Save the image usage for future cleanups. This isn't fatal either.
+    if let Err(e) = usage.save() {
+        warn!("Unable to save the image usage. Details: {}", e);
+    }
+
+    // Write the manifest, if requested. This happens even if a task failed, in which case the
+    // manifest lists the files from the tasks which succeeded and the `output_paths_on_failure` of
+    // the one which failed.
+    if let (Some(manifest), Some(path)) = (&manifest, &settings.output_manifest) {
+        if let Err(e) = manifest.save(path) {
+            if result.is_ok() {
+                return Err(e);
+            }
+
+            error!("{}", e);
+        }
+    }
+
     // Return early if needed.
     match result {
         Ok(_) | Err(Failure::User(_, _)) => {
@@ -656,7 +1314,7 @@ fn entry() -> Result<(), Failure> {
         };
 
         // Spawn the shell.
-        docker::spawn_shell(
+        settings.backend.spawn_shell(
             &context.image,
             &task_environment,
             &location,
@@ -677,3 +1335,399 @@ fn main() {
         exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        clean,
+        explain::Record,
+        failure::Failure,
+        fake,
+        fake::{Behavior, Call, Fake},
+        gc,
+        gc::Usage,
+        manifest::Manifest,
+        memo::Memo,
+        run_tasks, runner, toastfile, Settings,
+    };
+    use std::{
+        collections::{HashMap, HashSet},
+        fs::read_to_string,
+        path::PathBuf,
+        sync::{atomic::AtomicBool, Arc, Mutex},
+    };
+    use tempfile::tempdir;
+
+    const TOASTFILE: &str = r#"
+image: debian
+tasks:
+  foo:
+    command: make foo
+    output_paths:
+      - foo.txt
+  bar:
+    dependencies:
+      - foo
+    command: make bar
+    output_paths:
+      - bar.txt
+"#;
+
+    // Run the `foo` and `bar` tasks from `TOASTFILE` in the toastfile directory.
+    fn run_foo_bar(
+        settings: &Settings,
+        toastfile_data: &str,
+    ) -> (Result<(), Failure>, runner::Context, Option<String>) {
+        let toastfile = toastfile::parse(toastfile_data).unwrap();
+
+        run_tasks(
+            &["foo", "bar"],
+            settings,
+            &toastfile,
+            &HashMap::new(),
+            &Arc::new(AtomicBool::new(false)),
+            &Arc::new(Mutex::new(HashSet::new())),
+            &mut Memo::load(None).unwrap(),
+            &mut Record::load(None),
+            &mut Usage::load(None),
+            None,
+            None,
+        )
+    }
+
+    // Make the commands in `TOASTFILE` write their output files.
+    fn set_up_commands(fake: &Fake) {
+        fake.on_command(
+            "make foo",
+            Behavior::Succeed(vec![(PathBuf::from("/scratch/foo.txt"), "foo".to_owned())]),
+        );
+        fake.on_command(
+            "make bar",
+            Behavior::Succeed(vec![(PathBuf::from("/scratch/bar.txt"), "bar".to_owned())]),
+        );
+    }
+
+    #[test]
+    fn run_tasks_schedule() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_remote_image("debian");
+        set_up_commands(&fake);
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+
+        let (result, context, last_task) = run_foo_bar(&settings, TOASTFILE);
+
+        assert!(result.is_ok());
+        assert_eq!(last_task, Some("bar".to_owned()));
+        assert_eq!(read_to_string(dir.path().join("foo.txt")).unwrap(), "foo");
+        assert_eq!(read_to_string(dir.path().join("bar.txt")).unwrap(), "bar");
+
+        // The base image is pulled, and each task runs on top of the image from the previous one.
+        let created_images = fake
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::CreateContainer(image, _) => Some(image),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(fake.calls().contains(&Call::PullImage("debian".to_owned())));
+        assert_eq!(created_images.len(), 2);
+        assert_eq!(created_images[0], "debian");
+        assert_ne!(created_images[1], "debian");
+        assert!(fake.local_images().contains(&created_images[1]));
+        assert!(fake.local_images().contains(&context.image));
+    }
+
+    #[test]
+    fn run_tasks_cache_disabled() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        set_up_commands(&fake);
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+
+        let (result, context, _) = run_foo_bar(
+            &settings,
+            &TOASTFILE.replace("command: make foo", "command: make foo\n    cache: false"),
+        );
+
+        // Neither task checks the cache, so the only images looked up are the base image (to find
+        // its digest) and the ones the containers are created from. The resulting images are
+        // temporary.
+        assert!(result.is_ok());
+        assert!(!context.persist);
+        assert_eq!(
+            fake.calls()
+                .into_iter()
+                .filter(|call| match call {
+                    Call::ImageExists(_) => true,
+                    _ => false,
+                })
+                .count(),
+            3,
+        );
+    }
+
+    #[test]
+    fn run_tasks_base_image_updated() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_remote_image("debian");
+        set_up_commands(&fake);
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let starts = || {
+            fake.calls()
+                .into_iter()
+                .filter(|call| match call {
+                    Call::StartContainer(_) => true,
+                    _ => false,
+                })
+                .count()
+        };
+
+        // The second run is cached.
+        assert!(run_foo_bar(&settings, TOASTFILE).0.is_ok());
+        assert!(run_foo_bar(&settings, TOASTFILE).0.is_ok());
+        assert_eq!(starts(), 2);
+
+        // A new version of the base image isn't used, even when pulling is enabled, since the
+        // lockfile pins the old one.
+        fake.add_remote_image("debian");
+        settings.pull_base_image = true;
+        assert!(run_foo_bar(&settings, TOASTFILE).0.is_ok());
+        assert_eq!(starts(), 2);
+
+        // Once the lockfile is updated, the tasks run again.
+        runner::update_lockfile(&settings, "debian", &Arc::new(AtomicBool::new(false))).unwrap();
+        assert!(run_foo_bar(&settings, TOASTFILE).0.is_ok());
+        assert_eq!(starts(), 4);
+    }
+
+    #[test]
+    fn run_tasks_record() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        set_up_commands(&fake);
+        fake.on_command("make bar", Behavior::Fail(vec![]));
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let mut record = Record::load(None);
+
+        let (result, _, _) = run_tasks(
+            &["foo", "bar"],
+            &settings,
+            &toastfile::parse(TOASTFILE).unwrap(),
+            &HashMap::new(),
+            &Arc::new(AtomicBool::new(false)),
+            &Arc::new(Mutex::new(HashSet::new())),
+            &mut Memo::load(None).unwrap(),
+            &mut record,
+            &mut Usage::load(None),
+            None,
+            None,
+        );
+
+        // Only the task which succeeded is recorded.
+        assert!(result.is_err());
+        assert_eq!(record.get("foo").unwrap().command, "make foo");
+        assert!(record.get("bar").is_none());
+    }
+
+    #[test]
+    fn run_tasks_failure() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        set_up_commands(&fake);
+        fake.on_command("make foo", Behavior::Fail(vec![]));
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+
+        let (result, _, last_task) = run_foo_bar(&settings, TOASTFILE);
+
+        match result {
+            Err(Failure::User(message, _)) => assert_eq!(message, "Command failed."),
+            _ => panic!("The task should have failed."),
+        }
+        assert_eq!(last_task, Some("foo".to_owned()));
+        assert!(!fake.calls().iter().any(|call| match call {
+            Call::CreateContainer(_, command) if command == "make bar" => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn run_tasks_manifest_failure() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.on_command(
+            "make foo",
+            Behavior::Fail(vec![(PathBuf::from("/scratch/foo.log"), "oops".to_owned())]),
+        );
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let toastfile = toastfile::parse(
+            r#"
+image: debian
+tasks:
+  foo:
+    command: make foo
+    output_paths:
+      - foo.txt
+    output_paths_on_failure:
+      - foo.log
+"#,
+        )
+        .unwrap();
+        let mut manifest = Manifest::new();
+
+        let (result, _, _) = run_tasks(
+            &["foo"],
+            &settings,
+            &toastfile,
+            &HashMap::new(),
+            &Arc::new(AtomicBool::new(false)),
+            &Arc::new(Mutex::new(HashSet::new())),
+            &mut Memo::load(None).unwrap(),
+            &mut Record::load(None),
+            &mut Usage::load(None),
+            None,
+            Some(&mut manifest),
+        );
+        assert!(result.is_err());
+
+        // The file copied on failure is recorded.
+        let manifest_path = dir.path().join("manifest.json");
+        manifest.save(&manifest_path).unwrap();
+        let files =
+            &serde_json::from_str::<serde_json::Value>(&read_to_string(&manifest_path).unwrap())
+                .unwrap()["files"];
+        assert_eq!(files.as_array().unwrap().len(), 1);
+        assert_eq!(files[0]["path"], "foo.log");
+        assert_eq!(files[0]["task"], "foo");
+    }
+
+    #[test]
+    fn run_tasks_usage() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        set_up_commands(&fake);
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let mut usage = Usage::load(None);
+
+        let (result, context, _) = run_tasks(
+            &["foo", "bar"],
+            &settings,
+            &toastfile::parse(TOASTFILE).unwrap(),
+            &HashMap::new(),
+            &Arc::new(AtomicBool::new(false)),
+            &Arc::new(Mutex::new(HashSet::new())),
+            &mut Memo::load(None).unwrap(),
+            &mut Record::load(None),
+            &mut usage,
+            None,
+            None,
+        );
+
+        // The base image isn't a cached image, so only the task images are recorded.
+        assert!(result.is_ok());
+        assert!(usage.get(&context.image).is_some());
+        assert!(usage.get("debian").is_none());
+    }
+
+    #[test]
+    fn clean_all() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.add_local_image("toast:toast-foo");
+        fake.add_local_image("toast:toast-bar");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+
+        let result = clean(
+            &settings,
+            &toastfile::parse(TOASTFILE).unwrap(),
+            gc::Policy::All,
+            &mut Usage::load(None),
+            &Arc::new(AtomicBool::new(false)),
+        );
+
+        // Only the images in the Docker repository are deleted.
+        assert!(result.is_ok());
+        assert_eq!(fake.local_images(), vec!["debian"]);
+    }
+
+    #[test]
+    fn clean_dry_run() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("toast:toast-foo");
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        settings.dry_run = true;
+
+        let result = clean(
+            &settings,
+            &toastfile::parse(TOASTFILE).unwrap(),
+            gc::Policy::All,
+            &mut Usage::load(None),
+            &Arc::new(AtomicBool::new(false)),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(fake.local_images(), vec!["toast:toast-foo"]);
+    }
+
+    #[test]
+    fn clean_unreachable() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        set_up_commands(&fake);
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+
+        let (result, context, _) = run_foo_bar(&settings, TOASTFILE);
+        assert!(result.is_ok());
+        fake.add_local_image("toast:toast-stale");
+
+        let result = clean(
+            &settings,
+            &toastfile::parse(TOASTFILE).unwrap(),
+            gc::Policy::Unreachable,
+            &mut Usage::load(None),
+            &Arc::new(AtomicBool::new(false)),
+        );
+
+        // The images for the current schedule are kept.
+        assert!(result.is_ok());
+        assert!(!fake
+            .local_images()
+            .contains(&"toast:toast-stale".to_owned()));
+        assert!(fake.local_images().contains(&context.image));
+    }
+
+    #[test]
+    fn clean_unreachable_read_only() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.add_remote_image("debian");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+
+        let result = clean(
+            &settings,
+            &toastfile::parse(TOASTFILE).unwrap(),
+            gc::Policy::Unreachable,
+            &mut Usage::load(None),
+            &Arc::new(AtomicBool::new(false)),
+        );
+
+        // The base image isn't pulled, and the lockfile isn't created.
+        assert!(result.is_ok());
+        assert!(!fake.calls().iter().any(|call| match call {
+            Call::PullImage(_) => true,
+            _ => false,
+        }));
+        assert!(!dir.path().join("toast.lock").exists());
+    }
+}
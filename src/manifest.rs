@@ -0,0 +1,190 @@
+use crate::{cache, failure, failure::Failure, format::CodeStr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{write, File},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+// A file which was copied out of a container
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub task: String,
+    pub cache_key: String,
+}
+
+// The on-disk representation of a manifest
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    files: Vec<Entry>,
+}
+
+// A manifest records the files which Toast copied out of containers. If several tasks produce the
+// same file, the last one wins.
+pub struct Manifest {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Manifest {
+    // Create an empty manifest.
+    pub fn new() -> Self {
+        Manifest {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    // Record the files which a task copied to the host. Directories are traversed, but symbolic
+    // links are not followed, and only regular files are recorded. The paths are recorded relative
+    // to `toastfile_dir`.
+    pub fn add(
+        &mut self,
+        toastfile_dir: &Path,
+        task: &str,
+        cache_key: &str,
+        paths: &[PathBuf],
+    ) -> Result<(), Failure> {
+        for path in paths {
+            for entry in WalkDir::new(path).sort_by(|x, y| x.file_name().cmp(y.file_name())) {
+                // If we run into an error traversing the filesystem, report it.
+                let entry = entry.map_err(failure::system(format!(
+                    "Unable to traverse directory {}.",
+                    path.to_string_lossy().code_str(),
+                )))?;
+
+                // Skip anything which isn't a regular file.
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+
+                // Fetch the size of the file.
+                let size = entry
+                    .metadata()
+                    .map_err(failure::system(format!(
+                        "Unable to fetch filesystem metadata for {}.",
+                        entry.path().to_string_lossy().code_str(),
+                    )))?
+                    .len();
+
+                // Compute the hash of the file contents.
+                let sha256 = cache::hash_read(&mut File::open(entry.path()).map_err(
+                    failure::system(format!(
+                        "Unable to open file {}.",
+                        entry.path().to_string_lossy().code_str(),
+                    )),
+                )?)?;
+
+                // Record the file relative to the toastfile directory.
+                let relative_path = entry
+                    .path()
+                    .strip_prefix(toastfile_dir)
+                    .unwrap_or_else(|_| entry.path())
+                    .to_owned();
+                self.entries.insert(
+                    relative_path.clone(),
+                    Entry {
+                        path: relative_path,
+                        size,
+                        sha256,
+                        task: task.to_owned(),
+                        cache_key: cache_key.to_owned(),
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write the manifest to a file as JSON. The files are listed in path order.
+    pub fn save(&self, path: &Path) -> Result<(), Failure> {
+        let data = serde_json::to_string_pretty(&ManifestFile {
+            files: self.entries.values().cloned().collect(),
+        })
+        .map_err(failure::system("Unable to serialize the manifest."))?;
+
+        write(path, data).map_err(failure::system(format!(
+            "Unable to write file {}.",
+            path.to_string_lossy().code_str(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::CryptoHash,
+        manifest::{Entry, Manifest, ManifestFile},
+    };
+    use std::{
+        fs::{create_dir_all, read_to_string, write},
+        os::unix::fs::symlink,
+        path::Path,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn manifest_save() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("dist/nested")).unwrap();
+        write(dir.path().join("dist/nested/foo.txt"), "foo").unwrap();
+        symlink("nested/foo.txt", dir.path().join("dist/bar.txt")).unwrap();
+        write(dir.path().join("baz.txt"), "bazqux").unwrap();
+
+        let mut manifest = Manifest::new();
+        manifest
+            .add(dir.path(), "build", "abc", &[dir.path().join("dist")])
+            .unwrap();
+        manifest
+            .add(dir.path(), "package", "def", &[dir.path().join("baz.txt")])
+            .unwrap();
+
+        let manifest_path = dir.path().join("manifest.json");
+        manifest.save(&manifest_path).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<ManifestFile>(&read_to_string(&manifest_path).unwrap()).unwrap(),
+            ManifestFile {
+                files: vec![
+                    Entry {
+                        path: Path::new("baz.txt").to_owned(),
+                        size: 6,
+                        sha256: "bazqux".crypto_hash(),
+                        task: "package".to_owned(),
+                        cache_key: "def".to_owned(),
+                    },
+                    Entry {
+                        path: Path::new("dist/nested/foo.txt").to_owned(),
+                        size: 3,
+                        sha256: "foo".crypto_hash(),
+                        task: "build".to_owned(),
+                        cache_key: "abc".to_owned(),
+                    },
+                ],
+            },
+        );
+    }
+
+    #[test]
+    fn manifest_last_task_wins() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo.txt"), "foo").unwrap();
+
+        let mut manifest = Manifest::new();
+        manifest
+            .add(dir.path(), "build", "abc", &[dir.path().join("foo.txt")])
+            .unwrap();
+        manifest
+            .add(dir.path(), "package", "def", &[dir.path().join("foo.txt")])
+            .unwrap();
+
+        let entries = manifest.entries.values().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].task, "package");
+    }
+}
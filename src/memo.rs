@@ -0,0 +1,334 @@
+use crate::{failure, failure::Failure, format::CodeStr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    env::current_dir,
+    fs::{create_dir_all, read_to_string, symlink_metadata, Metadata, OpenOptions},
+    io,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+
+// Filesystems only record timestamps with limited precision, so a file that was modified very
+// recently might be modified again without its timestamps changing. We don't memoize the hashes of
+// files that have changed within this window.
+const MINIMUM_AGE: Duration = Duration::from_secs(2);
+
+// The filesystem metadata which identifies a particular version of a file
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Fingerprint {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ctime: i64,
+    ctime_nsec: i64,
+    inode: u64,
+    device: u64,
+}
+
+impl Fingerprint {
+    fn new(metadata: &Metadata) -> Self {
+        Fingerprint {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            ctime: metadata.ctime(),
+            ctime_nsec: metadata.ctime_nsec(),
+            inode: metadata.ino(),
+            device: metadata.dev(),
+        }
+    }
+
+    // Determine whether the fingerprint can be trusted to change whenever the file does. Files with
+    // timestamps that are very recent, in the future, or at the epoch (which some tools use to
+    // produce reproducible archives) are not trustworthy.
+    fn is_trustworthy(&self, minimum_age: Duration) -> bool {
+        let now = SystemTime::now();
+        [(self.mtime, self.mtime_nsec), (self.ctime, self.ctime_nsec)]
+            .iter()
+            .all(|&(seconds, nanoseconds)| {
+                if seconds <= 0 || nanoseconds < 0 {
+                    return false;
+                }
+
+                // The casts are safe because we checked that the numbers are positive above.
+                #[allow(clippy::cast_sign_loss)]
+                let timestamp = UNIX_EPOCH
+                    + Duration::from_secs(seconds as u64)
+                    + Duration::from_nanos(nanoseconds as u64);
+
+                now.duration_since(timestamp)
+                    .map(|age| age >= minimum_age)
+                    .unwrap_or(false)
+            })
+    }
+}
+
+// A memoized file hash
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    fingerprint: Fingerprint,
+    hash: String,
+}
+
+// Read the entries of a memo from a file, if it exists and can be parsed.
+fn read_entries(path: &Path) -> Option<HashMap<PathBuf, Entry>> {
+    read_to_string(path)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+}
+
+// A memo remembers the hashes of files so they don't need to be read again when they haven't
+// changed. It's persisted on disk between runs.
+pub struct Memo {
+    path: Option<PathBuf>,
+    working_dir: PathBuf,
+    minimum_age: Duration,
+    entries: HashMap<PathBuf, Entry>,
+    used_paths: HashSet<PathBuf>,
+}
+
+impl Memo {
+    // Load a memo from a file. If the file doesn't exist or can't be read, start with an empty
+    // memo. If `path` is `None`, the memo won't be persisted.
+    pub fn load(path: Option<&Path>) -> Result<Self, Failure> {
+        let entries = path
+            .and_then(|path| {
+                debug!(
+                    "Attempting to load hash memo {}\u{2026}",
+                    path.to_string_lossy().code_str()
+                );
+
+                read_entries(path)
+            })
+            .unwrap_or_else(|| {
+                debug!("Hash memo not found. Starting with an empty one.");
+                HashMap::new()
+            });
+
+        Ok(Memo {
+            path: path.map(ToOwned::to_owned),
+            working_dir: current_dir()
+                .map_err(failure::system("Unable to determine working directory."))?,
+            minimum_age: MINIMUM_AGE,
+            entries,
+            used_paths: HashSet::new(),
+        })
+    }
+
+    // Memo keys are absolute paths, so a memo is independent of the working directory.
+    fn key(&self, path: &Path) -> PathBuf {
+        self.working_dir.join(path)
+    }
+
+    // Look up the memoized hash of a file, if it hasn't changed since it was memoized.
+    pub fn get(&mut self, path: &Path, metadata: &Metadata) -> Option<String> {
+        let key = self.key(path);
+        let fingerprint = Fingerprint::new(metadata);
+        let entry = self.entries.get(&key)?;
+
+        if entry.fingerprint == fingerprint && fingerprint.is_trustworthy(self.minimum_age) {
+            let hash = entry.hash.clone();
+            self.used_paths.insert(key);
+            Some(hash)
+        } else {
+            None
+        }
+    }
+
+    // Memoize the hash of a file. The hash will only be remembered if the metadata is trustworthy.
+    pub fn insert(&mut self, path: &Path, metadata: &Metadata, hash: &str) {
+        let key = self.key(path);
+        let fingerprint = Fingerprint::new(metadata);
+
+        if fingerprint.is_trustworthy(self.minimum_age) {
+            self.entries.insert(
+                key.clone(),
+                Entry {
+                    fingerprint,
+                    hash: hash.to_owned(),
+                },
+            );
+            self.used_paths.insert(key);
+        } else {
+            self.entries.remove(&key);
+        }
+    }
+
+    // Write the memo back to disk. Entries for files which no longer exist are discarded. The memo
+    // is written to a temporary file first and then renamed, so concurrent runs never observe a
+    // partially written memo. Concurrent runs take turns via a lock file, and each one merges in
+    // the entries saved by the others since it loaded the memo, so no entries are lost.
+    pub fn save(&mut self) -> Result<(), Failure> {
+        // If the memo isn't backed by a file, there's nothing to do.
+        let path = if let Some(path) = &self.path {
+            path
+        } else {
+            return Ok(());
+        };
+
+        // Make sure the parent directory exists. The `unwrap` is safe because `path` is a file.
+        let parent = path.parent().unwrap();
+        create_dir_all(parent).map_err(failure::system(format!(
+            "Unable to create directory {}.",
+            parent.to_string_lossy().code_str(),
+        )))?;
+
+        // Lock the memo until this function returns, when the lock file is closed.
+        let lock_path = path.with_extension("lock");
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(failure::system(format!(
+                "Unable to open file {}.",
+                lock_path.to_string_lossy().code_str(),
+            )))?;
+
+        // The `unsafe` is needed for calling a C function. The file descriptor is valid for as long
+        // as `lock_file` is alive.
+        if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(failure::system(format!(
+                "Unable to lock file {}.",
+                lock_path.to_string_lossy().code_str(),
+            ))(io::Error::last_os_error()));
+        }
+
+        // Merge in the entries saved by other runs. Our own entries take precedence, since they
+        // reflect the latest state of the files we looked at.
+        if let Some(mut entries) = read_entries(path) {
+            entries.extend(self.entries.drain());
+            self.entries = entries;
+        }
+
+        // Forget about files that no longer exist.
+        let used_paths = &self.used_paths;
+        self.entries
+            .retain(|key, _| used_paths.contains(key) || symlink_metadata(key).is_ok());
+
+        // Write the memo to a temporary file in the same directory.
+        let temp_file = NamedTempFile::new_in(parent).map_err(failure::system(format!(
+            "Unable to create temporary file in {}.",
+            parent.to_string_lossy().code_str(),
+        )))?;
+        serde_json::to_writer(&temp_file, &self.entries).map_err(failure::system(format!(
+            "Unable to write file {}.",
+            temp_file.path().to_string_lossy().code_str(),
+        )))?;
+
+        // Move the temporary file into place.
+        temp_file.persist(path).map_err(failure::system(format!(
+            "Unable to write file {}.",
+            path.to_string_lossy().code_str(),
+        )))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memo::Memo;
+    use std::{
+        fs::{metadata, write},
+        time::Duration,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn memo_hit() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foo.txt");
+        write(&path, "foo").unwrap();
+
+        let mut memo = Memo::load(None).unwrap();
+        memo.minimum_age = Duration::from_secs(0);
+        memo.insert(&path, &metadata(&path).unwrap(), "bar");
+
+        assert_eq!(
+            memo.get(&path, &metadata(&path).unwrap()),
+            Some("bar".to_owned()),
+        );
+    }
+
+    #[test]
+    fn memo_miss_changed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foo.txt");
+        write(&path, "foo").unwrap();
+
+        let mut memo = Memo::load(None).unwrap();
+        memo.minimum_age = Duration::from_secs(0);
+        memo.insert(&path, &metadata(&path).unwrap(), "bar");
+        write(&path, "foobar").unwrap();
+
+        assert_eq!(memo.get(&path, &metadata(&path).unwrap()), None);
+    }
+
+    #[test]
+    fn memo_miss_recent() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foo.txt");
+        write(&path, "foo").unwrap();
+
+        let mut memo = Memo::load(None).unwrap();
+        memo.insert(&path, &metadata(&path).unwrap(), "bar");
+
+        assert_eq!(memo.get(&path, &metadata(&path).unwrap()), None);
+    }
+
+    #[test]
+    fn memo_persist() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foo.txt");
+        let memo_path = dir.path().join("memo/hashes.json");
+        write(&path, "foo").unwrap();
+
+        let mut memo = Memo::load(Some(&memo_path)).unwrap();
+        memo.minimum_age = Duration::from_secs(0);
+        memo.insert(&path, &metadata(&path).unwrap(), "bar");
+        memo.save().unwrap();
+
+        let mut memo = Memo::load(Some(&memo_path)).unwrap();
+        memo.minimum_age = Duration::from_secs(0);
+        assert_eq!(
+            memo.get(&path, &metadata(&path).unwrap()),
+            Some("bar".to_owned()),
+        );
+    }
+
+    #[test]
+    fn memo_persist_concurrent() {
+        let dir = tempdir().unwrap();
+        let path1 = dir.path().join("foo.txt");
+        let path2 = dir.path().join("bar.txt");
+        let memo_path = dir.path().join("memo/hashes.json");
+        write(&path1, "foo").unwrap();
+        write(&path2, "bar").unwrap();
+
+        let mut memo1 = Memo::load(Some(&memo_path)).unwrap();
+        let mut memo2 = Memo::load(Some(&memo_path)).unwrap();
+        memo1.minimum_age = Duration::from_secs(0);
+        memo2.minimum_age = Duration::from_secs(0);
+        memo1.insert(&path1, &metadata(&path1).unwrap(), "foo");
+        memo2.insert(&path2, &metadata(&path2).unwrap(), "bar");
+        memo1.save().unwrap();
+        memo2.save().unwrap();
+
+        let mut memo = Memo::load(Some(&memo_path)).unwrap();
+        memo.minimum_age = Duration::from_secs(0);
+        assert_eq!(
+            memo.get(&path1, &metadata(&path1).unwrap()),
+            Some("foo".to_owned()),
+        );
+        assert_eq!(
+            memo.get(&path2, &metadata(&path2).unwrap()),
+            Some("bar".to_owned()),
+        );
+    }
+}
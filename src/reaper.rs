@@ -0,0 +1,290 @@
+use crate::{
+    docker::{ContainerBackend, Labeled},
+    failure::Failure,
+    format,
+    format::CodeStr,
+};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::canonicalize,
+    io,
+    path::Path,
+    process,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+// Toast labels every container and image it creates with these.
+pub const VERSION_LABEL: &str = "toast.version";
+pub const TOASTFILE_LABEL: &str = "toast.toastfile";
+pub const TASK_LABEL: &str = "toast.task";
+pub const CACHE_KEY_LABEL: &str = "toast.cache-key";
+pub const PID_LABEL: &str = "toast.pid";
+pub const HOST_LABEL: &str = "toast.host";
+
+// Images also get this label, which is `true` for temporary images and `false` for cached ones.
+pub const TEMPORARY_LABEL: &str = "toast.temporary";
+
+// Construct the labels for the containers and images created for a task by this process.
+pub fn labels(toastfile_path: &Path, task: &str, cache_key: &str) -> BTreeMap<String, String> {
+    let toastfile_path = canonicalize(toastfile_path).unwrap_or_else(|_| toastfile_path.to_owned());
+
+    let mut labels = BTreeMap::new();
+    labels.insert(VERSION_LABEL.to_owned(), crate::VERSION.to_owned());
+    labels.insert(
+        TOASTFILE_LABEL.to_owned(),
+        toastfile_path.to_string_lossy().into_owned(),
+    );
+    labels.insert(TASK_LABEL.to_owned(), task.to_owned());
+    labels.insert(CACHE_KEY_LABEL.to_owned(), cache_key.to_owned());
+    labels.insert(PID_LABEL.to_owned(), process::id().to_string());
+    labels.insert(HOST_LABEL.to_owned(), hostname());
+    labels
+}
+
+// Determine the name of this machine. Process IDs are only meaningful on the machine they came
+// from, and several machines may share a container engine.
+fn hostname() -> String {
+    let mut buffer = [0_u8; 256];
+
+    // The `unsafe` is needed for calling a C function. The buffer is large enough for any hostname,
+    // and the last byte stays zero in case the name is truncated.
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len() - 1) };
+    if result != 0 {
+        return String::new();
+    }
+
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(0);
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+// Determine whether a process is still running.
+fn process_exists(pid: u32) -> bool {
+    // Process IDs which don't fit in a `pid_t` don't exist, and zero would refer to our own process
+    // group.
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+
+    // The `unsafe` is needed for calling a C function. Sending signal 0 only checks whether the
+    // process exists. If it belongs to another user, we aren't allowed to signal it, but it exists.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Determine whether the process which created a container or image on this machine is gone.
+// Anything from another machine, or without a valid process ID, is assumed to be owned by someone
+// else.
+fn orphaned(labeled: &Labeled, host: &str) -> bool {
+    labeled.labels.get(HOST_LABEL).map(String::as_str) == Some(host)
+        && labeled
+            .labels
+            .get(PID_LABEL)
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .map_or(false, |pid| pid != process::id() && !process_exists(pid))
+}
+
+// Delete the containers and temporary images left behind by Toast processes on this machine which
+// were killed before they could clean up after themselves. Cached images are never deleted. In a
+// dry run, the orphans are only listed.
+pub fn reap(
+    backend: &dyn ContainerBackend,
+    dry_run: bool,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(), Failure> {
+    // Find the orphans. The containers are deleted first, since they may use the images.
+    let host = hostname();
+    let host_filter = format!("{}={}", HOST_LABEL, host);
+    let containers = backend
+        .list_labeled_containers(&[PID_LABEL.to_owned(), host_filter.clone()], interrupted)?
+        .into_iter()
+        .filter(|container| orphaned(container, &host))
+        .collect::<Vec<_>>();
+    let images = backend
+        .list_labeled_images(
+            &[
+                PID_LABEL.to_owned(),
+                host_filter,
+                format!("{}=true", TEMPORARY_LABEL),
+            ],
+            interrupted,
+        )?
+        .into_iter()
+        .filter(|image| orphaned(image, &host))
+        .flat_map(|image| image.names)
+        .collect::<Vec<_>>();
+
+    // In a dry run, just list the orphans.
+    if dry_run {
+        for container in &containers {
+            println!(
+                "* Container {} (task {} of {})",
+                container.id.code_str(),
+                container
+                    .labels
+                    .get(TASK_LABEL)
+                    .map_or("", String::as_str)
+                    .code_str(),
+                container
+                    .labels
+                    .get(TOASTFILE_LABEL)
+                    .map_or("", String::as_str)
+                    .code_str(),
+            );
+        }
+        for image in &images {
+            println!("* Image {}", image.code_str());
+        }
+
+        info!(
+            "{} and {} would be deleted.",
+            format::number(containers.len(), "orphaned container"),
+            format::number(images.len(), "orphaned image"),
+        );
+        return Ok(());
+    }
+
+    // Delete the orphans.
+    for container in &containers {
+        backend.delete_container(&container.id, interrupted)?;
+    }
+    for image in &images {
+        backend.delete_image(image, interrupted)?;
+    }
+
+    if !containers.is_empty() || !images.is_empty() {
+        info!(
+            "Deleted {} and {}.",
+            format::number(containers.len(), "orphaned container"),
+            format::number(images.len(), "orphaned image"),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        docker::ContainerBackend,
+        fake::Fake,
+        reaper::{
+            labels, reap, CACHE_KEY_LABEL, HOST_LABEL, PID_LABEL, TASK_LABEL, TEMPORARY_LABEL,
+            TOASTFILE_LABEL, VERSION_LABEL,
+        },
+    };
+    use std::{
+        collections::{BTreeMap, HashMap},
+        path::Path,
+        process::{self, Command},
+        sync::{atomic::AtomicBool, Arc},
+    };
+
+    // Determine the ID of a process which has exited.
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    // Create a container with some labels in the fake backend and return its ID.
+    fn create_container(fake: &Fake, labels: &BTreeMap<String, String>) -> String {
+        fake.create_container(
+            "debian",
+            Path::new("."),
+            &HashMap::new(),
+            &[],
+            false,
+            &[],
+            Path::new("/scratch"),
+            "root",
+            "true",
+            &[],
+            labels,
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap()
+    }
+
+    // Commit a new container with some labels to an image in the fake backend.
+    fn commit_image(fake: &Fake, image: &str, labels: &BTreeMap<String, String>, temporary: bool) {
+        let container = create_container(fake, labels);
+        let mut image_labels = labels.clone();
+        image_labels.insert(TEMPORARY_LABEL.to_owned(), temporary.to_string());
+        fake.commit_container(
+            &container,
+            image,
+            &image_labels,
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+        fake.delete_container(&container, &Arc::new(AtomicBool::new(false)))
+            .unwrap();
+    }
+
+    #[test]
+    fn labels_task() {
+        let labels = labels(Path::new("/nonexistent/toast.yml"), "build", "toast-abc");
+
+        assert_eq!(labels[VERSION_LABEL], crate::VERSION);
+        assert_eq!(labels[TOASTFILE_LABEL], "/nonexistent/toast.yml");
+        assert_eq!(labels[TASK_LABEL], "build");
+        assert_eq!(labels[CACHE_KEY_LABEL], "toast-abc");
+        assert_eq!(labels[PID_LABEL], process::id().to_string());
+    }
+
+    #[test]
+    fn reap_orphans() {
+        let fake = Fake::new();
+        fake.add_local_image("debian");
+        let live_labels = labels(Path::new("toast.yml"), "build", "toast-abc");
+        let mut dead_labels = live_labels.clone();
+        dead_labels.insert(PID_LABEL.to_owned(), dead_pid().to_string());
+        let live_container = create_container(&fake, &live_labels);
+        let dead_container = create_container(&fake, &dead_labels);
+        commit_image(&fake, "toast:toast-live", &live_labels, true);
+        commit_image(&fake, "toast:toast-dead", &dead_labels, true);
+        commit_image(&fake, "toast:toast-abc", &dead_labels, false);
+
+        reap(&fake, false, &Arc::new(AtomicBool::new(false))).unwrap();
+
+        // Only the orphans are deleted. Cached images are kept.
+        assert_eq!(fake.containers(), vec![live_container]);
+        assert!(!fake.containers().contains(&dead_container));
+        assert_eq!(
+            fake.local_images(),
+            vec!["debian", "toast:toast-abc", "toast:toast-live"],
+        );
+    }
+
+    #[test]
+    fn reap_dry_run() {
+        let fake = Fake::new();
+        fake.add_local_image("debian");
+        let mut labels = labels(Path::new("toast.yml"), "build", "toast-abc");
+        labels.insert(PID_LABEL.to_owned(), dead_pid().to_string());
+        let container = create_container(&fake, &labels);
+        commit_image(&fake, "toast:toast-dead", &labels, true);
+
+        reap(&fake, true, &Arc::new(AtomicBool::new(false))).unwrap();
+
+        assert_eq!(fake.containers(), vec![container]);
+        assert_eq!(fake.local_images(), vec!["debian", "toast:toast-dead"]);
+    }
+
+    #[test]
+    fn reap_other_host() {
+        let fake = Fake::new();
+        fake.add_local_image("debian");
+        let mut labels = labels(Path::new("toast.yml"), "build", "toast-abc");
+        labels.insert(PID_LABEL.to_owned(), dead_pid().to_string());
+        labels.insert(HOST_LABEL.to_owned(), "some-other-host".to_owned());
+        let container = create_container(&fake, &labels);
+
+        reap(&fake, false, &Arc::new(AtomicBool::new(false))).unwrap();
+
+        assert_eq!(fake.containers(), vec![container]);
+    }
+}
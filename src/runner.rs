@@ -1,8 +1,23 @@
-use crate::{cache, docker, failure, failure::Failure, tar, toastfile::Task};
+use crate::{
+    api, docker,
+    explain::Explanation,
+    failure,
+    failure::Failure,
+    format::CodeStr,
+    glob, lock,
+    lock::Lockfile,
+    memo::Memo,
+    reaper,
+    spinner::spin,
+    tar,
+    toastfile::{InputPath, SpecialFiles, Task},
+};
 use std::{
     collections::{HashMap, HashSet},
+    env,
     io::{Seek, SeekFrom},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    slice,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
@@ -10,11 +25,20 @@ use std::{
 };
 use tempfile::tempfile;
 
+// The output files which a task copied to the host, and the cache key of the task. The cache key is
+// recorded even if the task fails, since `output_paths_on_failure` are copied in that case.
+#[derive(Default)]
+pub struct Exports {
+    pub cache_key: String,
+    pub paths: Vec<PathBuf>,
+}
+
 // A context is an image that may need to be cleaned up.
 #[derive(Clone)]
 pub struct Context {
     pub image: String,
     pub persist: bool,
+    pub backend: Arc<dyn docker::ContainerBackend>,
     pub interrupted: Arc<AtomicBool>,
 }
 
@@ -22,67 +46,402 @@ impl Drop for Context {
     fn drop(&mut self) {
         // Delete the image if needed.
         if !self.persist {
-            if let Err(e) = docker::delete_image(&self.image, &self.interrupted) {
+            if let Err(e) = self.backend.delete_image(&self.image, &self.interrupted) {
                 error!("{}", e);
             }
         }
     }
 }
 
-// Run a task and return the new cache key.
+// Determine where the input paths of a task will end up in the container. Glob patterns are
+// expanded relative to `toastfile_dir`.
+fn input_destinations(
+    task: &Task,
+    toastfile_dir: &Path,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Vec<PathBuf>, Failure> {
+    let mut destinations = vec![];
+
+    for input_path in &task.input_paths {
+        match input_path {
+            InputPath::Path(path) => {
+                for expanded_path in
+                    glob::expand_all(toastfile_dir, slice::from_ref(path), interrupted)?
+                {
+                    destinations.push(task.location.join(expanded_path));
+                }
+            }
+            InputPath::Mapping { destination, .. } => {
+                destinations.push(task.location.join(destination));
+            }
+        }
+    }
+
+    Ok(destinations)
+}
+
+// Determine the user ID and group ID of the user who invoked Toast. If Toast is running as root via
+// `sudo`, that's the user who ran `sudo`.
+fn invoking_user() -> (u32, u32) {
+    // The `unsafe` is needed for calling C functions, but these can't fail.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+
+    if uid == 0 {
+        if let (Some(sudo_uid), Some(sudo_gid)) = (
+            env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()),
+            env::var("SUDO_GID").ok().and_then(|gid| gid.parse().ok()),
+        ) {
+            return (sudo_uid, sudo_gid);
+        }
+    }
+
+    (uid, gid)
+}
+
+// Determine the content digest of the base image. The image is pulled first if it isn't available
+// locally or if the user wants the latest version of it.
+pub fn base_image_digest(
+    settings: &super::Settings,
+    image: &str,
+    pull: bool,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<String, Failure> {
+    // Pull the image if necessary. If there's a local copy, a failed pull (e.g., due to a network
+    // outage) isn't fatal.
+    let exists = settings.backend.image_exists(image, interrupted)?;
+    if !exists || pull {
+        if let Err(e) = settings.backend.pull_image(image, interrupted) {
+            if !exists || interrupted.load(Ordering::SeqCst) {
+                return Err(e);
+            }
+
+            warn!(
+                "Unable to pull image {}. Using the local copy instead. Details: {}",
+                image.code_str(),
+                e,
+            );
+        }
+    }
+
+    // Inspect the image.
+    let digest = settings.backend.image_digest(image, interrupted)?;
+    debug!(
+        "The digest of image {} is {}.",
+        image.code_str(),
+        digest.code_str(),
+    );
+
+    Ok(digest)
+}
+
+// Determine the image to start from and its content digest. The base image is pinned to the digest
+// recorded in the lockfile, which is created if it doesn't exist. A lockfile for a different image
+// is never rewritten here, since only `update_lockfile` is allowed to change the pinned version.
+pub fn base_image(
+    settings: &super::Settings,
+    image: &str,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(String, String), Failure> {
+    let lockfile_path = lock::path(&settings.toastfile_path);
+    let lockfile = lock::load(&lockfile_path)?;
+
+    // If there is a lockfile, use the image it pins.
+    if let Some(lockfile) = lockfile {
+        // A lockfile for a different image is out of date. With `--locked`, that's an error.
+        // Otherwise, the pinned image is used until the lockfile is updated.
+        if lockfile.image != image {
+            if settings.locked {
+                return Err(Failure::User(
+                    format!(
+                        "The lockfile {} is out of date. It pins image {}, but the toastfile \
+                         refers to {}. Run {} to update it.",
+                        lockfile_path.to_string_lossy().code_str(),
+                        lockfile.image.code_str(),
+                        image.code_str(),
+                        "toast --update-lock".code_str(),
+                    ),
+                    None,
+                ));
+            }
+
+            warn!(
+                "The lockfile {} pins image {}, but the toastfile refers to {}. Using the pinned \
+                 image. Run {} to update the lockfile.",
+                lockfile_path.to_string_lossy().code_str(),
+                lockfile.image.code_str(),
+                image.code_str(),
+                "toast --update-lock".code_str(),
+            );
+        }
+
+        let reference = pinned_image(settings, &lockfile, interrupted)?;
+        debug!(
+            "Using image {} as pinned by {}.",
+            reference.code_str(),
+            lockfile_path.to_string_lossy().code_str(),
+        );
+        return Ok((reference, lockfile.digest));
+    }
+
+    // With `--locked`, a missing lockfile is an error.
+    if settings.locked {
+        return Err(Failure::User(
+            format!(
+                "The lockfile {} doesn't exist. Run {} to create it.",
+                lockfile_path.to_string_lossy().code_str(),
+                "toast --update-lock".code_str(),
+            ),
+            None,
+        ));
+    }
+
+    // Pin the current version of the image. With `--check` or `--explain`, nothing is written to
+    // the toastfile directory, so the lockfile isn't created.
+    let digest = base_image_digest(settings, image, settings.pull_base_image, interrupted)?;
+    if settings.check || settings.explain {
+        debug!(
+            "Not creating the lockfile {}.",
+            lockfile_path.to_string_lossy().code_str(),
+        );
+    } else {
+        write_lockfile(&lockfile_path, image, &digest)?;
+        info!(
+            "Created lockfile {} to pin image {} to {}. Commit it along with the toastfile.",
+            lockfile_path.to_string_lossy().code_str(),
+            image.code_str(),
+            digest.code_str(),
+        );
+    }
+
+    Ok((image.to_owned(), digest))
+}
+
+// Determine the content digest of the base image without pulling it or writing the lockfile. The
+// digest pinned by the lockfile is used if there is one, just like in `base_image`. Otherwise, the
+// local copy of the image is inspected.
+pub fn local_base_image_digest(
+    settings: &super::Settings,
+    image: &str,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<String, Failure> {
+    let lockfile_path = lock::path(&settings.toastfile_path);
+    if let Some(lockfile) = lock::load(&lockfile_path)? {
+        return Ok(lockfile.digest);
+    }
+
+    if settings.backend.image_exists(image, interrupted)? {
+        settings.backend.image_digest(image, interrupted)
+    } else {
+        Err(Failure::User(
+            format!(
+                "Image {} isn't available locally, and there is no lockfile {} which pins it.",
+                image.code_str(),
+                lockfile_path.to_string_lossy().code_str(),
+            ),
+            None,
+        ))
+    }
+}
+
+// Pull the latest version of the base image and pin it in the lockfile. Returns the new digest.
+pub fn update_lockfile(
+    settings: &super::Settings,
+    image: &str,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<String, Failure> {
+    let digest = base_image_digest(settings, image, true, interrupted)?;
+    let lockfile_path = lock::path(&settings.toastfile_path);
+    write_lockfile(&lockfile_path, image, &digest)?;
+    info!(
+        "Pinned image {} to {} in {}.",
+        image.code_str(),
+        digest.code_str(),
+        lockfile_path.to_string_lossy().code_str(),
+    );
+
+    Ok(digest)
+}
+
+// Write a lockfile pinning an image to a digest.
+fn write_lockfile(path: &Path, image: &str, digest: &str) -> Result<(), Failure> {
+    lock::save(
+        path,
+        &Lockfile {
+            image: image.to_owned(),
+            digest: digest.to_owned(),
+        },
+    )
+}
+
+// Find the image pinned by a lockfile, pulling it if necessary, and return a reference to it.
+fn pinned_image(
+    settings: &super::Settings,
+    lockfile: &Lockfile,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<String, Failure> {
+    // Images from a registry can be found by their repository digest. Images which were only ever
+    // built locally don't have one, so they're pinned by their ID instead.
+    let reference = format!(
+        "{}@{}",
+        api::split_reference(&lockfile.image).0,
+        lockfile.digest,
+    );
+    for candidate in &[&reference, &lockfile.digest] {
+        if settings.backend.image_exists(candidate, interrupted)? {
+            return Ok((*candidate).clone());
+        }
+    }
+
+    settings.backend.pull_image(&reference, interrupted)?;
+
+    Ok(reference)
+}
+
+// Determine which filesystem metadata to preserve when copying the input files of a task.
+fn tar_options(task: &Task) -> tar::Options {
+    tar::Options {
+        preserve_permissions: task.preserve_permissions,
+        preserve_mtimes: task.preserve_mtimes,
+        skip_special_files: task.special_files == SpecialFiles::Skip,
+        follow_symlinks: task.follow_symlinks,
+    }
+}
+
+// Compute the cache key of a task without running it, and explain what went into it.
+pub fn explain(
+    settings: &super::Settings,
+    environment: &HashMap<String, String>,
+    interrupted: &Arc<AtomicBool>,
+    memo: &mut Memo,
+    task: &Task,
+    previous_cache_key: &str,
+) -> Result<Explanation, Failure> {
+    // All relative paths are relative to where the toastfile lives.
+    let mut toastfile_dir = PathBuf::from(&settings.toastfile_path);
+    toastfile_dir.pop();
+
+    // Compute the hashes of the input files.
+    let input_files = {
+        let _guard = spin("Reading files\u{2026}");
+        tar::hash(
+            &task.input_paths,
+            &task.excluded_input_paths,
+            &toastfile_dir,
+            &task.location,
+            memo,
+            tar_options(task),
+            interrupted,
+        )?
+    };
+
+    Ok(Explanation::new(
+        previous_cache_key,
+        task,
+        input_files,
+        environment,
+    ))
+}
+
+// Run a task and return an explanation of the new cache key. Output files are copied into
+// `output_dir`, and they are recorded in `exports`, even if the task fails.
 #[allow(clippy::too_many_arguments)]
 pub fn run(
     settings: &super::Settings,
     environment: &HashMap<String, String>,
     interrupted: &Arc<AtomicBool>,
     active_containers: &Arc<Mutex<HashSet<String>>>,
+    memo: &mut Memo,
+    exports: &mut Exports,
+    output_dir: &Path,
+    task_name: &str,
     task: &Task,
     previous_cache_key: &str,
     caching_enabled: bool,
     context: Context,
-) -> (Result<String, Failure>, Context) {
+) -> (Result<Explanation, Failure>, Context) {
     // All relative paths are relative to where the toastfile lives.
     let mut toastfile_dir = PathBuf::from(&settings.toastfile_path);
     toastfile_dir.pop();
 
-    // Create a temporary archive for the input file contents.
-    let tar_file = match tempfile() {
-        Ok(tar_file) => tar_file,
-        Err(e) => {
-            return (
-                Err(failure::system("Unable to create temporary file.")(e)),
-                context,
-            )
-        }
-    };
+    // Determine which filesystem metadata to preserve in the archive.
+    let tar_options = tar_options(task);
 
-    // Write to the archive.
-    let (mut tar_file, input_files_hash) = match tar::create(
-        "Reading files\u{2026}",
-        tar_file,
-        &task.input_paths,
-        &toastfile_dir,
-        &task.location,
-        &interrupted,
-    ) {
-        Ok((tar_file, input_files_hash)) => (tar_file, input_files_hash),
-        Err(e) => return (Err(e), context),
+    // Determine who should own the output files on the host, if anyone in particular.
+    let output_owner = if task.chown_output_paths || settings.chown_output_paths {
+        Some(invoking_user())
+    } else {
+        None
     };
 
-    // Seek back to the beginning of the archive to prepare for copying it into the container.
-    if let Err(e) = tar_file.seek(SeekFrom::Start(0)) {
-        return (
-            Err(failure::system("Unable to seek temporary file.")(e)),
-            context,
-        );
+    // Compute the hash of the input files. Unless the archive is going to be streamed into the
+    // container, write it to a temporary file in the process.
+    let (tar_file, input_files) = if settings.stream_input_paths {
+        // Only compute the hash for now. The archive will be constructed later if necessary.
+        let _guard = spin("Reading files\u{2026}");
+        match tar::hash(
+            &task.input_paths,
+            &task.excluded_input_paths,
+            &toastfile_dir,
+            &task.location,
+            memo,
+            tar_options,
+            &interrupted,
+        ) {
+            Ok(input_files) => (None, input_files),
+            Err(e) => return (Err(e), context),
+        }
+    } else {
+        // Create a temporary archive for the input file contents.
+        let tar_file = match tempfile() {
+            Ok(tar_file) => tar_file,
+            Err(e) => {
+                return (
+                    Err(failure::system("Unable to create temporary file.")(e)),
+                    context,
+                )
+            }
+        };
+
+        // Write to the archive.
+        let _guard = spin("Reading files\u{2026}");
+        let (mut tar_file, input_files) = match tar::create(
+            tar_file,
+            &task.input_paths,
+            &task.excluded_input_paths,
+            &toastfile_dir,
+            &task.location,
+            memo,
+            tar_options,
+            &interrupted,
+        ) {
+            Ok((tar_file, input_files)) => (tar_file, input_files),
+            Err(e) => return (Err(e), context),
+        };
+
+        // Seek back to the beginning of the archive to prepare for copying it into the container.
+        if let Err(e) = tar_file.seek(SeekFrom::Start(0)) {
+            return (
+                Err(failure::system("Unable to seek temporary file.")(e)),
+                context,
+            );
+        };
+
+        (Some(tar_file), input_files)
     };
 
     // Compute the cache key.
-    let cache_key = cache::key(previous_cache_key, &task, &input_files_hash, &environment);
+    let input_files_hash = input_files.total.clone();
+    let explanation = Explanation::new(previous_cache_key, task, input_files, environment);
+    let cache_key = explanation.key.clone();
+    exports.cache_key = cache_key.clone();
 
     // This is the image we'll look for in the caches.
     let image = format!("{}:{}", settings.docker_repo, cache_key);
 
+    // Label the containers and images so they can be traced back to this task, and so they can be
+    // cleaned up if this process dies before it gets the chance.
+    let labels = reaper::labels(&settings.toastfile_path, task_name, &cache_key);
+
     // Construct the environment.
     let mut task_environment = HashMap::<String, String>::new();
     for variable in task.environment.keys() {
@@ -95,14 +454,14 @@ pub fn run(
     if caching_enabled {
         // Check the local cache.
         cached = settings.read_local_cache
-            && match docker::image_exists(&image, interrupted) {
+            && match settings.backend.image_exists(&image, interrupted) {
                 Ok(exists) => exists,
                 Err(e) => return (Err(e), context),
             };
 
         // Check the remote cache.
         if !cached && settings.read_remote_cache {
-            if let Err(e) = docker::pull_image(&image, interrupted) {
+            if let Err(e) = settings.backend.pull_image(&image, interrupted) {
                 // If the pull failed, it could be because the user killed the child process (e.g.,
                 // by hitting CTRL+C).
                 if interrupted.load(Ordering::SeqCst) {
@@ -120,17 +479,18 @@ pub fn run(
         if task.output_paths.is_empty() {
             // There are no output files, so we're done.
             (
-                Ok(cache_key),
+                Ok(explanation),
                 Context {
                     image,
                     persist: true,
+                    backend: settings.backend.clone(),
                     interrupted: interrupted.clone(),
                 },
             )
         } else {
             // If we made it this far, we need to create a container from which we can extract the
             // output files.
-            let container = match docker::create_container(
+            let container = match settings.backend.create_container(
                 &image,
                 &toastfile_dir,
                 &task_environment,
@@ -140,6 +500,8 @@ pub fn run(
                 &task.location,
                 &task.user,
                 &task.command,
+                &[],
+                &labels,
                 interrupted,
             ) {
                 Ok(container) => container,
@@ -148,28 +510,34 @@ pub fn run(
 
             // Delete the container when we're done.
             defer! {{
-              if let Err(e) = docker::delete_container(&container, interrupted) {
+              if let Err(e) =
+                settings.backend.delete_container(&container, interrupted)
+              {
                 error!("{}", e);
               }
             }}
 
             // Extract the output files from the container.
-            if let Err(e) = docker::copy_from_container(
+            match docker::copy_from_container(
+                &*settings.backend,
                 &container,
                 &task.output_paths,
                 &task.location,
-                &toastfile_dir,
+                output_dir,
+                output_owner,
                 interrupted,
             ) {
-                return (Err(e), context);
+                Ok(copied_paths) => exports.paths.extend(copied_paths),
+                Err(e) => return (Err(e), context),
             }
 
             // The cached image becomes the new context.
             (
-                Ok(cache_key),
+                Ok(explanation),
                 Context {
                     image,
                     persist: true,
+                    backend: settings.backend.clone(),
                     interrupted: interrupted.clone(),
                 },
             )
@@ -177,17 +545,27 @@ pub fn run(
     } else {
         // Pull the image if necessary. Note that this is not considered reading from the remote
         // cache.
-        if !match docker::image_exists(&context.image, interrupted) {
+        if !match settings.backend.image_exists(&context.image, interrupted) {
             Ok(exists) => exists,
             Err(e) => return (Err(e), context),
         } {
-            if let Err(e) = docker::pull_image(&context.image, interrupted) {
+            if let Err(e) = settings.backend.pull_image(&context.image, interrupted) {
                 return (Err(e), context);
             }
         }
 
+        // Determine which paths in the container should be owned by the user, if any.
+        let chown_paths = if task.chown_input_paths {
+            match input_destinations(task, &toastfile_dir, interrupted) {
+                Ok(destinations) => destinations,
+                Err(e) => return (Err(e), context),
+            }
+        } else {
+            vec![]
+        };
+
         // Create a container from the image.
-        let container = match docker::create_container(
+        let container = match settings.backend.create_container(
             &context.image,
             &toastfile_dir,
             &task_environment,
@@ -197,6 +575,8 @@ pub fn run(
             &task.location,
             &task.user,
             &task.command,
+            &chown_paths,
+            &labels,
             interrupted,
         ) {
             Ok(container) => container,
@@ -221,35 +601,97 @@ pub fn run(
           }
 
           // Delete the container.
-          if let Err(e) = docker::delete_container(&container, interrupted) {
+          if let Err(e) =
+            settings.backend.delete_container(&container, interrupted)
+          {
             error!("{}", e);
           }
         }}
 
         // Copy files into the container. If `task.input_paths` is empty, then this will just create
         // a directory for `task.location`.
-        if let Err(e) = docker::copy_into_container(&container, &mut tar_file, interrupted) {
+        if let Err(e) = if let Some(tar_file) = tar_file {
+            docker::copy_into_container(&*settings.backend, &container, tar_file, interrupted)
+        } else {
+            // Construct the archive as it's being copied into the container. The files might have
+            // changed since we computed the cache key, in which case we must not proceed. Every
+            // file is hashed again as it's streamed, even if its hash is memoized, so the check
+            // covers exactly what the container received.
+            settings.backend.stream_into_container(
+                &container,
+                &mut |stdin| {
+                    let (_, streamed_input_files) = tar::create(
+                        stdin,
+                        &task.input_paths,
+                        &task.excluded_input_paths,
+                        &toastfile_dir,
+                        &task.location,
+                        &mut Memo::load(None)?,
+                        tar_options,
+                        &interrupted,
+                    )?;
+
+                    if streamed_input_files.total == input_files_hash {
+                        Ok(())
+                    } else {
+                        Err(Failure::System(
+                            "The input files changed while they were being read.".to_owned(),
+                            None,
+                        ))
+                    }
+                },
+                interrupted,
+            )
+        } {
+            // The container is deleted without being started or committed, so whatever it received
+            // never ends up in the cache.
             return (Err(e), context);
         }
 
         // Start the container to run the command.
-        let result = docker::start_container(&container, interrupted).map_err(|e| match e {
-            Failure::Interrupted => e,
-            Failure::System(_, _) | Failure::User(_, _) => {
-                Failure::User("Command failed.".to_owned(), None)
-            }
-        });
+        let result = settings
+            .backend
+            .start_container(&container, interrupted)
+            .map_err(|e| match e {
+                Failure::Interrupted => e,
+                Failure::System(_, _) | Failure::User(_, _) => {
+                    Failure::User("Command failed.".to_owned(), None)
+                }
+            });
 
         // Copy files from the container, if applicable.
         if result.is_ok() && !task.output_paths.is_empty() {
-            if let Err(e) = docker::copy_from_container(
+            match docker::copy_from_container(
+                &*settings.backend,
                 &container,
                 &task.output_paths,
                 &task.location,
-                &toastfile_dir,
+                output_dir,
+                output_owner,
                 interrupted,
             ) {
-                return (Err(e), context);
+                Ok(copied_paths) => exports.paths.extend(copied_paths),
+                Err(e) => return (Err(e), context),
+            }
+        }
+
+        // If the command failed, copy whatever it left behind for diagnosing the failure. Each path
+        // is copied separately, since some of them may not exist. The task still fails afterward.
+        if let Err(Failure::User(_, _)) = result {
+            for output_path in &task.output_paths_on_failure {
+                match docker::copy_from_container(
+                    &*settings.backend,
+                    &container,
+                    slice::from_ref(output_path),
+                    &task.location,
+                    output_dir,
+                    output_owner,
+                    interrupted,
+                ) {
+                    Ok(copied_paths) => exports.paths.extend(copied_paths),
+                    Err(Failure::Interrupted) => return (Err(Failure::Interrupted), context),
+                    Err(e) => error!("{}", e),
+                }
             }
         }
 
@@ -264,8 +706,15 @@ pub fn run(
                 )
             };
 
-        // Commit the container.
-        if let Err(e) = docker::commit_container(&container, &new_image, interrupted) {
+        // Commit the container. Temporary images are labeled as such so they can be told apart from
+        // cached ones.
+        let mut image_labels = labels.clone();
+        image_labels.insert(reaper::TEMPORARY_LABEL.to_owned(), (!persist).to_string());
+        if let Err(e) =
+            settings
+                .backend
+                .commit_container(&container, &new_image, &image_labels, interrupted)
+        {
             return (Err(e), context);
         }
 
@@ -273,17 +722,544 @@ pub fn run(
         let new_context = Context {
             image: new_image,
             persist,
+            backend: settings.backend.clone(),
             interrupted: interrupted.clone(),
         };
 
         // Write to remote cache, if applicable.
         if result.is_ok() && caching_enabled && settings.write_remote_cache {
-            if let Err(e) = docker::push_image(&new_context.image, interrupted) {
+            if let Err(e) = settings.backend.push_image(&new_context.image, interrupted) {
                 return (Err(e), new_context);
             }
         }
 
         // Return the new context.
-        (result.map(|_| cache_key), new_context)
+        (result.map(|()| explanation), new_context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        docker::ContainerBackend,
+        failure::Failure,
+        fake,
+        fake::{Behavior, Call, Fake},
+        lock,
+        lock::Lockfile,
+        memo::Memo,
+        reaper,
+        runner::{base_image, base_image_digest, explain, run, update_lockfile, Context, Exports},
+        toastfile, Settings,
+    };
+    use std::{
+        collections::{HashMap, HashSet},
+        fs::{read_to_string, write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use tempfile::tempdir;
+
+    const TOASTFILE: &str = r#"
+image: debian
+tasks:
+  build:
+    command: make
+    output_paths:
+      - out.txt
+    output_paths_on_failure:
+      - log.txt
+"#;
+
+    // Run the `build` task from `TOASTFILE` on the base image and return the new cache key along
+    // with the new context.
+    fn run_build(
+        settings: &Settings,
+        output_dir: &Path,
+        interrupted: &Arc<AtomicBool>,
+    ) -> (Result<String, Failure>, Context) {
+        let toastfile = toastfile::parse(TOASTFILE).unwrap();
+        let (result, context) = run(
+            settings,
+            &HashMap::new(),
+            interrupted,
+            &Arc::new(Mutex::new(HashSet::new())),
+            &mut Memo::load(None).unwrap(),
+            &mut Exports::default(),
+            output_dir,
+            "build",
+            &toastfile.tasks["build"],
+            "abc",
+            true,
+            Context {
+                image: "debian".to_owned(),
+                persist: true,
+                backend: settings.backend.clone(),
+                interrupted: interrupted.clone(),
+            },
+        );
+
+        (result.map(|explanation| explanation.key), context)
+    }
+
+    #[test]
+    fn base_image_digest_local() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.add_remote_image("debian");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let digest = base_image_digest(&settings, "debian", false, &interrupted).unwrap();
+
+        assert_eq!(digest, fake.image_digest("debian", &interrupted).unwrap());
+        assert!(!fake.calls().contains(&Call::PullImage("debian".to_owned())));
+    }
+
+    #[test]
+    fn base_image_digest_pull() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.add_remote_image("debian");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let old_digest = fake.image_digest("debian", &interrupted).unwrap();
+        let new_digest = base_image_digest(&settings, "debian", true, &interrupted).unwrap();
+
+        assert_ne!(new_digest, old_digest);
+        assert!(fake.calls().contains(&Call::PullImage("debian".to_owned())));
+    }
+
+    #[test]
+    fn base_image_digest_pull_failure() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        // The local copy is used instead.
+        assert!(base_image_digest(&settings, "debian", true, &interrupted).is_ok());
+    }
+
+    #[test]
+    fn base_image_digest_missing() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        assert!(base_image_digest(&settings, "debian", false, &interrupted).is_err());
+    }
+
+    #[test]
+    fn base_image_creates_lockfile() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_remote_image("debian");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (image, digest) = base_image(&settings, "debian", &interrupted).unwrap();
+
+        assert_eq!(image, "debian");
+        assert_eq!(
+            lock::load(&dir.path().join("toast.lock")).unwrap(),
+            Some(Lockfile {
+                image: "debian".to_owned(),
+                digest,
+            }),
+        );
+    }
+
+    #[test]
+    fn base_image_pinned() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_remote_image("debian");
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (_, old_digest) = base_image(&settings, "debian", &interrupted).unwrap();
+
+        // A new version of the image is published, and the local copy is deleted.
+        fake.add_remote_image("debian");
+        fake.delete_image("debian", &interrupted).unwrap();
+        settings.pull_base_image = true;
+
+        // The pinned version is pulled by its digest.
+        let (image, digest) = base_image(&settings, "debian", &interrupted).unwrap();
+        let reference = format!("debian@{}", old_digest);
+
+        assert_eq!(image, reference);
+        assert_eq!(digest, old_digest);
+        assert!(fake.calls().contains(&Call::PullImage(reference)));
+        assert_eq!(
+            fake.calls()
+                .into_iter()
+                .filter(|call| call == &Call::PullImage("debian".to_owned()))
+                .count(),
+            1,
+        );
+    }
+
+    #[test]
+    fn base_image_stale_lockfile() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.add_local_image("alpine");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (_, digest) = base_image(&settings, "debian", &interrupted).unwrap();
+
+        // The pinned image is still used.
+        let (image, pinned_digest) = base_image(&settings, "alpine", &interrupted).unwrap();
+        assert_eq!(image, format!("debian@{}", digest));
+        assert_eq!(pinned_digest, digest);
+        assert_eq!(
+            lock::load(&dir.path().join("toast.lock")).unwrap(),
+            Some(Lockfile {
+                image: "debian".to_owned(),
+                digest,
+            }),
+        );
+    }
+
+    #[test]
+    fn base_image_check_mode() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        settings.check = true;
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (image, digest) = base_image(&settings, "debian", &interrupted).unwrap();
+
+        assert_eq!(image, "debian");
+        assert_eq!(digest, fake.image_digest("debian", &interrupted).unwrap());
+        assert!(!dir.path().join("toast.lock").exists());
+    }
+
+    #[test]
+    fn base_image_locked_missing() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        settings.locked = true;
+
+        match base_image(&settings, "debian", &Arc::new(AtomicBool::new(false))) {
+            Err(Failure::User(message, _)) => assert!(message.contains("doesn't exist")),
+            _ => panic!("The lockfile should have been required."),
+        }
+        assert!(!dir.path().join("toast.lock").exists());
+    }
+
+    #[test]
+    fn base_image_locked_stale() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.add_local_image("alpine");
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        base_image(&settings, "debian", &interrupted).unwrap();
+        settings.locked = true;
+
+        assert!(base_image(&settings, "debian", &interrupted).is_ok());
+        match base_image(&settings, "alpine", &interrupted) {
+            Err(Failure::User(message, _)) => assert!(message.contains("is out of date")),
+            _ => panic!("The lockfile should have been up to date."),
+        }
+    }
+
+    #[test]
+    fn update_lockfile_pulls() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.add_remote_image("debian");
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (_, old_digest) = base_image(&settings, "debian", &interrupted).unwrap();
+        let new_digest = update_lockfile(&settings, "debian", &interrupted).unwrap();
+
+        assert_ne!(new_digest, old_digest);
+        assert_eq!(
+            base_image(&settings, "debian", &interrupted).unwrap().1,
+            new_digest,
+        );
+    }
+
+    #[test]
+    fn explain_matches_run() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.on_command(
+            "make",
+            Behavior::Succeed(vec![(PathBuf::from("/scratch/out.txt"), "out".to_owned())]),
+        );
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let toastfile = toastfile::parse(TOASTFILE).unwrap();
+
+        let explanation = explain(
+            &settings,
+            &HashMap::new(),
+            &interrupted,
+            &mut Memo::load(None).unwrap(),
+            &toastfile.tasks["build"],
+            "abc",
+        )
+        .unwrap();
+        let (result, _) = run_build(&settings, dir.path(), &interrupted);
+
+        assert_eq!(explanation.key, result.unwrap());
+        assert_eq!(explanation.previous_key, "abc");
+        assert_eq!(explanation.command, "make");
+    }
+
+    #[test]
+    fn run_cache_miss() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.on_command(
+            "make",
+            Behavior::Succeed(vec![(PathBuf::from("/scratch/out.txt"), "out".to_owned())]),
+        );
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (result, context) = run_build(&settings, dir.path(), &interrupted);
+        let cache_key = result.unwrap();
+        let image = format!("toast:{}", cache_key);
+
+        assert_eq!(context.image, image);
+        assert!(context.persist);
+        assert_eq!(read_to_string(dir.path().join("out.txt")).unwrap(), "out",);
+        assert_eq!(
+            fake.calls(),
+            vec![
+                Call::ImageExists(image.clone()),
+                Call::ImageExists("debian".to_owned()),
+                Call::CreateContainer("debian".to_owned(), "make".to_owned()),
+                Call::StreamIntoContainer("container-1".to_owned()),
+                Call::StartContainer("container-1".to_owned()),
+                Call::CopyPathFromContainer(
+                    "container-1".to_owned(),
+                    PathBuf::from("/scratch/out.txt"),
+                ),
+                Call::CommitContainer("container-1".to_owned(), image.clone()),
+                Call::DeleteContainer("container-1".to_owned()),
+            ],
+        );
+        assert_eq!(
+            fake.local_images(),
+            vec!["debian".to_owned(), image.clone()]
+        );
+
+        // The image is labeled with the task and its cache key.
+        let labels = fake.image_labels(&image).unwrap();
+        assert_eq!(labels[reaper::TASK_LABEL], "build");
+        assert_eq!(labels[reaper::CACHE_KEY_LABEL], cache_key);
+        assert_eq!(labels[reaper::TEMPORARY_LABEL], "false");
+    }
+
+    #[test]
+    fn run_cache_hit() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.on_command(
+            "make",
+            Behavior::Succeed(vec![(PathBuf::from("/scratch/out.txt"), "out".to_owned())]),
+        );
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (first_result, _) = run_build(&settings, dir.path(), &interrupted);
+        let output_dir = tempdir().unwrap();
+        let calls = fake.calls().len();
+        let (second_result, context) = run_build(&settings, output_dir.path(), &interrupted);
+
+        // The output files come from the cached image rather than from running the command again.
+        assert_eq!(second_result.unwrap(), first_result.unwrap());
+        assert!(context.persist);
+        assert_eq!(
+            read_to_string(output_dir.path().join("out.txt")).unwrap(),
+            "out",
+        );
+        assert!(!fake.calls()[calls..].iter().any(|call| match call {
+            Call::StartContainer(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn run_remote_cache_hit() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        // Populate the remote cache, then forget the image locally.
+        settings.write_remote_cache = true;
+        fake.on_command(
+            "make",
+            Behavior::Succeed(vec![(PathBuf::from("/scratch/out.txt"), "out".to_owned())]),
+        );
+        let (first_result, first_context) = run_build(&settings, dir.path(), &interrupted);
+        let image = first_context.image.clone();
+        fake.delete_image(&image, &interrupted).unwrap();
+        assert_eq!(fake.remote_images(), vec![image.clone()]);
+
+        settings.write_remote_cache = false;
+        settings.read_remote_cache = true;
+        let calls = fake.calls().len();
+        let (second_result, _) = run_build(&settings, dir.path(), &interrupted);
+
+        assert_eq!(second_result.unwrap(), first_result.unwrap());
+        assert!(fake.calls()[calls..].contains(&Call::PullImage(image)));
+        assert!(!fake.calls()[calls..].iter().any(|call| match call {
+            Call::StartContainer(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn run_failure() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.on_command(
+            "make",
+            Behavior::Fail(vec![(PathBuf::from("/scratch/log.txt"), "log".to_owned())]),
+        );
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (result, context) = run_build(&settings, dir.path(), &interrupted);
+
+        match result {
+            Err(Failure::User(message, _)) => assert_eq!(message, "Command failed."),
+            _ => panic!("The task should have failed."),
+        }
+        assert_eq!(read_to_string(dir.path().join("log.txt")).unwrap(), "log",);
+        assert!(!dir.path().join("out.txt").exists());
+
+        // The failed container is committed to a temporary image, which is deleted with the
+        // context.
+        assert!(!context.persist);
+        let image = context.image.clone();
+        assert!(fake.local_images().contains(&image));
+        assert_eq!(
+            fake.image_labels(&image).unwrap()[reaper::TEMPORARY_LABEL],
+            "true",
+        );
+        drop(context);
+        assert_eq!(fake.local_images(), vec!["debian".to_owned()]);
+        assert_eq!(fake.calls().last(), Some(&Call::DeleteImage(image)));
+    }
+
+    #[test]
+    fn run_streamed_input_files_changed() {
+        let dir = tempdir().unwrap();
+        let input_path = dir.path().join("in.txt");
+        write(&input_path, "in").unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.before_stream(move || write(&input_path, "changed").unwrap());
+        let mut settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        settings.stream_input_paths = true;
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let toastfile = toastfile::parse(
+            r#"
+image: debian
+tasks:
+  build:
+    input_paths:
+      - in.txt
+    command: make
+"#,
+        )
+        .unwrap();
+
+        let (result, _) = run(
+            &settings,
+            &HashMap::new(),
+            &interrupted,
+            &Arc::new(Mutex::new(HashSet::new())),
+            &mut Memo::load(None).unwrap(),
+            &mut Exports::default(),
+            dir.path(),
+            "build",
+            &toastfile.tasks["build"],
+            "abc",
+            true,
+            Context {
+                image: "debian".to_owned(),
+                persist: true,
+                backend: settings.backend.clone(),
+                interrupted: interrupted.clone(),
+            },
+        );
+
+        // The container is deleted without being started or committed.
+        match result {
+            Err(Failure::System(message, _)) => {
+                assert_eq!(
+                    message,
+                    "The input files changed while they were being read."
+                );
+            }
+            _ => panic!("The task should have failed."),
+        }
+        assert_eq!(
+            fake.calls()[2..],
+            [
+                Call::CreateContainer("debian".to_owned(), "make".to_owned()),
+                Call::StreamIntoContainer("container-1".to_owned()),
+                Call::DeleteContainer("container-1".to_owned()),
+            ],
+        );
+        assert!(fake.containers().is_empty());
+        assert_eq!(fake.local_images(), vec!["debian".to_owned()]);
+    }
+
+    #[test]
+    fn run_interrupted() {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(Fake::new());
+        fake.add_local_image("debian");
+        fake.on_command("make", Behavior::Interrupt);
+        let settings = fake::settings(&dir.path().join("toast.yml"), &fake);
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let (result, _) = run_build(&settings, dir.path(), &interrupted);
+
+        match result {
+            Err(Failure::Interrupted) => {}
+            _ => panic!("The task should have been interrupted."),
+        }
+        assert!(interrupted.load(Ordering::SeqCst));
+        assert!(!fake.calls().iter().any(|call| match call {
+            Call::CopyPathFromContainer(_, _) => true,
+            _ => false,
+        }));
     }
 }
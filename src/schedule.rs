@@ -78,7 +78,7 @@ pub fn compute<'a>(toastfile: &'a Toastfile, tasks: &[&'a str]) -> Vec<&'a str>
 #[cfg(test)]
 mod tests {
     use crate::schedule::compute;
-    use crate::toastfile::{Task, Toastfile, DEFAULT_LOCATION, DEFAULT_USER};
+    use crate::toastfile::{SpecialFiles, Task, Toastfile, DEFAULT_LOCATION, DEFAULT_USER};
     use std::{collections::HashMap, path::Path};
 
     fn task_with_dependencies(dependencies: Vec<String>) -> Task {
@@ -88,9 +88,18 @@ mod tests {
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
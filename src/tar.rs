@@ -1,10 +1,22 @@
-use crate::{cache, cache::CryptoHash, failure, failure::Failure, format::CodeStr, spinner::spin};
+use crate::{
+    cache,
+    cache::{CryptoHash, HashingReader},
+    failure,
+    failure::Failure,
+    format::CodeStr,
+    glob,
+    memo::Memo,
+    toastfile::InputPath,
+};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use std::{
-    collections::HashSet,
-    fs::{read_link, symlink_metadata, File, Metadata},
-    io::{empty, Read, Seek, SeekFrom, Write},
-    os::unix::fs::PermissionsExt,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    fs::{metadata, read_link, symlink_metadata, File, Metadata},
+    io::{empty, Read, Sink, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    slice,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -13,6 +25,10 @@ use std::{
 use tar::{Builder, EntryType, Header};
 use walkdir::WalkDir;
 
+// The name of the optional file, located next to the toastfile, which lists paths to exclude from
+// `input_paths`
+pub const IGNORE_FILE_NAME: &str = ".toastignore";
+
 // Tar archives must contain only relative paths. For our purposes, the paths will be relative to
 // the filesystem root, so we need to strip the leading `/` before adding paths to the archive.
 fn strip_root(absolute_path: &Path) -> &Path {
@@ -20,6 +36,61 @@ fn strip_root(absolute_path: &Path) -> &Path {
     absolute_path.strip_prefix("/").unwrap()
 }
 
+// These options determine which filesystem metadata is preserved in the archive. By default, files
+// are made readable and writable by everyone (and executable if any of the execute bits are set),
+// and all timestamps are set to the Unix epoch so the archive is reproducible.
+// If `skip_special_files` is enabled, sockets, FIFOs, and device files are skipped with a warning
+// rather than causing an error. If `follow_symlinks` is enabled, symbolic links are replaced by
+// whatever they point to.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    pub preserve_permissions: bool,
+    pub preserve_mtimes: bool,
+    pub skip_special_files: bool,
+    pub follow_symlinks: bool,
+}
+
+// The hash of the input files, along with the hashes of the individual entries it was computed
+// from, keyed by their paths in the archive
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Hashes {
+    pub total: String,
+    pub entries: BTreeMap<PathBuf, String>,
+}
+
+// Determine the permission bits and modification time to record in the archive for an entry. The
+// `default_mode` is used unless permissions are preserved.
+fn mode_and_mtime(options: Options, metadata: &Metadata, default_mode: u32) -> (u32, u64) {
+    (
+        if options.preserve_permissions {
+            metadata.permissions().mode() & 0o7777
+        } else {
+            default_mode
+        },
+        if options.preserve_mtimes {
+            // Timestamps before the Unix epoch can't be represented in the archive.
+            u64::try_from(metadata.mtime()).unwrap_or(0)
+        } else {
+            0
+        },
+    )
+}
+
+// Incorporate whatever metadata is preserved into the hash of an entry.
+fn hash_metadata(hash: String, options: Options, mode: u32, mtime: u64) -> String {
+    let mut hash = hash;
+
+    if options.preserve_permissions {
+        hash = cache::combine(&hash, &format!("{:o}", mode));
+    }
+
+    if options.preserve_mtimes {
+        hash = cache::combine(&hash, &mtime.to_string());
+    }
+
+    hash
+}
+
 // Add a file to a tar archive.
 fn add_file<R: Read, W: Write>(
     builder: &mut Builder<W>,
@@ -27,7 +98,8 @@ fn add_file<R: Read, W: Write>(
     path: &Path, // Must be relative
     data: R,
     size: u64,
-    executable: bool,
+    mode: u32,
+    mtime: u64,
 ) -> Result<(), Failure> {
     // Only visit this path once.
     if !visited_paths.insert(path.to_owned()) {
@@ -37,7 +109,8 @@ fn add_file<R: Read, W: Write>(
     // Construct a tar header for this entry.
     let mut header = Header::new_gnu();
     header.set_entry_type(EntryType::Regular);
-    header.set_mode(if executable { 0o777 } else { 0o666 });
+    header.set_mode(mode);
+    header.set_mtime(mtime);
     header.set_size(size);
 
     // Add the entry to the archive.
@@ -55,6 +128,7 @@ fn add_symlink<W: Write>(
     visited_paths: &mut HashSet<PathBuf>,
     path: &Path, // Must be relative
     target: &Path,
+    mtime: u64,
 ) -> Result<(), Failure> {
     // Only visit this path once.
     if !visited_paths.insert(path.to_owned()) {
@@ -68,6 +142,37 @@ fn add_symlink<W: Write>(
         "Error appending symbolic link to tar archive.",
     ))?;
     header.set_mode(0o777);
+    header.set_mtime(mtime);
+    header.set_size(0);
+
+    // Add the entry to the archive.
+    builder
+        .append_data(&mut header, path, empty())
+        .map_err(failure::system("Error appending data to tar archive."))?;
+
+    // Everything succeeded.
+    Ok(())
+}
+
+// Add a hard link to a tar archive.
+fn add_hard_link<W: Write>(
+    builder: &mut Builder<W>,
+    visited_paths: &mut HashSet<PathBuf>,
+    path: &Path,   // Must be relative
+    target: &Path, // Must be relative and already in the archive
+) -> Result<(), Failure> {
+    // Only visit this path once.
+    if !visited_paths.insert(path.to_owned()) {
+        return Ok(());
+    }
+
+    // Construct a tar header for this entry.
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Link);
+    header
+        .set_link_name(target)
+        .map_err(failure::system("Error appending hard link to tar archive."))?;
+    header.set_mode(0o777);
     header.set_size(0);
 
     // Add the entry to the archive.
@@ -84,6 +189,8 @@ fn add_directory<W: Write>(
     builder: &mut Builder<W>,
     visited_paths: &mut HashSet<PathBuf>,
     path: &Path, // Must be relative
+    mode: u32,
+    mtime: u64,
 ) -> Result<(), Failure> {
     // Only visit this path once.
     if !visited_paths.insert(path.to_owned()) {
@@ -100,7 +207,8 @@ fn add_directory<W: Write>(
     // Construct a tar header for this entry.
     let mut header = Header::new_gnu();
     header.set_entry_type(EntryType::Directory);
-    header.set_mode(0o777);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
     header.set_size(0);
 
     // Add the entry to the archive.
@@ -112,61 +220,137 @@ fn add_directory<W: Write>(
     Ok(())
 }
 
-// Add a file, symlink, or directory to a tar archive.
+// Add a file, symlink, or directory to a tar archive and record the hash of its contents and
+// metadata. If there is no archive, only the hash is computed.
+#[allow(clippy::too_many_arguments)]
 fn add_path<W: Write>(
-    builder: &mut Builder<W>,
-    content_hashes: &mut Vec<String>,
+    builder: &mut Option<Builder<W>>,
+    content_hashes: &mut BTreeMap<PathBuf, String>,
     visited_paths: &mut HashSet<PathBuf>,
+    hard_links: &mut HashMap<(u64, u64), PathBuf>,
+    memo: &mut Memo,
+    options: Options,
     source_path: &Path,
     destination_path: &Path, // Must be relative
     metadata: &Metadata,
 ) -> Result<(), Failure> {
     // Add the ancestor directories. They would be created automatically, but we add them explicitly
     // here to ensure they have the right permissions.
-    if let Some(parent) = destination_path.parent() {
-        for ancestor in parent.ancestors() {
-            add_directory(builder, visited_paths, ancestor)?;
+    if let Some(builder) = builder {
+        if let Some(parent) = destination_path.parent() {
+            for ancestor in parent.ancestors() {
+                add_directory(builder, visited_paths, ancestor, 0o777, 0)?;
+            }
         }
     }
 
     // Check the type of the entry.
     if metadata.file_type().is_file() {
+        // If the file has multiple names and we've already added it under a different one, add a
+        // hard link rather than another copy of the file.
+        if metadata.nlink() > 1 {
+            let target_path = hard_links
+                .entry((metadata.dev(), metadata.ino()))
+                .or_insert_with(|| destination_path.to_owned());
+
+            if target_path != destination_path {
+                // Compute the hash of the link path and the target path.
+                content_hashes.insert(
+                    destination_path.to_owned(),
+                    cache::combine(
+                        &cache::combine(destination_path, "hard link"),
+                        target_path.as_path(),
+                    ),
+                );
+
+                // Add the hard link to the archive.
+                if let Some(builder) = builder {
+                    add_hard_link(builder, visited_paths, destination_path, target_path)?;
+                }
+
+                // Everything succeeded.
+                return Ok(());
+            }
+        }
+
         // Determine if the file has the executable bit set.
-        let mode = metadata.permissions().mode();
-        let executable = mode & 0o1 > 0 || mode & 0o10 > 0 || mode & 0o100 > 0;
+        let executable = metadata.permissions().mode() & 0o111 > 0;
 
-        // It's a file. Open it so we can compute the hash of its contents and add it to the
-        // archive.
-        let mut file = File::open(source_path).map_err(failure::system(format!(
-            "Unable to open file {}.",
-            source_path.to_string_lossy().code_str(),
-        )))?;
+        // Determine the metadata to record in the archive.
+        let (mode, mtime) =
+            mode_and_mtime(options, metadata, if executable { 0o777 } else { 0o666 });
+
+        // It's a file. We'll need to open it to compute the hash of its contents or to add it to
+        // the archive.
+        let open_file = || {
+            File::open(source_path).map_err(failure::system(format!(
+                "Unable to open file {}.",
+                source_path.to_string_lossy().code_str(),
+            )))
+        };
+
+        // Check if we remember the hash of the file contents from a previous run.
+        let memoized_hash = memo.get(source_path, metadata);
+
+        // Compute the hash of the file contents, adding the file to the archive if there is one.
+        let file_hash = if let Some(builder) = builder {
+            let mut file = open_file()?;
+
+            if let Some(file_hash) = memoized_hash {
+                add_file(
+                    builder,
+                    visited_paths,
+                    destination_path,
+                    &mut file,
+                    metadata.len(),
+                    mode,
+                    mtime,
+                )?;
+
+                file_hash
+            } else {
+                // Compute the hash as the data streams into the archive so the file is only read
+                // once.
+                let mut reader = HashingReader::new(&mut file);
+                add_file(
+                    builder,
+                    visited_paths,
+                    destination_path,
+                    &mut reader,
+                    metadata.len(),
+                    mode,
+                    mtime,
+                )?;
+                let file_hash = reader.finish()?;
+                memo.insert(source_path, metadata, &file_hash);
+
+                file_hash
+            }
+        } else if let Some(file_hash) = memoized_hash {
+            file_hash
+        } else {
+            let file_hash = cache::hash_read(&mut open_file()?)?;
+            memo.insert(source_path, metadata, &file_hash);
+
+            file_hash
+        };
 
         // Compute the hash of the file contents and metadata.
-        content_hashes.push(cache::combine(
-            &cache::combine(
-                &destination_path.crypto_hash(),
-                &cache::hash_read(&mut file)?,
+        content_hashes.insert(
+            destination_path.to_owned(),
+            hash_metadata(
+                cache::combine(
+                    &cache::combine(&destination_path.crypto_hash(), &file_hash),
+                    if executable { "+x" } else { "-x" },
+                ),
+                options,
+                mode,
+                mtime,
             ),
-            if executable { "+x" } else { "-x" },
-        ));
-
-        // Jump back to the beginning of the file so the tar builder can read it.
-        file.seek(SeekFrom::Start(0))
-            .map_err(failure::system(format!(
-                "Unable to seek file {}.",
-                source_path.to_string_lossy().code_str(),
-            )))?;
+        );
 
-        // Add the file to the archive and return.
-        add_file(
-            builder,
-            visited_paths,
-            destination_path,
-            file,
-            metadata.len(),
-            executable,
-        )
+        // Everything succeeded.
+        Ok(())
     } else if metadata.file_type().is_symlink() {
         // It's a symlink. Read the target path.
         let target_path = read_link(source_path).map_err(failure::system(format!(
@@ -174,17 +358,60 @@ fn add_path<W: Write>(
             source_path.to_string_lossy().code_str(),
         )))?;
 
-        // Compute the hash of the symlink path and the target path.
-        content_hashes.push(cache::combine(destination_path, &target_path));
+        // Determine the metadata to record in the archive. On Linux, the permissions of a symlink
+        // are always `0o777`.
+        let (mode, mtime) = mode_and_mtime(options, metadata, 0o777);
+
+        // Compute the hash of the symlink path, the target path, and the metadata.
+        content_hashes.insert(
+            destination_path.to_owned(),
+            hash_metadata(
+                cache::combine(destination_path, &target_path),
+                options,
+                mode,
+                mtime,
+            ),
+        );
 
         // Add the symlink to the archive.
-        add_symlink(builder, visited_paths, destination_path, &target_path)
+        if let Some(builder) = builder {
+            add_symlink(
+                builder,
+                visited_paths,
+                destination_path,
+                &target_path,
+                mtime,
+            )?;
+        }
+
+        // Everything succeeded.
+        Ok(())
     } else if metadata.file_type().is_dir() {
-        // It's a directory. Only its name is relevant for the cache key.
-        content_hashes.push(destination_path.crypto_hash());
+        // Determine the metadata to record in the archive.
+        let (mode, mtime) = mode_and_mtime(options, metadata, 0o777);
+
+        // It's a directory. Only its name and metadata are relevant for the cache key.
+        content_hashes.insert(
+            destination_path.to_owned(),
+            hash_metadata(destination_path.crypto_hash(), options, mode, mtime),
+        );
 
         // Add the directory to the archive.
-        add_directory(builder, visited_paths, destination_path)
+        if let Some(builder) = builder {
+            add_directory(builder, visited_paths, destination_path, mode, mtime)?;
+        }
+
+        // Everything succeeded.
+        Ok(())
+    } else if options.skip_special_files {
+        // It's a socket, FIFO, or device file. Leave it out.
+        warn!(
+            "Skipping {}, which is not a file, directory, or symbolic link.",
+            source_path.to_string_lossy().code_str(),
+        );
+
+        // Everything succeeded.
+        Ok(())
     } else {
         Err(Failure::User(
             format!(
@@ -196,65 +423,350 @@ fn add_path<W: Write>(
     }
 }
 
-// Construct a tar archive and return a hash of its contents. This function does not follow symbolic
-// links.
+// Determine the destination of a path found inside an input path, given the destination of the
+// input path itself.
+fn destination_of(
+    path: &Path,
+    input_path: &Path,
+    destination_root: &Path,
+) -> Result<PathBuf, Failure> {
+    let relative_path = path
+        .strip_prefix(input_path)
+        .map_err(failure::system(format!(
+            "Unable to relativize path {} with respect to {}.",
+            path.to_string_lossy().code_str(),
+            input_path.to_string_lossy().code_str(),
+        )))?;
+
+    // Joining an empty path would add a trailing `/`.
+    Ok(if relative_path.components().next().is_none() {
+        destination_root.to_owned()
+    } else {
+        destination_root.join(relative_path)
+    })
+}
+
+// Add the directories between `source_dir` and an input path (but not the input path itself) to the
+// archive, along with their metadata. Their destinations are relative to `destination_dir`. Input
+// paths which lead out of `source_dir` have no such directories.
+#[allow(clippy::too_many_arguments)]
+fn add_ancestors<W: Write>(
+    builder: &mut Option<Builder<W>>,
+    content_hashes: &mut BTreeMap<PathBuf, String>,
+    visited_paths: &mut HashSet<PathBuf>,
+    hard_links: &mut HashMap<(u64, u64), PathBuf>,
+    memo: &mut Memo,
+    options: Options,
+    source_dir: &Path,
+    destination_dir: &Path,
+    input_path: &Path, // Relative to `source_dir`
+) -> Result<(), Failure> {
+    for component in input_path.components() {
+        match component {
+            Component::Normal(_) => {}
+            _ => return Ok(()),
+        }
+    }
+
+    // Add the ancestors from the outermost one inward, so each is added before its contents.
+    let mut ancestors = input_path
+        .ancestors()
+        .skip(1)
+        .filter(|ancestor| ancestor.components().next().is_some())
+        .collect::<Vec<_>>();
+    ancestors.reverse();
+
+    for ancestor in ancestors {
+        let source_path = source_dir.join(ancestor);
+        let ancestor_metadata = metadata(&source_path).map_err(failure::system(format!(
+            "Unable to fetch filesystem metadata for {}.",
+            source_path.to_string_lossy().code_str(),
+        )))?;
+
+        add_path(
+            builder,
+            content_hashes,
+            visited_paths,
+            hard_links,
+            memo,
+            options,
+            &source_path,
+            strip_root(&destination_dir.join(ancestor)),
+            &ancestor_metadata,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Construct a matcher for the paths which should be left out of the archive. The patterns use the
+// same syntax as `.gitignore` files. They come from the `IGNORE_FILE_NAME` file in `source_dir`, if
+// it exists, followed by `excluded_input_paths`.
+pub fn exclusions(
+    source_dir: &Path,
+    excluded_input_paths: &[PathBuf],
+) -> Result<Gitignore, Failure> {
+    let mut builder = GitignoreBuilder::new(source_dir);
+
+    // Read the ignore file, if there is one.
+    let ignore_file_path = source_dir.join(IGNORE_FILE_NAME);
+    if ignore_file_path.is_file() {
+        if let Some(error) = builder.add(&ignore_file_path) {
+            return Err(failure::user(format!(
+                "Unable to parse file {}.",
+                ignore_file_path.to_string_lossy().code_str(),
+            ))(error));
+        }
+    }
+
+    // Add the patterns from the task.
+    for pattern in excluded_input_paths {
+        builder
+            .add_line(None, &pattern.to_string_lossy())
+            .map_err(failure::user(format!(
+                "Invalid exclusion pattern {}.",
+                pattern.to_string_lossy().code_str(),
+            )))?;
+    }
+
+    // Compile the patterns.
+    builder
+        .build()
+        .map_err(failure::user("Unable to compile the exclusion patterns."))
+}
+
+// Determine whether a path or any of its ancestors are excluded. The patterns are relative to
+// `source_dir`, so only the ancestors inside it are considered. A path outside `source_dir` can
+// still be excluded by a pattern which matches its file name.
+fn is_excluded(exclusions: &Gitignore, source_dir: &Path, path: &Path, is_dir: bool) -> bool {
+    match path.strip_prefix(source_dir) {
+        Ok(relative_path) => exclusions.matched_path_or_any_parents(relative_path, is_dir),
+        Err(_) => exclusions.matched(path, is_dir),
+    }
+    .is_ignore()
+}
+
+// Construct a tar archive and return the hashes of its contents. Input paths may be glob patterns,
+// which are expanded relative to `source_dir`, or mappings to particular destinations, which are
+// relative to `destination_dir` unless they are absolute. Paths which match the exclusion patterns
+// (see `exclusions`) are skipped. The hashes of unchanged files are taken from `memo` rather than
+// computed again. Symbolic links are archived as links unless `options.follow_symlinks` is set, in
+// which case they are replaced by whatever they point to.
+#[allow(clippy::too_many_arguments)]
 pub fn create<W: Write>(
-    spinner_message: &str,
     writer: W,
-    input_paths: &[PathBuf],
+    input_paths: &[InputPath],
+    excluded_input_paths: &[PathBuf],
     source_dir: &Path,
     destination_dir: &Path,
+    memo: &mut Memo,
+    options: Options,
     interrupted: &Arc<AtomicBool>,
-) -> Result<(W, String), Failure> {
-    // Render a spinner animation in the terminal.
-    let _guard = spin(spinner_message);
+) -> Result<(W, Hashes), Failure> {
+    let (writer, hashes) = traverse(
+        Some(Builder::new(writer)),
+        input_paths,
+        excluded_input_paths,
+        source_dir,
+        destination_dir,
+        memo,
+        options,
+        interrupted,
+    )?;
+
+    // The `unwrap` is safe because we provided a builder.
+    Ok((writer.unwrap(), hashes))
+}
 
-    // This vector will store all the hashes of the contents and metadata of all the files in the
-    // archive. In the end, we will sort this vector and then take the hash of the whole thing.
-    let mut content_hashes = vec![];
+// Compute the same hashes as `create` without constructing an archive. This is useful for finding
+// out the hash before deciding whether the archive is needed at all.
+pub fn hash(
+    input_paths: &[InputPath],
+    excluded_input_paths: &[PathBuf],
+    source_dir: &Path,
+    destination_dir: &Path,
+    memo: &mut Memo,
+    options: Options,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<Hashes, Failure> {
+    traverse::<Sink>(
+        None,
+        input_paths,
+        excluded_input_paths,
+        source_dir,
+        destination_dir,
+        memo,
+        options,
+        interrupted,
+    )
+    .map(|(_, hashes)| hashes)
+}
+
+// Traverse the input paths, adding them to the archive if there is one, and return the archive
+// writer and the hashes of the contents. This is the shared implementation of `create` and `hash`.
+#[allow(clippy::too_many_arguments)]
+fn traverse<W: Write>(
+    mut builder: Option<Builder<W>>,
+    input_paths: &[InputPath],
+    excluded_input_paths: &[PathBuf],
+    source_dir: &Path,
+    destination_dir: &Path,
+    memo: &mut Memo,
+    options: Options,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(Option<W>, Hashes), Failure> {
+    // This manifest will store the hashes of the contents and metadata of all the files in the
+    // archive, keyed by their paths in the archive. In the end, we will take the hash of the whole
+    // thing in path order, so the result doesn't depend on how the filesystem orders directory
+    // entries.
+    let mut content_hashes = BTreeMap::new();
 
     // This set is used to avoid adding the same path to the archive multiple times, which could
     // otherwise easily happen since we explicitly add all ancestor directories for every entry
     // added to the archive.
     let mut visited_paths = HashSet::new();
 
-    // This builder will be responsible for writing to the tar file.
-    let mut builder = Builder::new(writer);
+    // This map remembers where each file with multiple names was first added to the archive, keyed
+    // by device and inode number, so the other names can be added as hard links.
+    let mut hard_links = HashMap::new();
 
     // Add `destination_dir` to the archive.
-    add_directory(
-        &mut builder,
-        &mut visited_paths,
-        strip_root(&destination_dir),
-    )?;
+    if let Some(builder) = &mut builder {
+        add_directory(
+            builder,
+            &mut visited_paths,
+            strip_root(&destination_dir),
+            0o777,
+            0,
+        )?;
+    }
 
-    // Add each path to the archive.
+    // Determine which paths should be left out.
+    let exclusions = exclusions(source_dir, excluded_input_paths)?;
+
+    // Determine where each input path goes. Glob patterns are expanded into the paths they match,
+    // and each path is copied to the corresponding location in `destination_dir` unless the entry
+    // specifies its own destination. The pairs are sorted so the archive doesn't depend on the
+    // order in which they were listed. For example, that order would otherwise determine which name
+    // of a hard-linked file is stored as a link.
+    let mut mappings = vec![];
     for input_path in input_paths {
+        match input_path {
+            InputPath::Path(path) => {
+                for expanded_path in
+                    glob::expand_all(source_dir, slice::from_ref(path), interrupted)?
+                {
+                    let destination_path = destination_dir.join(&expanded_path);
+                    mappings.push((expanded_path, destination_path));
+                }
+            }
+            InputPath::Mapping {
+                source,
+                destination,
+            } => {
+                // The source is not a glob pattern [ref:input_paths_mapping_literal]. If the
+                // destination is absolute, `join` returns it unchanged.
+                mappings.push((source.to_owned(), destination_dir.join(destination)));
+            }
+        }
+    }
+    mappings.sort();
+    mappings.dedup();
+
+    // Add each path to the archive.
+    for (relative_input_path, destination_root) in &mappings {
         // The original `input_path` is relative to `source_dir`. Here we make it relative to the
         // working directory instead.
-        let input_path = source_dir.join(input_path);
+        let input_path = source_dir.join(relative_input_path);
+
+        // The ancestors of a destination outside `destination_dir` may be system directories like
+        // `/etc`, so we leave them alone rather than adding them to the archive with permissive
+        // modes. Any that don't exist will be created when the archive is extracted.
+        if !destination_root.starts_with(destination_dir) {
+            if let Some(parent) = strip_root(destination_root).parent() {
+                visited_paths.extend(parent.ancestors().map(ToOwned::to_owned));
+            }
+        }
 
         // Fetch filesystem metadata for `input_path`.
-        let input_path_metadata =
-            symlink_metadata(&input_path).map_err(failure::system(format!(
-                "Unable to fetch filesystem metadata for {}.",
-                input_path.to_string_lossy().code_str(),
-            )))?;
+        let input_path_metadata = if options.follow_symlinks {
+            metadata(&input_path)
+        } else {
+            symlink_metadata(&input_path)
+        }
+        .map_err(failure::system(format!(
+            "Unable to fetch filesystem metadata for {}.",
+            input_path.to_string_lossy().code_str(),
+        )))?;
+
+        // Skip the path if it or any of its ancestors are excluded.
+        if is_excluded(
+            &exclusions,
+            source_dir,
+            &input_path,
+            input_path_metadata.is_dir(),
+        ) {
+            continue;
+        }
+
+        // If the destination mirrors the source, the directories in between come from the host
+        // too. Add them with their own metadata if any is preserved, since otherwise `add_path`
+        // would add them with the default permissions.
+        if (options.preserve_permissions || options.preserve_mtimes)
+            && *destination_root == destination_dir.join(relative_input_path)
+        {
+            add_ancestors(
+                &mut builder,
+                &mut content_hashes,
+                &mut visited_paths,
+                &mut hard_links,
+                memo,
+                options,
+                source_dir,
+                destination_dir,
+                relative_input_path,
+            )?;
+        }
 
         // Check what type of filesystem object the path corresponds to.
         if input_path_metadata.is_dir() {
-            // It's a directory. Traverse it.
-            for entry in WalkDir::new(&input_path) {
+            // It's a directory. Traverse it in order of file name, skipping any excluded entries.
+            // When a directory is excluded, its contents are never visited.
+            for entry in WalkDir::new(&input_path)
+                .follow_links(options.follow_symlinks)
+                .sort_by(|x, y| x.file_name().cmp(y.file_name()))
+                .into_iter()
+                .filter_entry(|entry| {
+                    !exclusions
+                        .matched(entry.path(), entry.file_type().is_dir())
+                        .is_ignore()
+                })
+            {
                 // If the user wants to stop the operation, quit now.
                 if interrupted.load(Ordering::SeqCst) {
                     return Err(Failure::Interrupted);
                 }
 
-                // Unwrap the entry.
-                let entry = entry.map_err(failure::user(format!(
-                    "Unable to traverse directory {}.",
-                    input_path.to_string_lossy().code_str(),
-                )))?;
+                // Unwrap the entry. When following symbolic links, the traversal fails if a link
+                // points to one of its own ancestors.
+                let entry = entry.map_err(|e| {
+                    if let (Some(path), Some(ancestor)) = (e.path(), e.loop_ancestor()) {
+                        Failure::User(
+                            format!(
+                                "Symbolic link {} points to its ancestor {}, which would form a \
+                                 cycle.",
+                                path.to_string_lossy().code_str(),
+                                ancestor.to_string_lossy().code_str(),
+                            ),
+                            None,
+                        )
+                    } else {
+                        failure::user(format!(
+                            "Unable to traverse directory {}.",
+                            input_path.to_string_lossy().code_str(),
+                        ))(e)
+                    }
+                })?;
 
                 // Fetch the metadata for this entry.
                 let entry_metadata = entry.metadata().map_err(failure::system(format!(
@@ -267,16 +779,15 @@ pub fn create<W: Write>(
                     &mut builder,
                     &mut content_hashes,
                     &mut visited_paths,
+                    &mut hard_links,
+                    memo,
+                    options,
                     entry.path(),
-                    strip_root(
-                        &destination_dir.join(entry.path().strip_prefix(&source_dir).map_err(
-                            failure::system(format!(
-                                "Unable to relativize path {} with respect to {}.",
-                                entry.path().to_string_lossy().code_str(),
-                                source_dir.to_string_lossy().code_str(),
-                            )),
-                        )?),
-                    ),
+                    strip_root(&destination_of(
+                        entry.path(),
+                        &input_path,
+                        destination_root,
+                    )?),
                     &entry_metadata,
                 )?;
             }
@@ -286,31 +797,699 @@ pub fn create<W: Write>(
                 &mut builder,
                 &mut content_hashes,
                 &mut visited_paths,
+                &mut hard_links,
+                memo,
+                options,
                 &input_path,
-                strip_root(
-                    &destination_dir.join(input_path.strip_prefix(&source_dir).map_err(
-                        failure::system(format!(
-                            "Unable to relativize path {} with respect to {}.",
-                            input_path.to_string_lossy().code_str(),
-                            source_dir.to_string_lossy().code_str(),
-                        )),
-                    )?),
-                ),
+                strip_root(destination_root),
                 &input_path_metadata,
             )?;
         }
     }
 
-    // Sort the file hashes to ensure the directory traversal order doesn't matter.
-    content_hashes.sort();
+    // The hash covers where each input path was copied from, not just where it ended up.
+    let hash = content_hashes
+        .values()
+        .fold(String::new(), |acc, x| cache::combine(&acc, x));
+    let hash = mappings
+        .iter()
+        .fold(hash, |acc, (source_path, destination_path)| {
+            cache::combine(&acc, &cache::combine(source_path, destination_path))
+        });
 
-    // Return the tar file and the hash of its contents.
+    // Return the tar file and the hashes of its contents.
     Ok((
         builder
-            .into_inner()
+            .map(Builder::into_inner)
+            .transpose()
             .map_err(failure::system("Error writing tar archive."))?,
-        content_hashes
-            .iter()
-            .fold(String::new(), |acc, x| cache::combine(&acc, x)),
+        Hashes {
+            total: hash,
+            entries: content_hashes,
+        },
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        memo::Memo,
+        tar::{create, hash, Options},
+        toastfile::InputPath,
+    };
+    use filetime::{set_file_mtime, FileTime};
+    use std::{
+        fs::{create_dir_all, hard_link, set_permissions, write, Permissions},
+        io::sink,
+        os::unix::{
+            fs::{symlink, PermissionsExt},
+            net::UnixListener,
+        },
+        path::Path,
+        sync::{atomic::AtomicBool, Arc},
+    };
+    use tar::{Archive, EntryType};
+    use tempfile::tempdir;
+
+    fn archive_hash(input_paths: &[InputPath], source_dir: &Path, options: Options) -> String {
+        create(
+            sink(),
+            input_paths,
+            &[],
+            source_dir,
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            options,
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap()
+        .1
+        .total
+    }
+
+    #[test]
+    fn create_hash_creation_order() {
+        let dir1 = tempdir().unwrap();
+        create_dir_all(dir1.path().join("foo/bar")).unwrap();
+        write(dir1.path().join("foo/bar/baz.txt"), "baz").unwrap();
+        write(dir1.path().join("foo/qux.txt"), "qux").unwrap();
+
+        let dir2 = tempdir().unwrap();
+        create_dir_all(dir2.path().join("foo")).unwrap();
+        write(dir2.path().join("foo/qux.txt"), "qux").unwrap();
+        create_dir_all(dir2.path().join("foo/bar")).unwrap();
+        write(dir2.path().join("foo/bar/baz.txt"), "baz").unwrap();
+
+        assert_eq!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                dir1.path(),
+                Options::default()
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                dir2.path(),
+                Options::default()
+            ),
+        );
+    }
+
+    #[test]
+    fn create_hash_input_paths_order() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo.txt"), "foo").unwrap();
+        write(dir.path().join("bar.txt"), "bar").unwrap();
+
+        assert_eq!(
+            archive_hash(
+                &[
+                    InputPath::Path(Path::new("foo.txt").to_owned()),
+                    InputPath::Path(Path::new("bar.txt").to_owned())
+                ],
+                dir.path(),
+                Options::default(),
+            ),
+            archive_hash(
+                &[
+                    InputPath::Path(Path::new("bar.txt").to_owned()),
+                    InputPath::Path(Path::new("foo.txt").to_owned())
+                ],
+                dir.path(),
+                Options::default(),
+            ),
+        );
+    }
+
+    #[test]
+    fn create_hash_contents() {
+        let dir1 = tempdir().unwrap();
+        write(dir1.path().join("foo.txt"), "foo").unwrap();
+
+        let dir2 = tempdir().unwrap();
+        write(dir2.path().join("foo.txt"), "bar").unwrap();
+
+        assert_ne!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir1.path(),
+                Options::default()
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir2.path(),
+                Options::default()
+            ),
+        );
+    }
+
+    #[test]
+    fn hash_matches_create() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo/bar")).unwrap();
+        write(dir.path().join("foo/bar/baz.txt"), "baz").unwrap();
+        write(dir.path().join("foo/qux.txt"), "qux").unwrap();
+
+        assert_eq!(
+            hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                &[],
+                dir.path(),
+                Path::new("/scratch"),
+                &mut Memo::load(None).unwrap(),
+                Options::default(),
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap()
+            .total,
+            archive_hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                dir.path(),
+                Options::default()
+            ),
+        );
+    }
+
+    #[test]
+    fn hash_entries() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo/bar.txt"), "bar").unwrap();
+
+        let hashes = hash(
+            &[InputPath::Path(Path::new("foo").to_owned())],
+            &[],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options::default(),
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        assert_eq!(
+            hashes.entries.keys().collect::<Vec<_>>(),
+            vec![Path::new("scratch/foo"), Path::new("scratch/foo/bar.txt")],
+        );
+    }
+
+    #[test]
+    fn create_hash_excluded() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo/target")).unwrap();
+        write(dir.path().join("foo/bar.txt"), "bar").unwrap();
+        write(dir.path().join("foo/baz.log"), "baz").unwrap();
+        write(dir.path().join("foo/target/qux.txt"), "qux").unwrap();
+
+        let (archive, hashes) = create(
+            vec![],
+            &[InputPath::Path(Path::new("foo").to_owned())],
+            &[
+                Path::new("*.log").to_owned(),
+                Path::new("target").to_owned(),
+            ],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options::default(),
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        let paths = Archive::new(archive.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("scratch").to_owned(),
+                Path::new("scratch/foo").to_owned(),
+                Path::new("scratch/foo/bar.txt").to_owned(),
+            ],
+        );
+        assert_eq!(
+            hashes.entries.keys().collect::<Vec<_>>(),
+            vec![Path::new("scratch/foo"), Path::new("scratch/foo/bar.txt")],
+        );
+
+        // The excluded files don't affect the hash.
+        write(dir.path().join("foo/baz.log"), "changed").unwrap();
+        write(dir.path().join("foo/target/qux.txt"), "changed").unwrap();
+        assert_eq!(
+            hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                &[
+                    Path::new("*.log").to_owned(),
+                    Path::new("target").to_owned()
+                ],
+                dir.path(),
+                Path::new("/scratch"),
+                &mut Memo::load(None).unwrap(),
+                Options::default(),
+                &Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap()
+            .total,
+            hashes.total,
+        );
+    }
+
+    #[test]
+    fn create_excluded_external_source() {
+        let dir = tempdir().unwrap();
+        let external_dir = tempdir().unwrap();
+        write(external_dir.path().join("bar.txt"), "bar").unwrap();
+        write(external_dir.path().join("baz.log"), "baz").unwrap();
+
+        let hashes = hash(
+            &[
+                InputPath::Mapping {
+                    source: external_dir.path().join("bar.txt"),
+                    destination: Path::new("bar.txt").to_owned(),
+                },
+                InputPath::Mapping {
+                    source: external_dir.path().join("baz.log"),
+                    destination: Path::new("baz.log").to_owned(),
+                },
+            ],
+            &[Path::new("*.log").to_owned()],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options::default(),
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        assert_eq!(
+            hashes.entries.keys().collect::<Vec<_>>(),
+            vec![Path::new("scratch/bar.txt")],
+        );
+    }
+
+    #[test]
+    fn create_hash_permissions_ignored() {
+        let dir1 = tempdir().unwrap();
+        write(dir1.path().join("foo.txt"), "foo").unwrap();
+        set_permissions(dir1.path().join("foo.txt"), Permissions::from_mode(0o644)).unwrap();
+
+        let dir2 = tempdir().unwrap();
+        write(dir2.path().join("foo.txt"), "foo").unwrap();
+        set_permissions(dir2.path().join("foo.txt"), Permissions::from_mode(0o600)).unwrap();
+
+        assert_eq!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir1.path(),
+                Options::default(),
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir2.path(),
+                Options::default(),
+            ),
+        );
+    }
+
+    #[test]
+    fn create_hash_preserve_permissions() {
+        let options = Options {
+            preserve_permissions: true,
+            ..Options::default()
+        };
+
+        let dir1 = tempdir().unwrap();
+        write(dir1.path().join("foo.txt"), "foo").unwrap();
+        set_permissions(dir1.path().join("foo.txt"), Permissions::from_mode(0o644)).unwrap();
+
+        let dir2 = tempdir().unwrap();
+        write(dir2.path().join("foo.txt"), "foo").unwrap();
+        set_permissions(dir2.path().join("foo.txt"), Permissions::from_mode(0o600)).unwrap();
+
+        assert_ne!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir1.path(),
+                options
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir2.path(),
+                options
+            ),
+        );
+    }
+
+    #[test]
+    fn create_preserve_permissions_ancestors() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo/bar")).unwrap();
+        write(dir.path().join("foo/bar/baz.txt"), "baz").unwrap();
+        set_permissions(
+            dir.path().join("foo/bar/baz.txt"),
+            Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        set_permissions(dir.path().join("foo"), Permissions::from_mode(0o750)).unwrap();
+        set_permissions(dir.path().join("foo/bar"), Permissions::from_mode(0o700)).unwrap();
+
+        let (archive, _) = create(
+            vec![],
+            &[InputPath::Path(Path::new("foo/bar/baz.txt").to_owned())],
+            &[],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options {
+                preserve_permissions: true,
+                ..Options::default()
+            },
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        let modes = Archive::new(archive.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.header().mode().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            modes[1..],
+            [
+                (Path::new("scratch/foo").to_owned(), 0o750),
+                (Path::new("scratch/foo/bar").to_owned(), 0o700),
+                (Path::new("scratch/foo/bar/baz.txt").to_owned(), 0o644),
+            ],
+        );
+    }
+
+    #[test]
+    fn create_hash_mtimes_ignored() {
+        let dir1 = tempdir().unwrap();
+        write(dir1.path().join("foo.txt"), "foo").unwrap();
+        set_file_mtime(dir1.path().join("foo.txt"), FileTime::from_unix_time(1, 0)).unwrap();
+
+        let dir2 = tempdir().unwrap();
+        write(dir2.path().join("foo.txt"), "foo").unwrap();
+        set_file_mtime(dir2.path().join("foo.txt"), FileTime::from_unix_time(2, 0)).unwrap();
+
+        assert_eq!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir1.path(),
+                Options::default(),
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir2.path(),
+                Options::default(),
+            ),
+        );
+    }
+
+    #[test]
+    fn create_hash_preserve_mtimes() {
+        let options = Options {
+            preserve_mtimes: true,
+            ..Options::default()
+        };
+
+        let dir1 = tempdir().unwrap();
+        write(dir1.path().join("foo.txt"), "foo").unwrap();
+        set_file_mtime(dir1.path().join("foo.txt"), FileTime::from_unix_time(1, 0)).unwrap();
+
+        let dir2 = tempdir().unwrap();
+        write(dir2.path().join("foo.txt"), "foo").unwrap();
+        set_file_mtime(dir2.path().join("foo.txt"), FileTime::from_unix_time(2, 0)).unwrap();
+
+        assert_ne!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir1.path(),
+                options
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo.txt").to_owned())],
+                dir2.path(),
+                options
+            ),
+        );
+    }
+
+    #[test]
+    fn create_hard_link() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("bar.txt"), "bar").unwrap();
+        hard_link(dir.path().join("bar.txt"), dir.path().join("foo.txt")).unwrap();
+
+        let (archive, _) = create(
+            vec![],
+            &[
+                InputPath::Path(Path::new("foo.txt").to_owned()),
+                InputPath::Path(Path::new("bar.txt").to_owned()),
+            ],
+            &[],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options::default(),
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        let entries = Archive::new(archive.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.header().entry_type(),
+                    entry.link_name().unwrap().map(|path| path.into_owned()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            vec![
+                (Path::new("scratch").to_owned(), EntryType::Directory, None),
+                (
+                    Path::new("scratch/bar.txt").to_owned(),
+                    EntryType::Regular,
+                    None
+                ),
+                (
+                    Path::new("scratch/foo.txt").to_owned(),
+                    EntryType::Link,
+                    Some(Path::new("scratch/bar.txt").to_owned()),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn create_mapping() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("config")).unwrap();
+        write(dir.path().join("config/prod.yml"), "prod").unwrap();
+        create_dir_all(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo/bar.txt"), "bar").unwrap();
+
+        let (archive, _) = create(
+            vec![],
+            &[
+                InputPath::Mapping {
+                    source: Path::new("config/prod.yml").to_owned(),
+                    destination: Path::new("/etc/app/config.yml").to_owned(),
+                },
+                InputPath::Mapping {
+                    source: Path::new("foo").to_owned(),
+                    destination: Path::new("baz").to_owned(),
+                },
+            ],
+            &[],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options::default(),
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        let paths = Archive::new(archive.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("scratch").to_owned(),
+                Path::new("etc/app/config.yml").to_owned(),
+                Path::new("scratch/baz").to_owned(),
+                Path::new("scratch/baz/bar.txt").to_owned(),
+            ],
+        );
+    }
+
+    #[test]
+    fn create_hash_mapping_source() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo.txt"), "foo").unwrap();
+        write(dir.path().join("bar.txt"), "foo").unwrap();
+
+        assert_ne!(
+            archive_hash(
+                &[InputPath::Mapping {
+                    source: Path::new("foo.txt").to_owned(),
+                    destination: Path::new("baz.txt").to_owned(),
+                }],
+                dir.path(),
+                Options::default()
+            ),
+            archive_hash(
+                &[InputPath::Mapping {
+                    source: Path::new("bar.txt").to_owned(),
+                    destination: Path::new("baz.txt").to_owned(),
+                }],
+                dir.path(),
+                Options::default()
+            ),
+        );
+    }
+
+    #[test]
+    fn create_hash_mapping_destination() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo.txt"), "foo").unwrap();
+
+        assert_ne!(
+            archive_hash(
+                &[InputPath::Mapping {
+                    source: Path::new("foo.txt").to_owned(),
+                    destination: Path::new("bar.txt").to_owned(),
+                }],
+                dir.path(),
+                Options::default()
+            ),
+            archive_hash(
+                &[InputPath::Mapping {
+                    source: Path::new("foo.txt").to_owned(),
+                    destination: Path::new("/bar.txt").to_owned(),
+                }],
+                dir.path(),
+                Options::default()
+            ),
+        );
+    }
+
+    #[test]
+    fn create_special_file_fail() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo")).unwrap();
+        let _listener = UnixListener::bind(dir.path().join("foo/bar.sock")).unwrap();
+
+        assert!(create(
+            sink(),
+            &[InputPath::Path(Path::new("foo").to_owned())],
+            &[],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options::default(),
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn create_special_file_skip() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo")).unwrap();
+        let _listener = UnixListener::bind(dir.path().join("foo/bar.sock")).unwrap();
+
+        let options = Options {
+            skip_special_files: true,
+            ..Options::default()
+        };
+
+        let empty_dir = tempdir().unwrap();
+        create_dir_all(empty_dir.path().join("foo")).unwrap();
+
+        assert_eq!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                dir.path(),
+                options
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                empty_dir.path(),
+                options
+            ),
+        );
+    }
+
+    #[test]
+    fn create_hash_follow_symlinks() {
+        let options = Options {
+            follow_symlinks: true,
+            ..Options::default()
+        };
+
+        let dir1 = tempdir().unwrap();
+        create_dir_all(dir1.path().join("foo")).unwrap();
+        write(dir1.path().join("bar.txt"), "bar").unwrap();
+        symlink(dir1.path().join("bar.txt"), dir1.path().join("foo/bar.txt")).unwrap();
+
+        let dir2 = tempdir().unwrap();
+        create_dir_all(dir2.path().join("foo")).unwrap();
+        write(dir2.path().join("foo/bar.txt"), "bar").unwrap();
+
+        assert_eq!(
+            archive_hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                dir1.path(),
+                options
+            ),
+            archive_hash(
+                &[InputPath::Path(Path::new("foo").to_owned())],
+                dir2.path(),
+                options
+            ),
+        );
+    }
+
+    #[test]
+    fn create_follow_symlinks_cycle() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("foo")).unwrap();
+        symlink(dir.path().join("foo"), dir.path().join("foo/bar")).unwrap();
+
+        assert!(create(
+            sink(),
+            &[InputPath::Path(Path::new("foo").to_owned())],
+            &[],
+            dir.path(),
+            Path::new("/scratch"),
+            &mut Memo::load(None).unwrap(),
+            Options {
+                follow_symlinks: true,
+                ..Options::default()
+            },
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .is_err());
+    }
+}
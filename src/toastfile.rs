@@ -1,10 +1,13 @@
-use crate::{failure::Failure, format, format::CodeStr};
+use crate::{failure, failure::Failure, format, format::CodeStr, glob, tar};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     env,
-    path::{Path, PathBuf},
+    fs::canonicalize,
+    path::{Component, Path, PathBuf},
 };
+use walkdir::WalkDir;
 
 // The default location for commands and files copied into the container
 pub const DEFAULT_LOCATION: &str = "/scratch";
@@ -12,6 +15,85 @@ pub const DEFAULT_LOCATION: &str = "/scratch";
 // The default user for commands and files copied into the container
 pub const DEFAULT_USER: &str = "root";
 
+// This enum represents what to do with special files (sockets, FIFOs, and device files) in the
+// input paths.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialFiles {
+    Fail,
+    Skip,
+}
+
+// This enum represents an entry in `input_paths`. An entry is either a path (or glob pattern) which
+// is copied to the corresponding location in the container, or an explicit mapping from a path on
+// the host to a destination in the container.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum InputPath {
+    Path(PathBuf),
+    Mapping {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+}
+
+impl InputPath {
+    // Return the path on the host.
+    pub fn source(&self) -> &Path {
+        match self {
+            InputPath::Path(path) => path,
+            InputPath::Mapping { source, .. } => source,
+        }
+    }
+}
+
+// This enum represents what happens to existing files when an output path is copied to the host.
+// With `Merge`, files are added or overwritten but never deleted. With `Mirror`, the path on the
+// host is replaced so it matches the one in the container exactly.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    Merge,
+    Mirror,
+}
+
+// This enum represents an entry in `output_paths`. An entry is either a path (or glob pattern)
+// which is copied from the corresponding location in the container, or an explicit mapping from a
+// path in the container to a destination on the host. The destination of a mapping defaults to its
+// source.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum OutputPath {
+    Path(PathBuf),
+    Mapping {
+        source: PathBuf,
+
+        #[serde(default)]
+        destination: Option<PathBuf>,
+
+        #[serde(default = "default_output_path_mode")]
+        mode: OutputMode,
+    },
+}
+
+fn default_output_path_mode() -> OutputMode {
+    OutputMode::Merge
+}
+
+impl OutputPath {
+    // Return the path on the host.
+    pub fn destination(&self) -> &Path {
+        match self {
+            OutputPath::Path(path) => path,
+            OutputPath::Mapping {
+                source,
+                destination,
+                ..
+            } => destination.as_ref().map_or(source, AsRef::as_ref),
+        }
+    }
+}
+
 // This struct represents a task.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -34,14 +116,53 @@ pub struct Task {
     pub environment: HashMap<String, Option<String>>,
 
     // Must be relative [ref:input_paths_relative]
+    // Must be contained in the toastfile directory unless `allow_external_paths` is enabled
+    // [ref:input_paths_contained]
+    // Glob patterns must be valid [ref:input_paths_globs_valid]
+    // Mapping sources must not be glob patterns [ref:input_paths_mapping_literal]
+    // Mapping destinations must not contain `..` [ref:input_paths_destination_no_parent]
+    #[serde(default)]
+    pub input_paths: Vec<InputPath>,
+
+    // Must be valid exclusion patterns [ref:excluded_input_paths_valid]
+    #[serde(default)]
+    pub excluded_input_paths: Vec<PathBuf>,
+
+    #[serde(default = "default_task_preserve_permissions")]
+    pub preserve_permissions: bool,
+
+    #[serde(default = "default_task_preserve_mtimes")]
+    pub preserve_mtimes: bool,
+
+    #[serde(default = "default_task_chown_input_paths")]
+    pub chown_input_paths: bool,
+
+    #[serde(default = "default_task_special_files")]
+    pub special_files: SpecialFiles,
+
+    #[serde(default = "default_task_follow_symlinks")]
+    pub follow_symlinks: bool,
+
+    // Destinations must be relative [ref:output_paths_relative]
+    // Destinations must be contained in the toastfile directory unless `allow_external_paths` is
+    // enabled [ref:output_paths_contained]
+    // Glob patterns must be valid [ref:output_paths_globs_valid]
+    // Glob patterns must have a literal directory prefix [ref:output_paths_globs_prefixed]
+    // Mapping sources must not be glob patterns [ref:output_paths_mapping_literal]
+    // Mirrored destinations must not be the toastfile directory [ref:output_paths_mirror_not_root]
     #[serde(default)]
-    pub input_paths: Vec<PathBuf>,
+    pub output_paths: Vec<OutputPath>,
 
-    // Must be relative [ref:output_paths_relative]
+    // Same requirements as `output_paths`
     #[serde(default)]
-    pub output_paths: Vec<PathBuf>,
+    pub output_paths_on_failure: Vec<OutputPath>,
+
+    #[serde(default = "default_task_chown_output_paths")]
+    pub chown_output_paths: bool,
 
     // Must be relative [ref:mount_paths_relative]
+    // Must be contained in the toastfile directory unless `allow_external_paths` is enabled
+    // [ref:mount_paths_contained]
     // Must not contain `,` [ref:mount_paths_no_commas]
     // Must be empty if `cache` is enabled [ref:mount_paths_nand_cache]
     #[serde(default)]
@@ -50,6 +171,9 @@ pub struct Task {
     #[serde(default = "default_task_mount_readonly")]
     pub mount_readonly: bool,
 
+    #[serde(default = "default_task_allow_external_paths")]
+    pub allow_external_paths: bool,
+
     // Must be empty if `cache` is enabled [ref:ports_nand_cache]
     #[serde(default)]
     pub ports: Vec<String>,
@@ -69,10 +193,38 @@ fn default_task_cache() -> bool {
     true
 }
 
+fn default_task_preserve_permissions() -> bool {
+    false
+}
+
+fn default_task_preserve_mtimes() -> bool {
+    false
+}
+
+fn default_task_chown_input_paths() -> bool {
+    false
+}
+
+fn default_task_special_files() -> SpecialFiles {
+    SpecialFiles::Fail
+}
+
+fn default_task_follow_symlinks() -> bool {
+    false
+}
+
+fn default_task_chown_output_paths() -> bool {
+    false
+}
+
 fn default_task_mount_readonly() -> bool {
     false
 }
 
+fn default_task_allow_external_paths() -> bool {
+    false
+}
+
 fn default_task_location() -> PathBuf {
     Path::new(DEFAULT_LOCATION).to_owned()
 }
@@ -298,6 +450,186 @@ fn check_dependencies<'a>(toastfile: &'a Toastfile) -> Result<(), Failure> {
     Ok(())
 }
 
+// Determine how many levels below the directory it's relative to a relative path refers to, without
+// consulting the filesystem. For example, `foo/../bar` is one level down. Returns `None` if the
+// path leaves the directory at any point, as `foo/../../bar` does.
+fn lexical_depth(path: &Path) -> Option<usize> {
+    let mut depth = 0_usize;
+
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => depth = depth.checked_sub(1)?,
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(depth)
+}
+
+// Determine whether a relative path refers to something inside the directory it's relative to,
+// without consulting the filesystem.
+fn is_lexically_contained(path: &Path) -> bool {
+    lexical_depth(path).is_some()
+}
+
+// Construct an error for a path which is outside the toastfile directory.
+fn external_path_failure(name: &str, kind: &str, path: &Path) -> Failure {
+    Failure::User(
+        format!(
+            "Task {} has an {} outside the toastfile directory: {}. \
+             To allow this, set {} for this task.",
+            name.code_str(),
+            kind.code_str(),
+            path.to_string_lossy().code_str(),
+            "allow_external_paths: true".code_str(),
+        ),
+        None,
+    )
+}
+
+// Determine whether a relative path is inside `canonical_dir` after resolving any symbolic links.
+// Only the part of the path which exists can be resolved. The rest is covered by the lexical
+// check. Unless `follow_last` is enabled, the last component isn't resolved, since it will be
+// copied as is rather than followed.
+fn resolves_inside(canonical_dir: &Path, path: &Path, follow_last: bool) -> bool {
+    // Remove any `.` components so they aren't mistaken for the last component.
+    let path = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect::<PathBuf>();
+    if path.components().next().is_none() {
+        return true;
+    }
+
+    // Find the deepest ancestor which exists, and check where it really is.
+    let path = canonical_dir.join(path);
+    let start = if follow_last {
+        Some(path.as_path())
+    } else {
+        path.parent()
+    };
+    start
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .find_map(|ancestor| canonicalize(ancestor).ok())
+        .map_or(false, |resolved| resolved.starts_with(canonical_dir))
+}
+
+// Find a symbolic link inside a path (relative to `canonical_dir`) which points outside of
+// `canonical_dir`, if there is one. The returned path is relative to `canonical_dir`. Like
+// `tar::create`, this follows links to directories and skips anything excluded. Links which don't
+// point to anything are ignored, since there is nothing to copy.
+fn find_external_link(
+    canonical_dir: &Path,
+    exclusions: &Gitignore,
+    path: &Path,
+) -> Option<PathBuf> {
+    WalkDir::new(canonical_dir.join(path))
+        .follow_links(true)
+        .sort_by(|x, y| x.file_name().cmp(y.file_name()))
+        .into_iter()
+        .filter_entry(|entry| {
+            !exclusions
+                .matched(entry.path(), entry.file_type().is_dir())
+                .is_ignore()
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path_is_symlink())
+        .find(|entry| {
+            canonicalize(entry.path())
+                .map(|target| !target.starts_with(canonical_dir))
+                .unwrap_or(false)
+        })
+        .map(|entry| {
+            entry
+                .path()
+                .strip_prefix(canonical_dir)
+                .unwrap_or_else(|_| entry.path())
+                .to_owned()
+        })
+}
+
+// Check that the paths of a task don't escape the toastfile directory via symbolic links. Unlike
+// `check_task`, this consults the filesystem, so it should be done right before the task runs.
+// [tag:paths_resolve_inside]
+pub fn check_task_paths_resolve(
+    name: &str,
+    task: &Task,
+    toastfile_dir: &Path,
+) -> Result<(), Failure> {
+    // The user can opt out of this check.
+    if task.allow_external_paths {
+        return Ok(());
+    }
+
+    // Resolve the toastfile directory itself.
+    let canonical_dir = canonicalize(toastfile_dir).map_err(failure::system(format!(
+        "Unable to resolve path {}.",
+        toastfile_dir.to_string_lossy().code_str(),
+    )))?;
+
+    // For glob patterns, only the literal prefix exists on the host, and it will be traversed.
+    let resolve = |path: &Path, follow_last| {
+        if glob::is_glob(path) {
+            resolves_inside(&canonical_dir, &glob::literal_prefix(path), true)
+        } else {
+            resolves_inside(&canonical_dir, path, follow_last)
+        }
+    };
+
+    // Check `input_paths`. Symbolic links among the inputs themselves are copied as links, unless
+    // `follow_symlinks` is enabled. In that case, the targets of the links inside the inputs are
+    // copied too, so they must not lead outside either. Excluded paths aren't copied, so they
+    // don't matter.
+    let exclusions = if task.follow_symlinks {
+        Some(tar::exclusions(&canonical_dir, &task.excluded_input_paths)?)
+    } else {
+        None
+    };
+    for path in task.input_paths.iter().map(InputPath::source) {
+        if !resolve(path, task.follow_symlinks) {
+            return Err(external_path_failure(name, "input_path", path));
+        }
+
+        if let Some(exclusions) = &exclusions {
+            let root = if glob::is_glob(path) {
+                glob::literal_prefix(path)
+            } else {
+                path.to_owned()
+            };
+
+            if let Some(link) = find_external_link(&canonical_dir, exclusions, &root) {
+                return Err(external_path_failure(name, "input_path", &link));
+            }
+        }
+    }
+
+    // Check `output_paths` and `output_paths_on_failure`. The outputs replace whatever is already
+    // there, rather than writing through symbolic links.
+    for (kind, output_paths) in &[
+        ("output_path", &task.output_paths),
+        ("output_path_on_failure", &task.output_paths_on_failure),
+    ] {
+        for path in output_paths.iter().map(OutputPath::destination) {
+            if !resolve(path, false) {
+                return Err(external_path_failure(name, kind, path));
+            }
+        }
+    }
+
+    // Check `mount_paths`. Mounting a symbolic link mounts its target.
+    for path in &task.mount_paths {
+        if !resolve(path, true) {
+            return Err(external_path_failure(name, "mount_path", path));
+        }
+    }
+
+    // If we made it this far, the paths are fine.
+    Ok(())
+}
+
 // Check that a task is valid.
 fn check_task(name: &str, task: &Task) -> Result<(), Failure> {
     // Check that environment variable names don't have `=` in them. [tag:env_var_equals]
@@ -315,8 +647,11 @@ fn check_task(name: &str, task: &Task) -> Result<(), Failure> {
         }
     }
 
-    // Check that `input_paths` are relative. [tag:input_paths_relative]
-    for path in &task.input_paths {
+    // Check `input_paths`.
+    for input_path in &task.input_paths {
+        let path = input_path.source();
+
+        // Check that the path is relative. [tag:input_paths_relative]
         if path.is_absolute() {
             return Err(Failure::User(
                 format!(
@@ -328,20 +663,180 @@ fn check_task(name: &str, task: &Task) -> Result<(), Failure> {
                 None,
             ));
         }
+
+        // Check that the path doesn't escape the toastfile directory. [tag:input_paths_contained]
+        if !task.allow_external_paths && !is_lexically_contained(path) {
+            return Err(external_path_failure(name, "input_path", path));
+        }
+
+        // Check that glob patterns are valid. [tag:input_paths_globs_valid]
+        if glob::is_glob(path) {
+            glob::validate(path).map_err(|e| {
+                Failure::User(
+                    format!(
+                        "Task {} has an invalid {}: {}.",
+                        name.code_str(),
+                        "input_path".code_str(),
+                        path.to_string_lossy().code_str()
+                    ),
+                    Some(Box::new(e)),
+                )
+            })?;
+        }
+
+        if let InputPath::Mapping { destination, .. } = input_path {
+            // Check that the destination doesn't use `..` to climb out of the `location`, or out of
+            // the directories it names. [tag:input_paths_destination_no_parent]
+            if destination
+                .components()
+                .any(|component| component == Component::ParentDir)
+            {
+                return Err(Failure::User(
+                    format!(
+                        "Task {} has an {} with a {} containing {}: {}.",
+                        name.code_str(),
+                        "input_path".code_str(),
+                        "destination".code_str(),
+                        "..".code_str(),
+                        destination.to_string_lossy().code_str()
+                    ),
+                    None,
+                ));
+            }
+
+            // Check that mapping sources are literal paths, since a pattern could match any number
+            // of paths but there is only one destination. [tag:input_paths_mapping_literal]
+            if glob::is_glob(path) {
+                return Err(Failure::User(
+                    format!(
+                        "Task {} has an {} with a {} and a glob pattern as its {}: {}.",
+                        name.code_str(),
+                        "input_path".code_str(),
+                        "destination".code_str(),
+                        "source".code_str(),
+                        path.to_string_lossy().code_str()
+                    ),
+                    None,
+                ));
+            }
+        }
     }
 
-    // Check that `output_paths` are relative. [tag:output_paths_relative]
-    for path in &task.output_paths {
-        if path.is_absolute() {
-            return Err(Failure::User(
-                format!(
-                    "Task {} has an absolute {}: {}.",
-                    name.code_str(),
-                    "output_path".code_str(),
-                    path.to_string_lossy().code_str()
-                ),
-                None,
-            ));
+    // Check that `excluded_input_paths` are valid patterns. [tag:excluded_input_paths_valid]
+    for pattern in &task.excluded_input_paths {
+        GitignoreBuilder::new("")
+            .add_line(None, &pattern.to_string_lossy())
+            .map_err(|e| {
+                Failure::User(
+                    format!(
+                        "Task {} has an invalid {}: {}.",
+                        name.code_str(),
+                        "excluded_input_path".code_str(),
+                        pattern.to_string_lossy().code_str()
+                    ),
+                    Some(Box::new(e)),
+                )
+            })?;
+    }
+
+    // Check `output_paths` and `output_paths_on_failure`. Only the destinations are on the host, so
+    // the sources of mappings may be absolute paths in the container.
+    for (kind, output_paths) in &[
+        ("output_path", &task.output_paths),
+        ("output_path_on_failure", &task.output_paths_on_failure),
+    ] {
+        for output_path in output_paths.iter() {
+            let path = output_path.destination();
+
+            // Check that the path is relative. [tag:output_paths_relative]
+            if path.is_absolute() {
+                return Err(Failure::User(
+                    format!(
+                        "Task {} has an absolute {}: {}.",
+                        name.code_str(),
+                        kind.code_str(),
+                        path.to_string_lossy().code_str()
+                    ),
+                    None,
+                ));
+            }
+
+            // Check that the path doesn't escape the toastfile directory.
+            // [tag:output_paths_contained]
+            if !task.allow_external_paths && !is_lexically_contained(path) {
+                return Err(external_path_failure(name, kind, path));
+            }
+
+            match output_path {
+                OutputPath::Path(path) => {
+                    // Check that glob patterns are valid. [tag:output_paths_globs_valid]
+                    if glob::is_glob(path) {
+                        glob::validate(path).map_err(|e| {
+                            Failure::User(
+                                format!(
+                                    "Task {} has an invalid {}: {}.",
+                                    name.code_str(),
+                                    kind.code_str(),
+                                    path.to_string_lossy().code_str()
+                                ),
+                                Some(Box::new(e)),
+                            )
+                        })?;
+
+                        // Check that the pattern starts with a literal directory. The literal
+                        // prefix is copied out of the container before the pattern is matched, so
+                        // without one the whole `location` would be copied.
+                        // [tag:output_paths_globs_prefixed]
+                        if glob::literal_prefix(path) == Path::new("") {
+                            return Err(Failure::User(
+                                format!(
+                                    "Task {} has an {} with a glob pattern but no literal \
+                                     directory prefix: {}.",
+                                    name.code_str(),
+                                    kind.code_str(),
+                                    path.to_string_lossy().code_str()
+                                ),
+                                None,
+                            ));
+                        }
+                    }
+                }
+                OutputPath::Mapping { source, mode, .. } => {
+                    // Check that a mirrored output wouldn't replace the whole toastfile directory.
+                    // [tag:output_paths_mirror_not_root]
+                    if *mode == OutputMode::Mirror && lexical_depth(path) == Some(0) {
+                        return Err(Failure::User(
+                            format!(
+                                "Task {} has an {} with {} {} which would replace the toastfile \
+                                 directory: {}.",
+                                name.code_str(),
+                                kind.code_str(),
+                                "mode".code_str(),
+                                "mirror".code_str(),
+                                path.to_string_lossy().code_str()
+                            ),
+                            None,
+                        ));
+                    }
+
+                    // Check that mapping sources are literal paths, since a pattern could match any
+                    // number of paths but there is only one destination.
+                    // [tag:output_paths_mapping_literal]
+                    if glob::is_glob(source) {
+                        return Err(Failure::User(
+                            format!(
+                                "Task {} has an {} with a {} and a glob pattern as its {}: {}.",
+                                name.code_str(),
+                                kind.code_str(),
+                                "destination".code_str(),
+                                "source".code_str(),
+                                source.to_string_lossy().code_str()
+                            ),
+                            None,
+                        ));
+                    }
+                }
+            }
         }
     }
 
@@ -360,6 +855,11 @@ fn check_task(name: &str, task: &Task) -> Result<(), Failure> {
             ));
         }
 
+        // Check that the path doesn't escape the toastfile directory. [tag:mount_paths_contained]
+        if !task.allow_external_paths && !is_lexically_contained(path) {
+            return Err(external_path_failure(name, "mount_path", path));
+        }
+
         // Check that the path doesn't contain any commas. [tag:mount_paths_no_commas]
         if path.to_string_lossy().contains(',') {
             return Err(Failure::User(
@@ -421,10 +921,11 @@ fn check_task(name: &str, task: &Task) -> Result<(), Failure> {
 #[cfg(test)]
 mod tests {
     use crate::toastfile::{
-        check_dependencies, check_task, environment, parse, Task, Toastfile, DEFAULT_LOCATION,
-        DEFAULT_USER,
+        check_dependencies, check_task, check_task_paths_resolve, environment, parse, InputPath,
+        OutputMode, OutputPath, SpecialFiles, Task, Toastfile, DEFAULT_LOCATION, DEFAULT_USER,
     };
-    use std::{collections::HashMap, env, path::Path};
+    use std::{collections::HashMap, env, fs::create_dir_all, os::unix::fs::symlink, path::Path};
+    use tempfile::tempdir;
 
     #[test]
     fn parse_empty() {
@@ -461,9 +962,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -499,16 +1009,30 @@ tasks:
     input_paths:
       - qux
       - quux
-      - quuz
+      - source: quuz
+        destination: /etc/quuz
+    excluded_input_paths:
+      - quux/target
+    preserve_permissions: true
+    preserve_mtimes: true
+    chown_input_paths: true
+    special_files: skip
+    follow_symlinks: true
     output_paths:
       - corge
       - grault
-      - garply
+      - source: /garply
+        destination: garply
+        mode: mirror
+    output_paths_on_failure:
+      - fred
+    chown_output_paths: true
     mount_paths:
       - wibble
       - wobble
       - wubble
     mount_readonly: true
+    allow_external_paths: true
     ports:
       - 3000
       - 3001
@@ -533,9 +1057,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -550,21 +1083,37 @@ tasks:
                 cache: false,
                 environment,
                 input_paths: vec![
-                    Path::new("qux").to_owned(),
-                    Path::new("quux").to_owned(),
-                    Path::new("quuz").to_owned(),
+                    InputPath::Path(Path::new("qux").to_owned()),
+                    InputPath::Path(Path::new("quux").to_owned()),
+                    InputPath::Mapping {
+                        source: Path::new("quuz").to_owned(),
+                        destination: Path::new("/etc/quuz").to_owned(),
+                    },
                 ],
+                excluded_input_paths: vec![Path::new("quux/target").to_owned()],
+                preserve_permissions: true,
+                preserve_mtimes: true,
+                chown_input_paths: true,
+                special_files: SpecialFiles::Skip,
+                follow_symlinks: true,
                 output_paths: vec![
-                    Path::new("corge").to_owned(),
-                    Path::new("grault").to_owned(),
-                    Path::new("garply").to_owned(),
+                    OutputPath::Path(Path::new("corge").to_owned()),
+                    OutputPath::Path(Path::new("grault").to_owned()),
+                    OutputPath::Mapping {
+                        source: Path::new("/garply").to_owned(),
+                        destination: Some(Path::new("garply").to_owned()),
+                        mode: OutputMode::Mirror,
+                    },
                 ],
+                output_paths_on_failure: vec![OutputPath::Path(Path::new("fred").to_owned())],
+                chown_output_paths: true,
                 mount_paths: vec![
                     Path::new("wibble").to_owned(),
                     Path::new("wobble").to_owned(),
                     Path::new("wubble").to_owned(),
                 ],
                 mount_readonly: true,
+                allow_external_paths: true,
                 ports: vec!["3000".to_owned(), "3001".to_owned(), "3002".to_owned()],
                 location: Path::new("/code").to_owned(),
                 user: "waldo".to_owned(),
@@ -581,6 +1130,22 @@ tasks:
         assert_eq!(parse(input).unwrap(), toastfile);
     }
 
+    #[test]
+    fn parse_input_paths_mapping_unknown_field() {
+        let input = r#"
+image: encom:os-12
+tasks:
+  foo:
+    input_paths:
+      - source: foo
+        destination: /foo
+        owner: root
+    "#
+        .trim();
+
+        assert!(parse(input).is_err());
+    }
+
     #[test]
     fn environment_empty() {
         let task = Task {
@@ -589,9 +1154,18 @@ tasks:
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -614,9 +1188,18 @@ tasks:
             cache: true,
             environment: env_map,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -644,9 +1227,18 @@ tasks:
             cache: true,
             environment: env_map,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -674,9 +1266,18 @@ tasks:
             cache: true,
             environment: env_map,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -701,9 +1302,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -731,9 +1341,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -774,9 +1393,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -804,9 +1432,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -821,9 +1458,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -851,9 +1497,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -868,9 +1523,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -900,9 +1564,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -932,9 +1605,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -949,9 +1631,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -981,9 +1672,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -998,9 +1698,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -1015,9 +1724,18 @@ tasks:
                 cache: true,
                 environment: HashMap::new(),
                 input_paths: vec![],
+                excluded_input_paths: vec![],
+                preserve_permissions: false,
+                preserve_mtimes: false,
+                chown_input_paths: false,
+                special_files: SpecialFiles::Fail,
+                follow_symlinks: false,
                 output_paths: vec![],
+                output_paths_on_failure: vec![],
+                chown_output_paths: false,
                 mount_paths: vec![],
                 mount_readonly: false,
+                allow_external_paths: false,
                 ports: vec![],
                 location: Path::new(DEFAULT_LOCATION).to_owned(),
                 user: DEFAULT_USER.to_owned(),
@@ -1049,9 +1767,18 @@ tasks:
             cache: true,
             environment,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1074,9 +1801,18 @@ tasks:
             cache: true,
             environment,
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1095,10 +1831,19 @@ tasks:
             dependencies: vec![],
             cache: false,
             environment: HashMap::new(),
-            input_paths: vec![Path::new("bar").to_owned()],
-            output_paths: vec![Path::new("baz").to_owned()],
+            input_paths: vec![InputPath::Path(Path::new("bar").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("baz").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![Path::new("qux").to_owned()],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1115,10 +1860,19 @@ tasks:
             dependencies: vec![],
             cache: true,
             environment: HashMap::new(),
-            input_paths: vec![Path::new("/bar").to_owned()],
+            input_paths: vec![InputPath::Path(Path::new("/bar").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1138,9 +1892,18 @@ tasks:
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
-            output_paths: vec![Path::new("/bar").to_owned()],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("/bar").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1153,16 +1916,25 @@ tasks:
     }
 
     #[test]
-    fn check_task_paths_absolute_mount_paths() {
+    fn check_task_paths_absolute_output_paths_on_failure() {
         let task = Task {
             description: None,
             dependencies: vec![],
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
-            mount_paths: vec![Path::new("/bar").to_owned()],
+            output_paths_on_failure: vec![OutputPath::Path(Path::new("/bar").to_owned())],
+            chown_output_paths: false,
+            mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1175,16 +1947,163 @@ tasks:
     }
 
     #[test]
-    fn check_task_paths_mount_paths_comma() {
+    fn check_task_paths_absolute_output_paths_mapping_source() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Mapping {
+                source: Path::new("/usr/local/bin/bar").to_owned(),
+                destination: Some(Path::new("bar").to_owned()),
+                mode: OutputMode::Merge,
+            }],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        assert!(check_task("foo", &task).is_ok());
+    }
+
+    #[test]
+    fn check_task_paths_absolute_output_paths_mapping_destination() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Mapping {
+                source: Path::new("bar").to_owned(),
+                destination: Some(Path::new("/bar").to_owned()),
+                mode: OutputMode::Merge,
+            }],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("/bar"));
+    }
+
+    #[test]
+    fn check_task_paths_glob_output_paths_mapping() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Mapping {
+                source: Path::new("/opt/*.whl").to_owned(),
+                destination: Some(Path::new("dist").to_owned()),
+                mode: OutputMode::Merge,
+            }],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("/opt/*.whl"));
+    }
+
+    #[test]
+    fn check_task_paths_output_paths_mirror_root() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Mapping {
+                source: Path::new("dist").to_owned(),
+                destination: Some(Path::new("foo/..").to_owned()),
+                mode: OutputMode::Mirror,
+            }],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("foo/.."));
+    }
+
+    #[test]
+    fn check_task_paths_absolute_mount_paths() {
         let task = Task {
             description: None,
             dependencies: vec![],
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
-            mount_paths: vec![Path::new("bar,baz").to_owned()],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![Path::new("/bar").to_owned()],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1193,7 +2112,713 @@ tasks:
 
         let result = check_task("foo", &task);
         assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("bar,baz"));
+        assert!(result.unwrap_err().to_string().contains("/bar"));
+    }
+
+    #[test]
+    fn check_task_paths_glob_input_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("src/**/*.rs").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("dist/*.whl").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        assert!(check_task("foo", &task).is_ok());
+    }
+
+    #[test]
+    fn check_task_paths_invalid_glob_input_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("src/[bar").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("src/[bar"));
+    }
+
+    #[test]
+    fn check_task_paths_glob_input_paths_mapping() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Mapping {
+                source: Path::new("src/*.rs").to_owned(),
+                destination: Path::new("/src").to_owned(),
+            }],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("src/*.rs"));
+    }
+
+    #[test]
+    fn check_task_paths_parent_input_paths_mapping_destination() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Mapping {
+                source: Path::new("config.yml").to_owned(),
+                destination: Path::new("../etc/config.yml").to_owned(),
+            }],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("../etc/config.yml"));
+    }
+
+    #[test]
+    fn check_task_paths_invalid_glob_output_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("dist/{bar").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("dist/{bar"));
+    }
+
+    #[test]
+    fn check_task_paths_unprefixed_glob_output_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("*.whl").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("*.whl"));
+    }
+
+    #[test]
+    fn check_task_paths_invalid_excluded_input_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![Path::new("target/[bar").to_owned()],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("target/[bar"));
+    }
+
+    #[test]
+    fn check_task_paths_mount_paths_comma() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![Path::new("bar,baz").to_owned()],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("bar,baz"));
+    }
+
+    #[test]
+    fn check_task_paths_external_input_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("foo/../../bar").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("foo/../../bar"));
+    }
+
+    #[test]
+    fn check_task_paths_external_output_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("../bar").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("../bar"));
+    }
+
+    #[test]
+    fn check_task_paths_external_mount_paths() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: false,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![Path::new("../bar").to_owned()],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task("foo", &task);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("../bar"));
+    }
+
+    #[test]
+    fn check_task_paths_contained_parent_dir() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("foo/../bar").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        assert!(check_task("foo", &task).is_ok());
+    }
+
+    #[test]
+    fn check_task_paths_external_allowed() {
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("../bar").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: true,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        assert!(check_task("foo", &task).is_ok());
+    }
+
+    #[test]
+    fn check_task_paths_resolve_ok() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink("../outside", dir.path().join("project/link")).unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![
+                InputPath::Path(Path::new("link").to_owned()),
+                InputPath::Path(Path::new("qux").to_owned()),
+            ],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("link").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        assert!(check_task_paths_resolve("foo", &task, &dir.path().join("project")).is_ok());
+    }
+
+    #[test]
+    fn check_task_paths_resolve_input_paths_symlink() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink("../outside", dir.path().join("project/link")).unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("link/bar.txt").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task_paths_resolve("foo", &task, &dir.path().join("project"));
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("link/bar.txt"));
+    }
+
+    #[test]
+    fn check_task_paths_resolve_output_paths_symlink() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink("../outside", dir.path().join("project/link")).unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![OutputPath::Path(Path::new("link/dist/*.whl").to_owned())],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task_paths_resolve("foo", &task, &dir.path().join("project"));
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("link/dist/*.whl"));
+    }
+
+    #[test]
+    fn check_task_paths_resolve_mount_paths_symlink() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink("../outside", dir.path().join("project/link")).unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: false,
+            environment: HashMap::new(),
+            input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![Path::new("link").to_owned()],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task_paths_resolve("foo", &task, &dir.path().join("project"));
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("link"));
+    }
+
+    #[test]
+    fn check_task_paths_resolve_follow_symlinks() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project/src")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink("../../outside", dir.path().join("project/src/link")).unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("src").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: true,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        let result = check_task_paths_resolve("foo", &task, &dir.path().join("project"));
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("src/link"));
+
+        // Without `follow_symlinks`, the link is copied as a link.
+        let task = Task {
+            follow_symlinks: false,
+            ..task
+        };
+        assert!(check_task_paths_resolve("foo", &task, &dir.path().join("project")).is_ok());
+    }
+
+    #[test]
+    fn check_task_paths_resolve_follow_symlinks_excluded() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project/src/vendor")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink(
+            "../../../outside",
+            dir.path().join("project/src/vendor/link"),
+        )
+        .unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("src").to_owned())],
+            excluded_input_paths: vec![Path::new("src/vendor").to_owned()],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: true,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        // The link is excluded, so it isn't copied.
+        assert!(check_task_paths_resolve("foo", &task, &dir.path().join("project")).is_ok());
+    }
+
+    #[test]
+    fn check_task_paths_resolve_follow_symlinks_nested() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project/src")).unwrap();
+        create_dir_all(dir.path().join("project/lib")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink("../lib", dir.path().join("project/src/lib")).unwrap();
+        symlink("../../outside", dir.path().join("project/lib/link")).unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("src").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: true,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: false,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        // The link is reached through the link to `lib`, which points inside.
+        let result = check_task_paths_resolve("foo", &task, &dir.path().join("project"));
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("src/lib/link"));
+    }
+
+    #[test]
+    fn check_task_paths_resolve_allowed() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("project")).unwrap();
+        create_dir_all(dir.path().join("outside")).unwrap();
+        symlink("../outside", dir.path().join("project/link")).unwrap();
+
+        let task = Task {
+            description: None,
+            dependencies: vec![],
+            cache: true,
+            environment: HashMap::new(),
+            input_paths: vec![InputPath::Path(Path::new("link/bar.txt").to_owned())],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
+            output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
+            mount_paths: vec![],
+            mount_readonly: false,
+            allow_external_paths: true,
+            ports: vec![],
+            location: Path::new(DEFAULT_LOCATION).to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            command: String::new(),
+        };
+
+        assert!(check_task_paths_resolve("foo", &task, &dir.path().join("project")).is_ok());
     }
 
     #[test]
@@ -1204,9 +2829,18 @@ tasks:
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new("code").to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1226,9 +2860,18 @@ tasks:
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec!["3000:80".to_owned()],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1248,9 +2891,18 @@ tasks:
             cache: false,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec!["3000:80".to_owned()],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1268,9 +2920,18 @@ tasks:
             cache: true,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![Path::new("bar").to_owned()],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec![],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),
@@ -1290,9 +2951,18 @@ tasks:
             cache: false,
             environment: HashMap::new(),
             input_paths: vec![],
+            excluded_input_paths: vec![],
+            preserve_permissions: false,
+            preserve_mtimes: false,
+            chown_input_paths: false,
+            special_files: SpecialFiles::Fail,
+            follow_symlinks: false,
             output_paths: vec![],
+            output_paths_on_failure: vec![],
+            chown_output_paths: false,
             mount_paths: vec![Path::new("bar").to_owned()],
             mount_readonly: false,
+            allow_external_paths: false,
             ports: vec!["3000:80".to_owned()],
             location: Path::new(DEFAULT_LOCATION).to_owned(),
             user: DEFAULT_USER.to_owned(),